@@ -4,6 +4,8 @@ pub enum VhdError {
     WriteBeyondEOD,
     UnexpectedEOD, //
     WriteZero,
+    ReadOnly,
+    ImageLocked,
     NotFound(String),
 
     FileTooSmall,
@@ -13,15 +15,26 @@ pub enum VhdError {
     InvalidSparseHeaderChecksum,
     InvalidSparseHeaderOffset,
     DiskSizeTooBig,
+    InvalidBlockSize(u32),
     UnknownVhdType(u32),
     InvalidBlockIndex(usize),
     UnexpectedBlockId(usize, u32), // the value returend from Bat::block_id()
 
     ParentNotExist,
     ParentNotDynamic,
+    ParentUuidMismatch,
+    ChainTooDeep,
     FilePathNeedAbsolute,
     CannotGetRelativePath, 
     NeedDyncOrDiffImage,   
+    NeedFixedOrDynamicImage,
+    NeedDiffImage,
+    NeedDynamicImage,
+    NotAzureCompatible(crate::vhd::AzureViolation),
+    InvalidJournalHeader,
+    InvalidJournalEntry(u64), // the entry position in the journal file
+    JournalPending(String),
+    JournalImageMismatch,
 
     Io(std::io::Error),
 }
@@ -33,6 +46,8 @@ impl core::fmt::Display for VhdError {
             VhdError::WriteBeyondEOD => f.write_str("Write beyond EOD"),
             VhdError::UnexpectedEOD => f.write_str("Unexpected EOD"),
             VhdError::WriteZero => f.write_str("Write zero"),
+            VhdError::ReadOnly => f.write_str("Image opened read-only"),
+            VhdError::ImageLocked => f.write_str("Image locked by another process"),
             VhdError::NotFound(s) => write!(f, "Not found '{}'", s),
 
             VhdError::FileTooSmall => f.write_str("File too small"),
@@ -42,17 +57,28 @@ impl core::fmt::Display for VhdError {
             VhdError::InvalidSparseHeaderChecksum => f.write_str("Invalid VHD Sparse header checksum"),
             VhdError::InvalidSparseHeaderOffset => f.write_str("Invalid VHD Sparse header BAT offset"),
             VhdError::DiskSizeTooBig => f.write_str("Disk size too big for VHD"),
+            VhdError::InvalidBlockSize(n) => write!(f, "Invalid block size '{}'", n),
             VhdError::UnknownVhdType(n) => write!(f, "Unknown VHD type '{}'", n),
             VhdError::InvalidBlockIndex(idx) => write!(f, "Invalid block index '{}'", idx),
             VhdError::UnexpectedBlockId(idx, id) => write!(f, "Unexpected '{}' block id '{:08X}'", idx, id),
 
             VhdError::ParentNotExist => f.write_str("Diff parent not exist"),
             VhdError::ParentNotDynamic => f.write_str("Diff parent not dynamic"),
+            VhdError::ParentUuidMismatch => f.write_str("Diff parent UUID mismatch"),
+            VhdError::ChainTooDeep => f.write_str("Diff chain too deep"),
             VhdError::FilePathNeedAbsolute => f.write_str("Need absolute file path"),
             VhdError::CannotGetRelativePath => f.write_str("Cannot get relative path"),
             VhdError::NeedDyncOrDiffImage => f.write_str("Need dynamic or diff type image"),
+            VhdError::NeedFixedOrDynamicImage => f.write_str("Need fixed or dynamic type image"),
+            VhdError::NeedDiffImage => f.write_str("Need diff type image"),
+            VhdError::NeedDynamicImage => f.write_str("Need dynamic type image"),
+            VhdError::NotAzureCompatible(v) => write!(f, "Not Azure compatible: {}", v),
+            VhdError::InvalidJournalHeader => f.write_str("Invalid journal header"),
+            VhdError::InvalidJournalEntry(pos) => write!(f, "Invalid journal entry at '{}'", pos),
+            VhdError::JournalPending(path) => write!(f, "Interrupted operation, journal '{}' left", path),
+            VhdError::JournalImageMismatch => f.write_str("The journal belongs to another image"),
             
-            VhdError::Io(e) => write!(f, "Io error: {}", e.to_string()),
+            VhdError::Io(e) => write!(f, "Io error: {}", e),
         }
     }
 }
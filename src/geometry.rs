@@ -18,6 +18,9 @@ impl std::fmt::Display for Geometry {
     }
 }
 
+/// Largest disk size in sectors the VHD geometry can describe
+pub const MAX_VHD_CHS_SECTORS: u64 = 65535 * 16 * 255;
+
 impl Geometry {
     pub fn chs(cylinders: u64, heads: u32, sectors_per_track: u32) -> Self {
         Geometry {
@@ -37,15 +40,15 @@ impl Geometry {
         let total_sectors = if capacity > 65535_u64 * 16_u64 * 255_u64 * sector_size as u64 {
             65535_u32 * 16_u32 * 255_u32
         } else {
-            capacity as u32 / sector_size
+            (capacity / sector_size as u64) as u32
         };
     
         let (heads_per_cylinder, sectors_per_track) = if total_sectors > 65535_u32 * 16_u32 * 63_u32 {
-            (255, 16)
+            (16, 255)
         } else {
             let mut sectors_per_track = 17_u32;
             let mut cylinders_times_heads = total_sectors / sectors_per_track;
-            let mut heads_per_cylinder = (cylinders_times_heads + 1023) / 1024;
+            let mut heads_per_cylinder = cylinders_times_heads.div_ceil(1024);
     
             if heads_per_cylinder < 4 {
                 heads_per_cylinder = 4
@@ -70,11 +73,36 @@ impl Geometry {
         Geometry {
             cylinders: cylinders as u64,
             heads: heads_per_cylinder,
-            sectors_per_track: sectors_per_track,
+            sectors_per_track,
             bytes_per_sector: sector_size,
         }
     }
 
+    /// Returns the smallest capacity >= `capacity` whose VHD geometry covers it exactly.
+    /// Capacities beyond the largest VHD geometry are returned unchanged.
+    pub fn vhd_chs_capacity(capacity: u64) -> u64 {
+        let sector = sizes::SECTOR_U64;
+        let capacity = crate::math::round_up(capacity, sector);
+        if capacity > MAX_VHD_CHS_SECTORS * sector {
+            return capacity;
+        }
+
+        let mut candidate = capacity;
+        loop {
+            let geometry = Self::with_vhd_capacity(candidate);
+            let chs_capacity = geometry.capacity();
+            if chs_capacity >= capacity && Self::with_vhd_capacity(chs_capacity).capacity() == chs_capacity {
+                return chs_capacity;
+            }
+
+            // one more cylinder
+            candidate = chs_capacity + geometry.heads as u64 * geometry.sectors_per_track as u64 * sector;
+            if candidate > MAX_VHD_CHS_SECTORS * sector {
+                return capacity;
+            }
+        }
+    }
+
     pub fn capacity(&self) -> u64 {
         self.capacity_in_sectors() * (self.bytes_per_sector as u64)
     }
@@ -82,4 +110,36 @@ impl Geometry {
     pub fn capacity_in_sectors(&self) -> u64 {
         self.cylinders * (self.heads as u64) * (self.sectors_per_track as u64)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_vhd_capacity_test() {
+        let chs = |g: Geometry| (g.cylinders, g.heads, g.sectors_per_track);
+
+        assert_eq!(chs(Geometry::with_vhd_capacity(10 << 20)), (301, 4, 17));
+        assert_eq!(chs(Geometry::with_vhd_capacity(1 << 30)), (2080, 16, 63));
+        // beyond 65535/16/63 the geometry uses 16 heads of 255 sectors
+        assert_eq!(chs(Geometry::with_vhd_capacity(64 << 30)), (32896, 16, 255));
+        assert_eq!(chs(Geometry::with_vhd_capacity(200 << 30)), (65535, 16, 255));
+    }
+
+    #[test]
+    fn vhd_chs_capacity_test() {
+        for capacity in [1_u64, 10 << 20, 127 << 20, 1_000_000_000, 40 << 30] {
+            let chs_capacity = Geometry::vhd_chs_capacity(capacity);
+            assert!(chs_capacity >= capacity);
+            assert_eq!(Geometry::with_vhd_capacity(chs_capacity).capacity(), chs_capacity);
+        }
+
+        // already a CHS size
+        let capacity = Geometry::chs(1000, 16, 63).capacity();
+        assert_eq!(Geometry::vhd_chs_capacity(capacity), capacity);
+
+        // beyond the geometry limits
+        assert_eq!(Geometry::vhd_chs_capacity(200 << 30), 200 << 30);
+    }
+}
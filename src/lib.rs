@@ -23,12 +23,29 @@ pub use geometry::*;
 mod math;
 pub use math::*;
 
-mod vhd;
+pub mod vhd;
+pub use vhd::{
+    VhdImage,
+    VhdType,
+    VhdFooter,
+    VhdHeader,
+    VhdCreateOptions,
+    ConvertStats,
+    AzureMode,
+    AzureViolation,
+    azure_size,
+    VhdCheckReport,
+    VhdCheckProblem,
+    VhdRegion,
+    VhdRepairAction,
+    VhdJournal,
+    VhdCoalesceOptions,
+    VhdSnapshotOptions,
+    VhdLocator,
+};
 
 trait UuidEx {
     fn swap_bytes(&self) -> Self;
-    fn from_be_bytes(bytes: [u8; 16]) -> Self;
-    fn from_le_bytes(bytes: [u8; 16]) -> Self;
 }
 
 impl UuidEx for Uuid {
@@ -36,14 +53,6 @@ impl UuidEx for Uuid {
         let fields = self.to_fields_le();
         Uuid::from_fields(fields.0, fields.1, fields.2, fields.3).unwrap()
     }
-
-    fn from_be_bytes(bytes: [u8; 16]) -> Self {
-        Uuid::from_bytes(bytes).swap_bytes()
-    }
-
-    fn from_le_bytes(bytes: [u8; 16]) -> Self {
-        Uuid::from_bytes(bytes)
-    }
 }
 
 pub mod sizes {
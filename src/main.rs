@@ -0,0 +1,552 @@
+//! `rvhd` - a vhd-util compatible command line tool.
+//!
+//! Every sub command returns 0 on success or a positive errno value on failure,
+//! the same way vhd-util does, so existing scripts can check the exit code unchanged.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use rvhd_util_convert::{Disk, OpenMode, VhdCoalesceOptions, VhdError, VhdImage, VhdSnapshotOptions, VhdType};
+
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+const EINVAL: i32 = 22;
+
+/// Image types accepted by `convert -s/-t`, numbered as in vhd-util convert
+const CONVERT_RAW: u32 = 0;
+const CONVERT_FIXED: u32 = 1;
+const CONVERT_DYNAMIC: u32 = 2;
+
+struct Command {
+    name: &'static str,
+    func: fn(&[String]) -> i32,
+}
+
+const COMMANDS: &[Command] = &[
+    Command { name: "create", func: vhd_util_create },
+    Command { name: "snapshot", func: vhd_util_snapshot },
+    Command { name: "query", func: vhd_util_query },
+    Command { name: "read", func: vhd_util_read },
+    Command { name: "convert", func: vhd_util_convert },
+    Command { name: "check", func: vhd_util_check },
+    Command { name: "repair", func: vhd_util_repair },
+    Command { name: "resize", func: vhd_util_resize },
+    Command { name: "coalesce", func: vhd_util_coalesce },
+    Command { name: "compact", func: vhd_util_compact },
+    Command { name: "modify", func: vhd_util_modify },
+];
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    let ret = match args.get(1) {
+        Some(name) => match COMMANDS.iter().find(|cmd| cmd.name == name) {
+            Some(cmd) => (cmd.func)(&args[2..]),
+            None => usage(),
+        },
+        None => usage(),
+    };
+
+    std::process::exit(ret);
+}
+
+fn usage() -> i32 {
+    println!("usage: rvhd COMMAND [OPTIONS]");
+    print!("COMMAND := {{ ");
+    for cmd in COMMANDS {
+        print!("{} ", cmd.name);
+    }
+    println!("}}");
+
+    EINVAL
+}
+
+/// Parses `args` the way getopt(3) does with the option string `spec`:
+/// a letter followed by ':' takes a value, any other letter is a flag.
+/// Flags may be clustered (`-rh`) and a value may be attached (`-n/path`).
+fn getopt(args: &[String], spec: &str) -> Result<HashMap<char, String>, String> {
+    let mut opts = HashMap::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let mut chars = arg.chars();
+        if chars.next() != Some('-') {
+            return Err(format!("unexpected argument '{}'", arg));
+        }
+
+        let mut chars = chars.peekable();
+        if chars.peek().is_none() {
+            return Err(String::from("missing option letter"));
+        }
+
+        while let Some(opt) = chars.next() {
+            let pos = match spec.find(opt) {
+                Some(pos) if opt != ':' => pos,
+                _ => return Err(format!("invalid option -- '{}'", opt)),
+            };
+
+            if !spec[pos + 1..].starts_with(':') {
+                opts.insert(opt, String::new());
+                continue;
+            }
+
+            let rest: String = chars.by_ref().collect();
+            let value = if !rest.is_empty() {
+                rest
+            } else {
+                match args.next() {
+                    Some(value) => value.clone(),
+                    None => return Err(format!("option requires an argument -- '{}'", opt)),
+                }
+            };
+
+            opts.insert(opt, value);
+        }
+    }
+
+    Ok(opts)
+}
+
+fn errno(e: &VhdError) -> i32 {
+    match e {
+        VhdError::Io(e) => e.raw_os_error().unwrap_or(EIO),
+        VhdError::NotFound(_) | VhdError::ParentNotExist => ENOENT,
+        _ => EINVAL,
+    }
+}
+
+fn fail(action: &str, name: &str, e: VhdError) -> i32 {
+    eprintln!("error {} {}: {}", action, name, e);
+    errno(&e)
+}
+
+fn absolute_path(path: &str) -> std::io::Result<PathBuf> {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(std::env::current_dir()?.join(path))
+    }
+}
+
+fn parse_num(opts: &HashMap<char, String>, opt: char) -> Option<u64> {
+    opts.get(&opt).and_then(|v| v.parse().ok())
+}
+
+fn vhd_util_create(args: &[String]) -> i32 {
+    let help = || {
+        println!("options: <-n name> <-s size (MB)> [-r reserve] [-h help]");
+        EINVAL
+    };
+
+    let opts = match getopt(args, "n:s:rh") {
+        Ok(opts) if !opts.contains_key(&'h') => opts,
+        _ => return help(),
+    };
+
+    let (name, size) = match (opts.get(&'n'), parse_num(&opts, 's')) {
+        (Some(name), Some(size)) => (name, size),
+        _ => return help(),
+    };
+
+    let res = if opts.contains_key(&'r') {
+        VhdImage::create_fixed(name.as_str(), size)
+    } else {
+        VhdImage::create_dynamic(name.as_str(), size)
+    };
+
+    match res {
+        Ok(_) => 0,
+        Err(e) => fail("creating", name, e),
+    }
+}
+
+fn vhd_util_snapshot(args: &[String]) -> i32 {
+    let help = || {
+        println!("options: <-n name> <-p parent> [-m parent_is_raw] [-h help]");
+        EINVAL
+    };
+
+    let opts = match getopt(args, "n:p:mh") {
+        Ok(opts) if !opts.contains_key(&'h') => opts,
+        _ => return help(),
+    };
+
+    let (name, parent) = match (opts.get(&'n'), opts.get(&'p')) {
+        (Some(name), Some(parent)) => (name, parent),
+        _ => return help(),
+    };
+
+    // parent locators are computed from absolute paths
+    let paths = absolute_path(name).and_then(|child| Ok((child, std::fs::canonicalize(parent)?)));
+    let (child_path, parent_path) = match paths {
+        Ok(paths) => paths,
+        Err(e) => return fail("snapshotting", name, VhdError::Io(e)),
+    };
+
+    let options = VhdSnapshotOptions::default().raw_parent(opts.contains_key(&'m'));
+    let res = VhdImage::snapshot(
+        child_path.to_string_lossy().into_owned(),
+        parent_path.to_string_lossy().into_owned(),
+        &options,
+    );
+
+    match res {
+        Ok(_) => 0,
+        Err(e) => fail("snapshotting", name, e),
+    }
+}
+
+fn vhd_util_query(args: &[String]) -> i32 {
+    let help = || {
+        println!("options: <-n name> [-v print virtual size (in MB)] [-s print physical utilization (bytes)] \
+                  [-p print parent] [-f field] [-h help]");
+        EINVAL
+    };
+
+    let opts = match getopt(args, "n:vspf:h") {
+        Ok(opts) if !opts.contains_key(&'h') => opts,
+        _ => return help(),
+    };
+
+    let name = match opts.get(&'n') {
+        Some(name) => name,
+        None => return help(),
+    };
+
+    let img = match VhdImage::open(name.as_str()) {
+        Ok(img) => img,
+        Err(e) => return fail("opening", name, e),
+    };
+
+    if opts.contains_key(&'v') {
+        match img.capacity() {
+            Ok(size) => println!("{}", size >> 20),
+            Err(e) => return fail("querying", name, e),
+        }
+    }
+
+    if opts.contains_key(&'s') {
+        match img.file_size() {
+            Ok(size) => println!("{}", size),
+            Err(e) => return fail("querying", name, e),
+        }
+    }
+
+    if opts.contains_key(&'p') {
+        match img.disk_type() {
+            VhdType::Diff => println!("{}", img.parent().unwrap().file_path()),
+            _ => println!("{} has no parent", name),
+        }
+    }
+
+    if let Some(field) = opts.get(&'f') {
+        match field.as_str() {
+            "hidden" => println!("{}: {}", field, img.footer().hidden() as u8),
+            _ => {
+                eprintln!("error querying {}: unknown field '{}'", name, field);
+                return EINVAL;
+            }
+        }
+    }
+
+    0
+}
+
+fn vhd_util_read(args: &[String]) -> i32 {
+    let help = || {
+        println!("options: <-n name> [-p print VHD footer and header] [-P print parent locators] [-h help]");
+        EINVAL
+    };
+
+    let opts = match getopt(args, "n:pPh") {
+        Ok(opts) if !opts.contains_key(&'h') => opts,
+        _ => return help(),
+    };
+
+    let name = match opts.get(&'n') {
+        Some(name) => name,
+        None => return help(),
+    };
+
+    let img = match VhdImage::open(name.as_str()) {
+        Ok(img) => img,
+        Err(e) => return fail("opening", name, e),
+    };
+
+    if opts.contains_key(&'p') {
+        println!("{}", img.footer());
+        if let Some(header) = img.sparse_header() {
+            println!("{}", header);
+        }
+    }
+
+    if opts.contains_key(&'P') {
+        match img.disk_type() {
+            VhdType::Diff => print!("{}", img.parent_locator().unwrap_or_default()),
+            _ => println!("{} has no parent", name),
+        }
+    }
+
+    0
+}
+
+fn vhd_util_convert(args: &[String]) -> i32 {
+    let help = || {
+        println!("options: <-s src type> <-t dst type> <-i input> <-o output> [-h help]");
+        println!("types: {} raw, {} fixed VHD, {} dynamic VHD", CONVERT_RAW, CONVERT_FIXED, CONVERT_DYNAMIC);
+        EINVAL
+    };
+
+    let opts = match getopt(args, "s:t:i:o:h") {
+        Ok(opts) if !opts.contains_key(&'h') => opts,
+        _ => return help(),
+    };
+
+    let (src_type, dst_type, input, output) =
+        match (parse_num(&opts, 's'), parse_num(&opts, 't'), opts.get(&'i'), opts.get(&'o')) {
+            (Some(s), Some(t), Some(i), Some(o)) => (s as u32, t as u32, i, o),
+            _ => return help(),
+        };
+
+    let res = match (src_type, dst_type) {
+        (CONVERT_RAW, CONVERT_FIXED) => VhdImage::convert_raw(input.as_str(), output.as_str(), VhdType::Fixed).map(|_| ()),
+        (CONVERT_RAW, CONVERT_DYNAMIC) => VhdImage::convert_raw(input.as_str(), output.as_str(), VhdType::Dynamic).map(|_| ()),
+        (CONVERT_FIXED, CONVERT_RAW) | (CONVERT_DYNAMIC, CONVERT_RAW) => {
+            let img = match VhdImage::open(input.as_str()) {
+                Ok(img) => img,
+                Err(e) => return fail("opening", input, e),
+            };
+
+            if (img.disk_type() == VhdType::Fixed) != (src_type == CONVERT_FIXED) {
+                eprintln!("error converting {}: not a type {} image", input, src_type);
+                return EINVAL;
+            }
+
+            img.export_raw(output.as_str()).map(|_| ())
+        }
+        _ => {
+            eprintln!("error converting {}: unsupported conversion {} -> {}", input, src_type, dst_type);
+            return EINVAL;
+        }
+    };
+
+    match res {
+        Ok(_) => 0,
+        Err(e) => fail("converting", input, e),
+    }
+}
+
+fn vhd_util_check(args: &[String]) -> i32 {
+    let help = || {
+        println!("options: <-n name> [-h help]");
+        EINVAL
+    };
+
+    let opts = match getopt(args, "n:h") {
+        Ok(opts) if !opts.contains_key(&'h') => opts,
+        _ => return help(),
+    };
+
+    let name = match opts.get(&'n') {
+        Some(name) => name,
+        None => return help(),
+    };
+
+    match VhdImage::check(name.as_str()) {
+        Ok(report) if report.is_ok() => {
+            println!("{} is valid", name);
+            0
+        }
+        Ok(report) => {
+            print!("{} appears invalid; dumping problems\n{}", name, report);
+            EINVAL
+        }
+        Err(e) => fail("checking", name, e),
+    }
+}
+
+fn vhd_util_repair(args: &[String]) -> i32 {
+    let help = || {
+        println!("options: <-n name> [-d dry run] [-h help]");
+        EINVAL
+    };
+
+    let opts = match getopt(args, "n:dh") {
+        Ok(opts) if !opts.contains_key(&'h') => opts,
+        _ => return help(),
+    };
+
+    let name = match opts.get(&'n') {
+        Some(name) => name,
+        None => return help(),
+    };
+
+    match VhdImage::repair(name.as_str(), opts.contains_key(&'d')) {
+        Ok(actions) => {
+            for action in actions {
+                println!("{}", action);
+            }
+            0
+        }
+        Err(e) => fail("repairing", name, e),
+    }
+}
+
+fn vhd_util_resize(args: &[String]) -> i32 {
+    let help = || {
+        println!("options: <-n name> <-s size (MB)> <-j journal> [-h help]");
+        EINVAL
+    };
+
+    let opts = match getopt(args, "n:s:j:h") {
+        Ok(opts) if !opts.contains_key(&'h') => opts,
+        _ => return help(),
+    };
+
+    let (name, size, journal) = match (opts.get(&'n'), parse_num(&opts, 's'), opts.get(&'j')) {
+        (Some(name), Some(size), Some(journal)) => (name, size, journal),
+        _ => return help(),
+    };
+
+    let res = VhdImage::open_with_mode(name.as_str(), OpenMode::ReadWrite)
+        .and_then(|mut img| img.resize(size << 20, journal));
+
+    match res {
+        Ok(_) => 0,
+        Err(e) => fail("resizing", name, e),
+    }
+}
+
+fn vhd_util_coalesce(args: &[String]) -> i32 {
+    let help = || {
+        println!("options: <-n name> [-r remove child] [-h help]");
+        EINVAL
+    };
+
+    let opts = match getopt(args, "n:rh") {
+        Ok(opts) if !opts.contains_key(&'h') => opts,
+        _ => return help(),
+    };
+
+    let name = match opts.get(&'n') {
+        Some(name) => name,
+        None => return help(),
+    };
+
+    let options = VhdCoalesceOptions::default().remove_child(opts.contains_key(&'r'));
+    match VhdImage::coalesce_with(name.as_str(), &options, |_, _| {}) {
+        Ok(_) => 0,
+        Err(e) => fail("coalescing", name, e),
+    }
+}
+
+fn vhd_util_compact(args: &[String]) -> i32 {
+    let help = || {
+        println!("options: <-n name> <-j journal> [-h help]");
+        EINVAL
+    };
+
+    let opts = match getopt(args, "n:j:h") {
+        Ok(opts) if !opts.contains_key(&'h') => opts,
+        _ => return help(),
+    };
+
+    let (name, journal) = match (opts.get(&'n'), opts.get(&'j')) {
+        (Some(name), Some(journal)) => (name, journal),
+        _ => return help(),
+    };
+
+    let res = VhdImage::open_with_mode(name.as_str(), OpenMode::ReadWrite)
+        .and_then(|mut img| img.compact(journal));
+
+    match res {
+        Ok(_) => 0,
+        Err(e) => fail("compacting", name, e),
+    }
+}
+
+fn vhd_util_modify(args: &[String]) -> i32 {
+    let help = || {
+        println!("options: <-n name> <-p parent> [-f force parent UUID] [-h help]");
+        EINVAL
+    };
+
+    let opts = match getopt(args, "n:p:fh") {
+        Ok(opts) if !opts.contains_key(&'h') => opts,
+        _ => return help(),
+    };
+
+    let (name, parent) = match (opts.get(&'n'), opts.get(&'p')) {
+        (Some(name), Some(parent)) => (name, parent),
+        _ => return help(),
+    };
+
+    // parent locators are computed from absolute paths
+    let paths = std::fs::canonicalize(name).and_then(|child| Ok((child, std::fs::canonicalize(parent)?)));
+    let (child_path, parent_path) = match paths {
+        Ok(paths) => paths,
+        Err(e) => return fail("modifying", name, VhdError::Io(e)),
+    };
+
+    let res = VhdImage::reparent(
+        child_path.to_string_lossy().into_owned(),
+        parent_path.to_string_lossy().into_owned(),
+        opts.contains_key(&'f'),
+    );
+
+    match res {
+        Ok(_) => 0,
+        Err(e) => fail("modifying", name, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn getopt_test() {
+        let opts = getopt(&args("-n a.vhd -s10 -r"), "n:s:rh").unwrap();
+        assert_eq!(opts[&'n'], "a.vhd");
+        assert_eq!(opts[&'s'], "10");
+        assert!(opts.contains_key(&'r'));
+        assert!(!opts.contains_key(&'h'));
+    }
+
+    #[test]
+    fn getopt_cluster_test() {
+        let opts = getopt(&args("-rh"), "n:s:rh").unwrap();
+        assert!(opts.contains_key(&'r'));
+        assert!(opts.contains_key(&'h'));
+
+        let opts = getopt(&args("-rn/tmp/a.vhd -s 10"), "n:s:rh").unwrap();
+        assert!(opts.contains_key(&'r'));
+        assert_eq!(opts[&'n'], "/tmp/a.vhd");
+        assert_eq!(opts[&'s'], "10");
+
+        let opts = getopt(&args("-rs 10"), "n:s:rh").unwrap();
+        assert_eq!(opts[&'s'], "10");
+
+        assert!(getopt(&args("-rx"), "n:s:rh").is_err());
+        assert!(getopt(&args("-rs"), "n:s:rh").is_err());
+    }
+
+    #[test]
+    fn getopt_error_test() {
+        assert!(getopt(&args("-x"), "n:s:rh").is_err());
+        assert!(getopt(&args("-n"), "n:s:rh").is_err());
+        assert!(getopt(&args("name"), "n:s:rh").is_err());
+    }
+
+    #[test]
+    fn errno_test() {
+        assert_eq!(errno(&VhdError::ParentNotExist), ENOENT);
+        assert_eq!(errno(&VhdError::InvalidHeaderCookie), EINVAL);
+        assert_eq!(errno(&VhdError::Io(std::io::Error::from_raw_os_error(13))), 13);
+    }
+}
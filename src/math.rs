@@ -27,10 +27,7 @@ pub fn bound_to(data_size: u64, offset: u64, len: usize) -> Option<usize> {
 }
 
 pub fn rest(data_size: u64, offset: u64, len: usize) -> usize {
-    match bound_to(data_size, offset, len) {
-        Some(sz) => sz,
-        None => 0,
-    }
+    bound_to(data_size, offset, len).unwrap_or_default()
 }
 
 #[cfg(test)]
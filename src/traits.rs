@@ -5,11 +5,15 @@ pub trait ReadAt {
     fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize>;
 
     fn read_exact_at(&self, offset: u64, buffer: &mut [u8]) -> Result<()> {
+        let mut offset = offset;
         let mut buffer = buffer;
         while !buffer.is_empty() {
             match self.read_at(offset, buffer) {
                 Ok(0) => break, // EOF
-                Ok(n) => buffer = &mut buffer[n..],
+                Ok(n) => {
+                    buffer = &mut buffer[n..];
+                    offset += n as u64;
+                }
                 Err(e) => return Err(e),
             }
         }
@@ -52,6 +56,43 @@ pub trait SeekAt {
     fn seek_at(&self, pos: std::io::SeekFrom) -> Result<u64>;
 }
 
+/// A request of a batch passed to [`Disk::submit`].
+pub enum DiskRequest<'a> {
+    /// Fill the buffer from the offset.
+    Read(u64, &'a mut [u8]),
+    /// Write the data at the offset.
+    Write(u64, &'a [u8]),
+}
+
+impl DiskRequest<'_> {
+    pub fn offset(&self) -> u64 {
+        match self {
+            DiskRequest::Read(offset, _) | DiskRequest::Write(offset, _) => *offset,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            DiskRequest::Read(_, buffer) => buffer.len(),
+            DiskRequest::Write(_, data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // whether @next is of the same kind and starts where this request ends
+    fn is_followed_by(&self, next: &DiskRequest) -> bool {
+        let same_kind = matches!(
+            (self, next),
+            (DiskRequest::Read(..), DiskRequest::Read(..)) | (DiskRequest::Write(..), DiskRequest::Write(..))
+        );
+
+        same_kind && self.offset() + self.len() as u64 == next.offset()
+    }
+}
+
 pub trait Disk: ReadAt + WriteAt + Flush {
     fn geometry(&self) -> Result<Geometry>;
     fn capacity(&self) -> Result<u64>;
@@ -60,6 +101,78 @@ pub trait Disk: ReadAt + WriteAt + Flush {
     fn logical_sector_size(&self) -> Result<u32> {
         Ok(self.geometry()?.bytes_per_sector)
     }
+
+    /// Reads `buffers` one after the other from `offset`, returns the number of bytes read.
+    fn read_vectored_at(&self, offset: u64, buffers: &mut [&mut [u8]]) -> Result<usize> {
+        let mut done = 0;
+        for buffer in buffers.iter_mut() {
+            let n = self.read_at(offset + done as u64, buffer)?;
+            done += n;
+            if n < buffer.len() {
+                break;
+            }
+        }
+
+        Ok(done)
+    }
+
+    /// Writes `buffers` one after the other from `offset`, returns the number of bytes written.
+    fn write_vectored_at(&self, offset: u64, buffers: &[&[u8]]) -> Result<usize> {
+        let mut done = 0;
+        for data in buffers {
+            let n = self.write_at(offset + done as u64, data)?;
+            done += n;
+            if n < data.len() {
+                break;
+            }
+        }
+
+        Ok(done)
+    }
+
+    /// Runs a batch of requests in order, each one is completed entirely or fails.
+    ///
+    /// Consecutive requests of the same kind at contiguous offsets are merged into a single
+    /// call to [`Disk::read_vectored_at`] or [`Disk::write_vectored_at`].
+    fn submit(&self, requests: &mut [DiskRequest]) -> Result<()> {
+        let mut first = 0;
+        while first < requests.len() {
+            let mut last = first + 1;
+            while last < requests.len() && requests[last - 1].is_followed_by(&requests[last]) {
+                last += 1;
+            }
+
+            let offset = requests[first].offset();
+            let len: usize = requests[first..last].iter().map(|request| request.len()).sum();
+            if let DiskRequest::Read(..) = requests[first] {
+                let mut buffers: Vec<&mut [u8]> = requests[first..last]
+                    .iter_mut()
+                    .filter_map(|request| match request {
+                        DiskRequest::Read(_, buffer) => Some(&mut **buffer),
+                        DiskRequest::Write(..) => None,
+                    })
+                    .collect();
+                if self.read_vectored_at(offset, &mut buffers)? != len {
+                    return Err(VhdError::UnexpectedEOD);
+                }
+            } else {
+                let buffers: Vec<&[u8]> = requests[first..last]
+                    .iter()
+                    .filter_map(|request| match request {
+                        DiskRequest::Write(_, data) => Some(&**data),
+                        DiskRequest::Read(..) => None,
+                    })
+                    .collect();
+                if self.write_vectored_at(offset, &buffers)? != len {
+                    return Err(VhdError::WriteZero);
+                }
+            }
+
+            first = last;
+        }
+
+        Ok(())
+    }
 }
 
 pub trait DiskImage: Disk {
@@ -78,4 +191,30 @@ pub(crate) trait ImageExtentOps: ReadAt + WriteAt + Flush + SeekAt {}
 pub trait ImageExtent {
     fn backing_files(&self) -> Box<dyn core::iter::Iterator<Item = String>>;
     fn storage_size(&self) -> Result<u64>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // returns at most 3 bytes per read, each byte is its offset
+    struct ShortReader;
+
+    impl ReadAt for ShortReader {
+        fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+            let n = std::cmp::min(buffer.len(), 3);
+            for (i, b) in buffer[..n].iter_mut().enumerate() {
+                *b = (offset + i as u64) as u8;
+            }
+
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn read_exact_at_test() {
+        let mut buffer = [0_u8; 10];
+        ShortReader.read_exact_at(5, &mut buffer).unwrap();
+        assert_eq!(buffer, [5, 6, 7, 8, 9, 10, 11, 12, 13, 14]);
+    }
 }
\ No newline at end of file
@@ -1,7 +1,6 @@
-use crate::{traits, Result};
+use crate::{traits, Result, VhdError};
 use std::fs::File;
-use std::io::{SeekFrom, prelude::*};
-use std::cell::RefCell;
+use std::io::prelude::*;
 
 pub trait AsByteSlice {
     /// # Safety
@@ -11,6 +10,9 @@ pub trait AsByteSlice {
 }
 
 pub trait AsByteSliceMut {
+    /// # Safety
+    /// Any byte may be written, the caller must leave a valid value of the type behind.
+    /// There are not any Endianness assumtions either.
     unsafe fn as_byte_slice_mut(&mut self) -> &mut [u8];
 }
 
@@ -211,68 +213,144 @@ impl<T: Sized + Copy + Clone> AsByteSliceMut for StructBuffer<T> {
 }
 
 /// # Safety
-/// The allocated buffer is uninitialized and should be entirely rewritten before read.
+/// The allocated buffer is zero filled, it should still be entirely rewritten before read.
 pub unsafe fn alloc_buffer(size: usize) -> Vec<u8> {
-    let mut buffer = Vec::with_capacity(size);
-    buffer.set_len(size);
-    buffer
+    vec![0_u8; size]
 }
 
 
 /// vhd file open/create/size/read_at/write_at/flush
-pub struct VhdFile(RefCell<File>);
+///
+/// Reads and writes are positional (`pread`/`pwrite`), so a file can be shared between threads.
+pub struct VhdFile(File);
 
 impl traits::ReadAt for VhdFile {
     fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<usize> {
-        let mut file = self.0.borrow_mut();
-        file.seek(SeekFrom::Start(offset))?;
-        file.read(data).map_err(From::from)
+        #[cfg(unix)]
+        let res = std::os::unix::fs::FileExt::read_at(&self.0, data, offset);
+        #[cfg(windows)]
+        let res = std::os::windows::fs::FileExt::seek_read(&self.0, data, offset);
+
+        res.map_err(From::from)
     }
 }
 
 impl traits::WriteAt for VhdFile {
     fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize> {
-        //println!("{:?}", data);
-        let mut file = self.0.borrow_mut();
-        file.seek(SeekFrom::Start(offset))?;
-        file.write(data).map_err(From::from)
+        #[cfg(unix)]
+        let res = std::os::unix::fs::FileExt::write_at(&self.0, data, offset);
+        #[cfg(windows)]
+        let res = std::os::windows::fs::FileExt::seek_write(&self.0, data, offset);
+
+        res.map_err(From::from)
     }
 }
 
 impl traits::Flush for VhdFile {
     fn flush(&self) -> Result<()> {
-        let mut file = self.0.borrow_mut();
-        file.flush().map_err(From::from)
+        (&self.0).flush().map_err(From::from)
     }
 }
 
 impl traits::SeekAt for VhdFile {
     fn seek_at(&self, pos: std::io::SeekFrom) -> Result<u64> {
-        let mut file = self.0.borrow_mut();
-        file.seek(pos).map_err(From::from)
+        (&self.0).seek(pos).map_err(From::from)
     }
 }
 
+/// How an existing image file is opened
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OpenMode {
+    ReadOnly,
+    ReadWrite,
+    /// read-write with an exclusive lock on the file, held until the image is dropped
+    Exclusive,
+}
+
 impl VhdFile {
-    pub fn open(path: &str) -> Result<Self> {
-        let file = File::open(path)?;
-        Ok(VhdFile(
-            RefCell::new(file)
-        ))
+    pub fn open(path: &str, mode: OpenMode) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(mode != OpenMode::ReadOnly)
+            .open(path)?;
+
+        if mode == OpenMode::Exclusive {
+            match file.try_lock() {
+                Ok(()) => (),
+                Err(std::fs::TryLockError::WouldBlock) => return Err(VhdError::ImageLocked),
+                Err(std::fs::TryLockError::Error(e)) => return Err(e.into()),
+            }
+        }
+
+        Ok(VhdFile(file))
     }
 
     pub fn create(path: &str, _size: u64) -> Result<Self> {
-        let file = File::create(path)?;
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
         //file.seek(SeekFrom::Start(size))?;
-        Ok(VhdFile(
-            RefCell::new(file)
-        ))
+        Ok(VhdFile(file))
+    }
+
+    /// Creates the file at `path`, failing if it already exists.
+    pub fn create_new(path: &str) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        Ok(VhdFile(file))
     }
 
     pub fn size(&self) -> Result<u64> {
-        let metadata = self.0.borrow().metadata()?;
+        let metadata = self.0.metadata()?;
         Ok(metadata.len())
     }        
+
+    pub fn set_len(&self, size: u64) -> Result<()> {
+        self.0.set_len(size)?;
+        Ok(())
+    }
+
+    /// Zeroes `len` bytes from `offset`, releasing their space in the file system where supported.
+    /// The file size is unchanged.
+    pub fn punch_hole(&self, offset: u64, len: u64) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+            if unsafe { libc::fallocate(self.0.as_raw_fd(), mode, offset as libc::off_t, len as libc::off_t) } == 0 {
+                return Ok(());
+            }
+        }
+
+        // the file system cannot deallocate, the range is written with zeros instead
+        self.write_zeros(offset, len)
+    }
+
+    /// Writes `len` zero bytes from `offset`.
+    pub fn write_zeros(&self, offset: u64, len: u64) -> Result<()> {
+        let zeros = vec![0_u8; std::cmp::min(len, 1 << 20) as usize];
+        let mut pos = offset;
+        while pos < offset + len {
+            let n = std::cmp::min(zeros.len() as u64, offset + len - pos) as usize;
+            traits::WriteAt::write_all_at(self, pos, &zeros[..n])?;
+            pos += n as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the file content and metadata to the disk.
+    pub fn sync(&self) -> Result<()> {
+        self.0.sync_all()?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -324,32 +402,24 @@ mod tests {
         let mut buffer = StructBuffer::<S>::zeroed();
         assert_eq!(3, buffer.len());
 
-        unsafe {
-            // packed fileds
-            assert_eq!(0, buffer.byte);
-            assert_eq!(0, buffer.word)
-        }
+        // packed fileds
+        assert_eq!(0, { buffer.byte });
+        assert_eq!(0, { buffer.word });
 
         buffer.byte = 12;
-        unsafe {
-            assert_eq!(12, buffer.byte);
-            assert_eq!(0, buffer.word)
-        }
+        assert_eq!(12, { buffer.byte });
+        assert_eq!(0, { buffer.word });
 
         let bytes = unsafe { buffer.as_byte_slice() };
         assert_eq!(3, bytes.len());
 
         let s = buffer.copy();
-        unsafe {
-            assert_eq!(12, s.byte);
-            assert_eq!(0, s.word)
-        }
+        assert_eq!(12, { s.byte });
+        assert_eq!(0, { s.word });
 
         let s = buffer.take();
-        unsafe {
-            assert_eq!(12, s.byte);
-            assert_eq!(0, s.word)
-        }
+        assert_eq!(12, { s.byte });
+        assert_eq!(0, { s.word });
     }
 
     #[test]
@@ -382,8 +452,8 @@ mod tests {
         let mut buffer = StructBuffer::<S>::zeroed();
         assert_eq!(3, buffer.len());
         assert!(!buffer.has_ext_buffer());
-        assert!(buffer.ext_buffer().len() == 0);
-        assert!(buffer.ext_buffer_mut().len() == 0);
+        assert!(buffer.ext_buffer().is_empty());
+        assert!(buffer.ext_buffer_mut().is_empty());
     }
 
     #[test]
@@ -391,8 +461,8 @@ mod tests {
         let mut buffer = unsafe { StructBuffer::<S>::with_value(&S{ byte: 78, word: 0x1326}) };
         assert_eq!(3, buffer.len());
         assert!(!buffer.has_ext_buffer());
-        assert!(buffer.ext_buffer().len() == 0);
-        assert!(buffer.ext_buffer_mut().len() == 0);
+        assert!(buffer.ext_buffer().is_empty());
+        assert!(buffer.ext_buffer_mut().is_empty());
 
         assert!( buffer.byte == 78 );
         assert!( buffer.word == 0x1326 );
@@ -0,0 +1,220 @@
+use super::*;
+use crate::{geometry, math, sizes, Geometry, Result, VhdError};
+
+/// How image creation and conversion handle the Azure upload rules.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AzureMode {
+    /// No Azure specific rule is applied.
+    Off,
+    /// Non-conforming sizes are refused with [`VhdError::NotAzureCompatible`].
+    Strict,
+    /// Sizes are padded up to the next size Azure accepts.
+    Pad,
+}
+
+/// A reason why Azure would refuse an image.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AzureViolation {
+    /// Only fixed images can be uploaded.
+    NotFixed(VhdType),
+    /// The virtual size is not a whole number of MiB.
+    SizeNotMibAligned(u64),
+    /// The size described by the geometry differs from `curr_size`.
+    GeometryMismatch { geometry_size: u64, current_size: u64 },
+    /// The file is not exactly the virtual size plus the footer.
+    FileSizeMismatch { file_size: u64, expected: u64 },
+}
+
+impl std::fmt::Display for AzureViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AzureViolation::NotFixed(kind) => write!(f, "{} image is not fixed", vhd_type_str(*kind)),
+            AzureViolation::SizeNotMibAligned(size) => write!(f, "size {} is not a whole number of MiB", size),
+            AzureViolation::GeometryMismatch { geometry_size, current_size } => {
+                write!(f, "geometry size {} differs from current size {}", geometry_size, current_size)
+            }
+            AzureViolation::FileSizeMismatch { file_size, expected } => {
+                write!(f, "file size {} should be {}", file_size, expected)
+            }
+        }
+    }
+}
+
+const MAX_CHS_CAPACITY: u64 = geometry::MAX_VHD_CHS_SECTORS * sizes::SECTOR_U64;
+
+// the size the geometry describes, None beyond the largest geometry
+fn chs_capacity(size: u64) -> Option<u64> {
+    if size > MAX_CHS_CAPACITY {
+        None
+    } else {
+        Some(Geometry::with_vhd_capacity(size).capacity())
+    }
+}
+
+fn size_violations(size: u64) -> Vec<AzureViolation> {
+    let mut violations = Vec::new();
+
+    if !size.is_multiple_of(sizes::MIB) {
+        violations.push(AzureViolation::SizeNotMibAligned(size));
+    }
+
+    match chs_capacity(size) {
+        Some(geometry_size) if geometry_size != size => {
+            violations.push(AzureViolation::GeometryMismatch { geometry_size, current_size: size })
+        }
+        _ => {}
+    }
+
+    violations
+}
+
+/// Returns the smallest size >= `size` Azure accepts: a whole number of MiB
+/// that the VHD geometry describes exactly.
+pub fn azure_size(size: u64) -> u64 {
+    let mut size = math::round_up(size, sizes::MIB);
+    while matches!(chs_capacity(size), Some(chs) if chs != size) {
+        size += sizes::MIB;
+    }
+
+    size
+}
+
+impl AzureMode {
+    pub(crate) fn disk_size(&self, size: u64) -> Result<u64> {
+        match self {
+            AzureMode::Off => Ok(size),
+            AzureMode::Pad => Ok(azure_size(size)),
+            AzureMode::Strict => match size_violations(size).first() {
+                Some(violation) => Err(VhdError::NotAzureCompatible(*violation)),
+                None => Ok(size),
+            },
+        }
+    }
+
+    pub(crate) fn check_type(&self, disk_type: VhdType) -> Result<()> {
+        if *self != AzureMode::Off && disk_type != VhdType::Fixed {
+            return Err(VhdError::NotAzureCompatible(AzureViolation::NotFixed(disk_type)));
+        }
+
+        Ok(())
+    }
+}
+
+impl VhdFooter {
+    /// Lists the footer fields Azure would refuse, empty if there is none.
+    pub fn azure_violations(&self) -> Vec<AzureViolation> {
+        let mut violations = Vec::new();
+
+        if self.disk_type() != VhdType::Fixed {
+            violations.push(AzureViolation::NotFixed(self.disk_type()));
+        }
+
+        let current_size = self.current_size();
+        if !current_size.is_multiple_of(sizes::MIB) {
+            violations.push(AzureViolation::SizeNotMibAligned(current_size));
+        }
+
+        let geometry_size = self.geometry().capacity();
+        if current_size <= MAX_CHS_CAPACITY && geometry_size != current_size {
+            violations.push(AzureViolation::GeometryMismatch { geometry_size, current_size });
+        }
+
+        violations
+    }
+}
+
+impl VhdImage {
+    /// Lists the reasons why Azure would refuse this image, empty if it can be uploaded as is.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{AzureMode, VhdCreateOptions, VhdImage};
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_azure.vhd");
+    /// let options = VhdCreateOptions::default().azure(AzureMode::Pad);
+    /// let img = VhdImage::create_fixed_bytes(path.to_str().unwrap(), 10 << 20, &options).unwrap();
+    /// assert!(img.azure_violations().unwrap().is_empty());
+    /// # drop(img);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
+    pub fn azure_violations(&self) -> Result<Vec<AzureViolation>> {
+        let mut violations = self.footer().azure_violations();
+
+        if self.disk_type() == VhdType::Fixed {
+            let file_size = self.file_size()?;
+            let expected = self.footer().current_size() + sizes::SECTOR_U64;
+            if file_size != expected {
+                violations.push(AzureViolation::FileSizeMismatch { file_size, expected });
+            }
+        }
+
+        Ok(violations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Disk;
+    use crate::vhd::test_util::temp_path;
+
+    #[test]
+    fn azure_size_test() {
+        for size in [1_u64, 10 << 20, 127 << 20, 1_000_000_000, 40 << 30] {
+            let azure = azure_size(size);
+            assert!(azure >= size);
+            assert!(size_violations(azure).is_empty(), "{}", azure);
+        }
+
+        // 100 MiB needs 17 sectors per track, which never ends on a MiB boundary with 12 heads
+        assert!(azure_size(100 << 20) > 100 << 20);
+        // beyond the geometry limits only the MiB alignment counts
+        assert_eq!(azure_size((200 << 30) + 1), (200 << 30) + sizes::MIB);
+    }
+
+    #[test]
+    fn create_azure_test() {
+        let path = temp_path("rvhd_create_azure.vhd");
+
+        let strict = VhdCreateOptions::default().azure(AzureMode::Strict);
+        let res = VhdImage::create_fixed_bytes(path.as_str(), 100 << 20, &strict);
+        assert!(matches!(res, Err(VhdError::NotAzureCompatible(AzureViolation::GeometryMismatch { .. }))));
+
+        let size = azure_size(100 << 20);
+        let res = VhdImage::create_dynamic_bytes(path.as_str(), size, &strict);
+        assert!(matches!(res, Err(VhdError::NotAzureCompatible(AzureViolation::NotFixed(VhdType::Dynamic)))));
+
+        let img = VhdImage::create_fixed_bytes(path.as_str(), size, &strict).unwrap();
+        assert!(img.azure_violations().unwrap().is_empty());
+        assert_eq!(img.footer().crtr_app(), "win ");
+        drop(img);
+
+        let pad = VhdCreateOptions::default().azure(AzureMode::Pad);
+        let img = VhdImage::create_fixed_bytes(path.as_str(), 100 << 20, &pad).unwrap();
+        assert_eq!(img.capacity().unwrap(), size);
+        drop(img);
+
+        let img = VhdImage::open(path.as_str()).unwrap();
+        assert!(img.azure_violations().unwrap().is_empty());
+        drop(img);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn azure_violations_test() {
+        let path = temp_path("rvhd_azure_violations.vhd");
+
+        let img = VhdImage::create_fixed_bytes(path.as_str(), 1_000_448, &VhdCreateOptions::default()).unwrap();
+        let violations = img.azure_violations().unwrap();
+        assert!(violations.contains(&AzureViolation::SizeNotMibAligned(1_000_448)));
+        assert!(violations.iter().any(|v| matches!(v, AzureViolation::GeometryMismatch { .. })));
+        drop(img);
+
+        let img = VhdImage::create_dynamic(path.as_str(), 128).unwrap();
+        let violations = img.azure_violations().unwrap();
+        assert!(violations.contains(&AzureViolation::NotFixed(VhdType::Dynamic)));
+        drop(img);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
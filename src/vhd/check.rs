@@ -0,0 +1,444 @@
+use super::*;
+use crate::{math, sizes, OpenMode, Result, VhdError, VhdFile};
+
+/// A part of the image file, as named in [`VhdCheckProblem::Overlap`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VhdRegion {
+    /// The footer copy at the start of dynamic and differencing images.
+    BackupFooter,
+    Header,
+    Bat,
+    Locator(usize),
+    Block(usize),
+}
+
+/// A problem found by [`VhdImage::check`].
+#[derive(Debug)]
+pub enum VhdCheckProblem {
+    FileTooSmall(u64),
+    InvalidFooter(VhdError),
+    InvalidBackupFooter(VhdError),
+    /// The backup footer differs from the trailing footer.
+    FooterMismatch,
+    /// Fixed image whose file is not the disk size plus the footer.
+    FixedSizeMismatch { file_size: u64, expected: u64 },
+    InvalidFooterDataOffset(u64),
+    InvalidHeader(VhdError),
+    InvalidHeaderDataOffset(u64),
+    InvalidHeaderVersion(u32),
+    /// The BAT has fewer entries than blocks in the disk.
+    BatTooSmall { entries: u32, expected: u32 },
+    BatOutOfFile(u64),
+    BitmapOutOfFile { index: usize, offset: u64 },
+    BlockOutOfFile { index: usize, offset: u64 },
+    LocatorOutOfFile { index: usize, offset: u64 },
+    LocatorTooLong { index: usize, len: u32, space: u64 },
+    Overlap(VhdRegion, VhdRegion),
+    ParentNotFound,
+    ParentUuidMismatch,
+    ParentTimestampMismatch { expected: u32, found: u32 },
+}
+
+impl std::fmt::Display for VhdCheckProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VhdCheckProblem::FileTooSmall(size) => write!(f, "file size {} is too small", size),
+            VhdCheckProblem::InvalidFooter(e) => write!(f, "invalid footer: {}", e),
+            VhdCheckProblem::InvalidBackupFooter(e) => write!(f, "invalid backup footer: {}", e),
+            VhdCheckProblem::FooterMismatch => f.write_str("backup footer differs from footer"),
+            VhdCheckProblem::FixedSizeMismatch { file_size, expected } => {
+                write!(f, "file size {} should be {}", file_size, expected)
+            }
+            VhdCheckProblem::InvalidFooterDataOffset(offset) => write!(f, "invalid footer data offset {:#X}", offset),
+            VhdCheckProblem::InvalidHeader(e) => write!(f, "invalid header: {}", e),
+            VhdCheckProblem::InvalidHeaderDataOffset(offset) => write!(f, "invalid header data offset {:#X}", offset),
+            VhdCheckProblem::InvalidHeaderVersion(version) => write!(f, "invalid header version {:#010X}", version),
+            VhdCheckProblem::BatTooSmall { entries, expected } => {
+                write!(f, "BAT has {} entries, {} needed", entries, expected)
+            }
+            VhdCheckProblem::BatOutOfFile(offset) => write!(f, "BAT at {:#X} is outside of the file", offset),
+            VhdCheckProblem::BitmapOutOfFile { index, offset } => {
+                write!(f, "block {} bitmap at {:#X} is outside of the file", index, offset)
+            }
+            VhdCheckProblem::BlockOutOfFile { index, offset } => {
+                write!(f, "block {} at {:#X} is outside of the file", index, offset)
+            }
+            VhdCheckProblem::LocatorOutOfFile { index, offset } => {
+                write!(f, "parent locator {} at {:#X} is outside of the file", index, offset)
+            }
+            VhdCheckProblem::LocatorTooLong { index, len, space } => {
+                write!(f, "parent locator {} length {} exceeds its space {}", index, len, space)
+            }
+            VhdCheckProblem::Overlap(a, b) => write!(f, "{:?} overlaps {:?}", a, b),
+            VhdCheckProblem::ParentNotFound => f.write_str("parent not found"),
+            VhdCheckProblem::ParentUuidMismatch => f.write_str("parent UUID mismatch"),
+            VhdCheckProblem::ParentTimestampMismatch { expected, found } => {
+                write!(f, "parent timestamp {} should be {}", found, expected)
+            }
+        }
+    }
+}
+
+/// Every problem found by [`VhdImage::check`], empty for a consistent image.
+#[derive(Debug, Default)]
+pub struct VhdCheckReport {
+    pub problems: Vec<VhdCheckProblem>,
+}
+
+impl VhdCheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn push(&mut self, problem: VhdCheckProblem) {
+        self.problems.push(problem);
+    }
+}
+
+impl std::fmt::Display for VhdCheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl VhdImage {
+    /// Checks the consistency of the image at `path`, the way `vhd-util check` does.
+    ///
+    /// The image is not opened with [`VhdImage::open`], so damaged images are reported
+    /// instead of refused. An error is only returned when the file cannot be read, or
+    /// when the parent of a differencing image is there but fails to open.
+    ///
+    /// ```
+    /// use rvhd_util_convert::VhdImage;
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_check.vhd");
+    /// drop(VhdImage::create_dynamic(path.to_str().unwrap(), 4).unwrap());
+    ///
+    /// let report = VhdImage::check(path.to_str().unwrap()).unwrap();
+    /// assert!(report.is_ok(), "{}", report);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
+    pub fn check<S: Into<String>>(path: S) -> Result<VhdCheckReport> {
+        let path = path.into();
+        let file = VhdFile::open(&path, OpenMode::ReadOnly)?;
+        let file_size = file.size()?;
+        let mut report = VhdCheckReport::default();
+
+        if file_size < sizes::SECTOR_U64 {
+            report.push(VhdCheckProblem::FileTooSmall(file_size));
+            return Ok(report);
+        }
+
+        let (footer, footer_size) = match VhdFooter::read_trailing(&file, file_size) {
+            Ok((footer, footer_size)) => (Some(footer), footer_size),
+            Err(e) => {
+                report.push(VhdCheckProblem::InvalidFooter(e));
+                (None, FOOTER_SIZE)
+            }
+        };
+        let footer_pos = file_size - footer_size;
+
+        // the backup footer only exists in sparse images, it stands in for a damaged footer
+        let sparse = footer.is_none_or(|footer| footer.disk_type() != VhdType::Fixed);
+        let backup = if sparse {
+            match VhdFooter::read(&file, 0) {
+                Ok(backup) => Some(backup),
+                Err(e) => {
+                    report.push(VhdCheckProblem::InvalidBackupFooter(e));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        if let (Some(footer), Some(backup)) = (footer, backup) {
+            if footer.to_bytes() != backup.to_bytes() {
+                report.push(VhdCheckProblem::FooterMismatch);
+            }
+        }
+
+        let footer = match footer.or(backup) {
+            Some(footer) => footer,
+            None => return Ok(report),
+        };
+
+        if footer.disk_type() == VhdType::Fixed {
+            let expected = footer.current_size() + footer_size;
+            if file_size != expected {
+                report.push(VhdCheckProblem::FixedSizeMismatch { file_size, expected });
+            }
+
+            return Ok(report);
+        }
+
+        let data_offset = footer.data_offset();
+        if data_offset < sizes::SECTOR_U64 || data_offset > footer_pos {
+            report.push(VhdCheckProblem::InvalidFooterDataOffset(data_offset));
+            return Ok(report);
+        }
+
+        let header = match VhdHeader::read(&file, data_offset) {
+            Ok(header) => header,
+            Err(e) => {
+                report.push(VhdCheckProblem::InvalidHeader(e));
+                return Ok(report);
+            }
+        };
+
+        Self::check_sparse(&file, footer_pos, &footer, &header, &mut report)?;
+
+        if footer.disk_type() == VhdType::Diff {
+            Self::check_parent(&path, &header, &mut report)?;
+        }
+
+        Ok(report)
+    }
+
+    // `file_end` is the trailing footer position, the end of the usable space
+    fn check_sparse(
+        file: &VhdFile,
+        file_end: u64,
+        footer: &VhdFooter,
+        header: &VhdHeader,
+        report: &mut VhdCheckReport,
+    ) -> Result<()> {
+        let data_offset = footer.data_offset();
+
+        if header.data_offset() != sparse::DD_OFFSET {
+            report.push(VhdCheckProblem::InvalidHeaderDataOffset(header.data_offset()));
+        }
+
+        if header.hdr_ver() != sparse::DD_VERSION {
+            report.push(VhdCheckProblem::InvalidHeaderVersion(header.hdr_ver()));
+        }
+
+        let block_size = header.block_size() as u64;
+        let entries = header.max_bat_size();
+        let expected = math::ceil(footer.current_size(), block_size) as u32;
+        if entries < expected {
+            report.push(VhdCheckProblem::BatTooSmall { entries, expected });
+        }
+
+        let mut regions = vec![
+            (0, sizes::SECTOR_U64, VhdRegion::BackupFooter),
+            (data_offset, data_offset + 2 * sizes::SECTOR_U64, VhdRegion::Header),
+        ];
+
+        // the offsets come from a possibly damaged image, an overflow means out of the file
+        let table_offset = header.table_offset();
+        let table_size = math::round_up(entries as u64 * 4, sizes::SECTOR_U64);
+        let table_end = table_offset.checked_add(table_size).filter(|end| *end <= file_end);
+        match table_end {
+            Some(table_end) => regions.push((table_offset, table_end, VhdRegion::Bat)),
+            None => report.push(VhdCheckProblem::BatOutOfFile(table_offset)),
+        }
+
+        for (index, locator) in header.prt_loc().iter().enumerate() {
+            if locator.prt_loc_code() == sparse::PLAT_CODE_NONE {
+                continue;
+            }
+
+            let offset = locator.prt_loc_offset();
+            let space = locator.prt_loc_space_bytes();
+            if locator.prt_loc_len() as u64 > space {
+                report.push(VhdCheckProblem::LocatorTooLong { index, len: locator.prt_loc_len(), space });
+            }
+
+            match offset.checked_add(space).filter(|end| *end <= file_end) {
+                Some(end) => regions.push((offset, end, VhdRegion::Locator(index))),
+                None => report.push(VhdCheckProblem::LocatorOutOfFile { index, offset }),
+            }
+        }
+
+        // the blocks cannot be checked without the BAT
+        if table_end.is_some() {
+            let bat = bat::VhdBat::read(file, table_offset, entries)?;
+            let bitmap_size = sparse::bitmap_size(header.block_size()) as u64;
+            for index in 0..entries as usize {
+                let block_id = bat.block_id(index)?;
+                if block_id == bat::DD_BLOCK_UNUSED {
+                    continue;
+                }
+
+                let offset = block_id as u64 * sizes::SECTOR_U64;
+                if offset + bitmap_size > file_end {
+                    report.push(VhdCheckProblem::BitmapOutOfFile { index, offset });
+                } else if offset + bitmap_size + block_size > file_end {
+                    report.push(VhdCheckProblem::BlockOutOfFile { index, offset });
+                } else {
+                    regions.push((offset, offset + bitmap_size + block_size, VhdRegion::Block(index)));
+                }
+            }
+        }
+
+        // any region starting before the end of the previous ones overlaps the one ending last
+        regions.sort_by_key(|(start, _, _)| *start);
+        let mut last: Option<(u64, VhdRegion)> = None;
+        for (start, end, region) in regions {
+            match last {
+                Some((last_end, last_region)) if start < last_end => {
+                    report.push(VhdCheckProblem::Overlap(last_region, region));
+                    if end > last_end {
+                        last = Some((end, region));
+                    }
+                }
+                _ => last = Some((end, region)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reports a missing parent or one not matching the header, any other error
+    /// opening the chain is returned.
+    fn check_parent(path: &str, header: &VhdHeader, report: &mut VhdCheckReport) -> Result<()> {
+        match Self::open(path) {
+            // a raw parent has no time stamp
+            Ok(img) if img.parent().unwrap().is_raw() => {}
+            Ok(img) => {
+                let expected = img.parent().unwrap().footer().timestamps();
+                if header.prt_ts() != expected {
+                    report.push(VhdCheckProblem::ParentTimestampMismatch { expected, found: header.prt_ts() });
+                }
+            }
+            Err(VhdError::ParentNotExist) => report.push(VhdCheckProblem::ParentNotFound),
+            Err(VhdError::ParentUuidMismatch) => report.push(VhdCheckProblem::ParentUuidMismatch),
+            Err(e) => return Err(e),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ReadAt, WriteAt};
+    use crate::vhd::test_util::temp_path;
+
+    #[test]
+    fn check_valid_test() {
+        let parent_path = temp_path("rvhd_check_valid_parent.vhd");
+        let child_path = temp_path("rvhd_check_valid_child.vhd");
+        let fixed_path = temp_path("rvhd_check_valid_fixed.vhd");
+
+        let parent = VhdImage::create_dynamic(parent_path.as_str(), 8).unwrap();
+        parent.write_all_at(0, &[1_u8; 4096]).unwrap();
+        parent.write_all_at(5 << 20, &[2_u8; 4096]).unwrap();
+        drop(parent);
+        drop(VhdImage::create_diff(child_path.as_str(), parent_path.as_str()).unwrap());
+        drop(VhdImage::create_fixed(fixed_path.as_str(), 2).unwrap());
+
+        for path in [&parent_path, &child_path, &fixed_path] {
+            let report = VhdImage::check(path.as_str()).unwrap();
+            assert!(report.is_ok(), "{}: {}", path, report);
+        }
+
+        std::fs::remove_file(child_path).unwrap();
+        std::fs::remove_file(parent_path).unwrap();
+        std::fs::remove_file(fixed_path).unwrap();
+    }
+
+    #[test]
+    fn check_parent_test() {
+        let parent_path = temp_path("rvhd_check_parent_parent.vhd");
+        let child_path = temp_path("rvhd_check_parent_child.vhd");
+
+        drop(VhdImage::create_dynamic(parent_path.as_str(), 8).unwrap());
+        drop(VhdImage::create_diff(child_path.as_str(), parent_path.as_str()).unwrap());
+
+        // a parent which is not a VHD is not a problem of the child
+        std::fs::write(&parent_path, [0_u8; 4096]).unwrap();
+        assert!(VhdImage::check(child_path.as_str()).is_err());
+
+        std::fs::remove_file(&parent_path).unwrap();
+        let report = VhdImage::check(child_path.as_str()).unwrap();
+        assert!(matches!(report.problems[..], [VhdCheckProblem::ParentNotFound]), "{}", report);
+
+        std::fs::remove_file(child_path).unwrap();
+    }
+
+    #[test]
+    fn check_damaged_test() {
+        let path = temp_path("rvhd_check_damaged.vhd");
+
+        let img = VhdImage::create_dynamic(path.as_str(), 8).unwrap();
+        img.write_all_at(0, &[1_u8; 4096]).unwrap();
+        img.write_all_at(5 << 20, &[2_u8; 4096]).unwrap();
+        let table_offset = img.sparse_header().unwrap().table_offset();
+        drop(img);
+
+        let file = VhdFile::open(&path, OpenMode::ReadWrite).unwrap();
+        // the backup footer is broken and the block 2 entry points to the block 0 one
+        file.write_all_at(0, &[0_u8; 8]).unwrap();
+        let mut entry = [0_u8; 4];
+        file.read_exact_at(table_offset, &mut entry).unwrap();
+        file.write_all_at(table_offset + 2 * 4, &entry).unwrap();
+        // and the last block reaches beyond the end of the file
+        let file_size = file.size().unwrap();
+        file.write_all_at(table_offset + 3 * 4, &((file_size / sizes::SECTOR_U64) as u32 - 2).to_be_bytes()).unwrap();
+        drop(file);
+
+        let report = VhdImage::check(path.as_str()).unwrap();
+        let problems = &report.problems;
+        assert_eq!(problems.len(), 3, "{}", report);
+        assert!(matches!(problems[0], VhdCheckProblem::InvalidBackupFooter(VhdError::InvalidHeaderCookie)));
+        assert!(matches!(problems[1], VhdCheckProblem::BlockOutOfFile { index: 3, .. }));
+        assert!(matches!(problems[2], VhdCheckProblem::Overlap(VhdRegion::Block(0), VhdRegion::Block(2))));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn check_overflow_test() {
+        let path = temp_path("rvhd_check_overflow.vhd");
+        drop(VhdImage::create_dynamic(path.as_str(), 8).unwrap());
+
+        // the BAT and a locator end beyond the largest offset
+        let file = VhdFile::open(&path, OpenMode::ReadWrite).unwrap();
+        let mut header = [0_u8; 1024];
+        file.read_exact_at(DEFAULT_HEADER_OFFSET, &mut header).unwrap();
+        header[16..24].copy_from_slice(&(u64::MAX - 100).to_be_bytes());
+        // the second parent locator entry: code, space, length, reserved and offset
+        header[600..604].copy_from_slice(&sparse::PLAT_CODE_W2KU.to_be_bytes());
+        header[604..608].copy_from_slice(&512_u32.to_be_bytes());
+        header[608..612].copy_from_slice(&16_u32.to_be_bytes());
+        header[616..624].copy_from_slice(&(u64::MAX - 100).to_be_bytes());
+        header[36..40].fill(0);
+        let checksum = !header.iter().map(|b| *b as u32).sum::<u32>();
+        header[36..40].copy_from_slice(&checksum.to_be_bytes());
+        file.write_all_at(DEFAULT_HEADER_OFFSET, &header).unwrap();
+        drop(file);
+
+        let report = VhdImage::check(path.as_str()).unwrap();
+        let problems = &report.problems;
+        assert_eq!(problems.len(), 2, "{}", report);
+        assert!(matches!(problems[0], VhdCheckProblem::BatOutOfFile(offset) if offset == u64::MAX - 100));
+        assert!(matches!(problems[1], VhdCheckProblem::LocatorOutOfFile { index: 1, .. }));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn check_fixed_size_test() {
+        let path = temp_path("rvhd_check_fixed_size.vhd");
+        drop(VhdImage::create_fixed(path.as_str(), 2).unwrap());
+
+        // a sector more, the footer is still the last sector
+        let mut data = std::fs::read(&path).unwrap();
+        let footer = data.split_off(data.len() - sizes::SECTOR as usize);
+        data.extend_from_slice(&[0_u8; 512]);
+        data.extend_from_slice(&footer);
+        std::fs::write(&path, data).unwrap();
+
+        let report = VhdImage::check(path.as_str()).unwrap();
+        assert!(matches!(report.problems[..], [VhdCheckProblem::FixedSizeMismatch { .. }]));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
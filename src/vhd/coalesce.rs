@@ -0,0 +1,333 @@
+use std::collections::HashSet;
+
+use super::*;
+use crate::{sizes, Disk, Flush, OpenMode, ReadAt, Result, VhdError, VhdFile, WriteAt};
+
+/// Options for [`VhdImage::coalesce_with`].
+#[derive(Debug, Default, Copy, Clone)]
+pub struct VhdCoalesceOptions {
+    remove_child: bool,
+}
+
+impl VhdCoalesceOptions {
+    /// Deletes the differencing image once its data is in the parent.
+    pub fn remove_child(mut self, remove_child: bool) -> Self {
+        self.remove_child = remove_child;
+        self
+    }
+}
+
+// runs of consecutive set bits among the first `sectors` bits of `bitmap`, as (first sector, count)
+fn sector_runs(bitmap: &[u8], sectors: usize) -> Vec<(usize, usize)> {
+    let mut runs = Vec::new();
+    let mut sector = 0;
+
+    while sector < sectors {
+        let (present, count) = sparse::sector_run(bitmap, sector, sectors - sector);
+        if present {
+            runs.push((sector, count));
+        }
+        sector += count;
+    }
+
+    runs
+}
+
+// writes the sectors present in `child` to `parent`, returns the number of bytes written
+fn copy_blocks<F>(child: &VhdImage, parent: &VhdImage, journal: &VhdJournal, mut progress: F) -> Result<u64>
+where
+    F: FnMut(usize, usize),
+{
+    let header = child.sparse_header().unwrap();
+    let block_size = header.block_size() as u64;
+    let bitmap_size = sparse::bitmap_size(header.block_size()) as u64;
+    let entries = header.max_bat_size() as usize;
+    let capacity = child.capacity()?;
+    let parent_block_size = parent.sparse_header().map(|header| header.block_size() as u64);
+
+    let file = VhdFile::open(&child.file_path(), OpenMode::ReadOnly)?;
+    let mut bitmap = vec![0_u8; bitmap_size as usize];
+    let mut buffer = vec![0_u8; block_size as usize];
+    let mut journaled = HashSet::new();
+    let mut copied = 0;
+
+    for index in 0..entries {
+        let block_id = child.sparse_bat().unwrap().read().unwrap().block_id(index)?;
+        if block_id != bat::DD_BLOCK_UNUSED {
+            let bitmap_offset = block_id as u64 * sizes::SECTOR_U64;
+            file.read_exact_at(bitmap_offset, &mut bitmap)?;
+
+            let block_offset = index as u64 * block_size;
+            let sectors = std::cmp::min(block_size, capacity - block_offset) / sizes::SECTOR_U64;
+            for (start, count) in sector_runs(&bitmap, sectors as usize) {
+                let offset = block_offset + start as u64 * sizes::SECTOR_U64;
+                let len = count * sizes::SECTOR as usize;
+
+                // the parent content about to be overwritten is saved first
+                match parent_block_size {
+                    Some(parent_block_size) => {
+                        let first = offset / parent_block_size;
+                        let last = (offset + len as u64 - 1) / parent_block_size;
+                        for parent_index in first..=last {
+                            if journaled.insert(parent_index) {
+                                journal.add_block(parent, parent_index as usize, VHD_JOURNAL_METADATA | VHD_JOURNAL_DATA)?;
+                            }
+                        }
+                    }
+                    None => journal.add_data(offset, len as u32)?,
+                }
+
+                let data = &mut buffer[..len];
+                file.read_exact_at(bitmap_offset + bitmap_size + start as u64 * sizes::SECTOR_U64, data)?;
+                parent.write_all_at(offset, data)?;
+                copied += len as u64;
+            }
+        }
+
+        progress(index + 1, entries);
+    }
+
+    Ok(copied)
+}
+
+// grows `parent` to the size of `child` under `journal`, the parent is reopened then
+fn grow_parent(child: &VhdImage, parent: &mut VhdImage, journal: &VhdJournal) -> Result<()> {
+    let size = child.capacity()?;
+    if size <= parent.capacity()? {
+        return Ok(());
+    }
+
+    let file = VhdFile::open(&parent.file_path(), OpenMode::ReadWrite)?;
+    resize::resize_journaled(parent, journal, &file, size)?;
+    file.sync()?;
+    drop(file);
+
+    parent.reload()
+}
+
+impl VhdImage {
+    /// Merges the differencing image at `path` into its parent, like `vhd-util coalesce`.
+    ///
+    /// Returns the number of bytes written to the parent. See [`VhdImage::coalesce_with`].
+    pub fn coalesce<S: Into<String>>(path: S) -> Result<u64> {
+        Self::coalesce_with(path, &VhdCoalesceOptions::default(), |_, _| {})
+    }
+
+    /// Merges the differencing image at `path` into its parent, the parent time stamp is updated.
+    ///
+    /// Every sector present in the child is written to the parent, which is grown first if the
+    /// child is larger. The parent is protected, growth included, by a journal at
+    /// [`VhdJournal::default_path`], reverted if the merge fails and left behind if it is interrupted. `progress` is called
+    /// with the number of child blocks done and the total after each block.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{ReadAt, VhdCoalesceOptions, VhdImage, WriteAt};
+    ///
+    /// let dir = std::env::temp_dir();
+    /// let base = dir.join("rvhd_doc_coalesce_base.vhd");
+    /// let child = dir.join("rvhd_doc_coalesce_child.vhd");
+    /// drop(VhdImage::create_dynamic(base.to_str().unwrap(), 4).unwrap());
+    /// let img = VhdImage::create_diff(child.to_str().unwrap(), base.to_str().unwrap()).unwrap();
+    /// img.write_all_at(4096, &[0x5A_u8; 512]).unwrap();
+    /// drop(img);
+    ///
+    /// let options = VhdCoalesceOptions::default().remove_child(true);
+    /// let copied = VhdImage::coalesce_with(child.to_str().unwrap(), &options, |done, total| {
+    ///     println!("{}/{}", done, total);
+    /// }).unwrap();
+    /// assert_eq!(copied, 512);
+    /// assert!(!child.exists());
+    ///
+    /// let img = VhdImage::open(base.to_str().unwrap()).unwrap();
+    /// let mut buffer = [0_u8; 512];
+    /// img.read_exact_at(4096, &mut buffer).unwrap();
+    /// assert_eq!(buffer, [0x5A_u8; 512]);
+    /// # drop(img);
+    /// # std::fs::remove_file(base).unwrap();
+    /// ```
+    pub fn coalesce_with<S, F>(path: S, options: &VhdCoalesceOptions, progress: F) -> Result<u64>
+    where
+        S: Into<String>,
+        F: FnMut(usize, usize),
+    {
+        let path = path.into();
+        let child = VhdImage::open(path.as_str())?;
+        let parent_path = match child.parent() {
+            Some(parent) => parent.file_path(),
+            None => return Err(VhdError::NeedDiffImage),
+        };
+
+        let mut parent = VhdImage::open_with_mode(parent_path.as_str(), OpenMode::ReadWrite)?;
+        let jpath = VhdJournal::default_path(&parent_path);
+        let journal = VhdJournal::create(&parent, jpath.as_str())?;
+        let res = grow_parent(&child, &mut parent, &journal)
+            .and_then(|_| copy_blocks(&child, &parent, &journal, progress))
+            .and_then(|copied| {
+                parent.footer_mut().touch();
+                parent.flush()?;
+                Ok(copied)
+            });
+
+        let copied = match res {
+            Ok(copied) => {
+                journal.commit()?;
+                copied
+            }
+            Err(e) => {
+                parent.drop_stale();
+                journal.revert()?;
+                return Err(e);
+            }
+        };
+        drop(parent);
+
+        if options.remove_child {
+            drop(child);
+            std::fs::remove_file(path)?;
+        }
+
+        Ok(copied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vhd::test_util::{temp_path, pattern, read};
+
+    #[test]
+    fn sector_runs_test() {
+        assert_eq!(sector_runs(&[0b1100_0001, 0b1000_0000], 16), [(0, 2), (7, 2)]);
+        assert_eq!(sector_runs(&[0xFF], 4), [(0, 4)]);
+        assert!(sector_runs(&[0; 4], 32).is_empty());
+    }
+
+    #[test]
+    fn coalesce_dynamic_test() {
+        let base = temp_path("rvhd_coalesce_dynamic_base.vhd");
+        let child = temp_path("rvhd_coalesce_dynamic_child.vhd");
+        let old = pattern(8192, 1);
+        let new = pattern(1024, 2);
+
+        let img = VhdImage::create_dynamic(base.as_str(), 8).unwrap();
+        img.write_all_at(0, &old).unwrap();
+        drop(img);
+
+        // overwrite part of an allocated parent block, and write to an unallocated one
+        let img = VhdImage::create_diff(child.as_str(), base.as_str()).unwrap();
+        img.write_all_at(1024, &new).unwrap();
+        img.write_all_at(5 << 20, &new).unwrap();
+        drop(img);
+
+        let mut calls = Vec::new();
+        let before = vhd_time();
+        let copied = VhdImage::coalesce_with(child.as_str(), &VhdCoalesceOptions::default(), |done, total| {
+            calls.push((done, total))
+        })
+        .unwrap();
+        let after = vhd_time();
+        assert_eq!(copied, 2 * new.len() as u64);
+        assert_eq!(calls, [(1, 4), (2, 4), (3, 4), (4, 4)]);
+        assert!(std::path::Path::new(&child).exists());
+        assert!(!std::path::Path::new(&VhdJournal::default_path(&base)).exists());
+
+        let img = VhdImage::open(base.as_str()).unwrap();
+        // the parent is stamped with the time of the coalesce
+        assert!((before..=after).contains(&img.footer().timestamps()));
+        let mut expected = old.clone();
+        expected[1024..2048].copy_from_slice(&new);
+        assert!(read(&img, 0, expected.len()) == expected);
+        assert!(read(&img, 5 << 20, new.len()) == new);
+        drop(img);
+        assert!(VhdImage::check(base.as_str()).unwrap().is_ok());
+
+        std::fs::remove_file(child).unwrap();
+        std::fs::remove_file(base).unwrap();
+    }
+
+    #[test]
+    fn coalesce_fixed_test() {
+        let base = temp_path("rvhd_coalesce_fixed_base.vhd");
+        let child = temp_path("rvhd_coalesce_fixed_child.vhd");
+        let data = pattern(4096, 3);
+
+        drop(VhdImage::create_fixed(base.as_str(), 4).unwrap());
+        let img = VhdImage::create_diff(child.as_str(), base.as_str()).unwrap();
+        img.write_all_at(3 << 20, &data).unwrap();
+        drop(img);
+
+        let options = VhdCoalesceOptions::default().remove_child(true);
+        assert_eq!(VhdImage::coalesce_with(child.as_str(), &options, |_, _| {}).unwrap(), 4096);
+        assert!(!std::path::Path::new(&child).exists());
+
+        let img = VhdImage::open(base.as_str()).unwrap();
+        assert!(read(&img, 3 << 20, data.len()) == data);
+        drop(img);
+
+        std::fs::remove_file(base).unwrap();
+    }
+
+    #[test]
+    fn coalesce_grown_child_test() {
+        let base = temp_path("rvhd_coalesce_grown_base.vhd");
+        let child = temp_path("rvhd_coalesce_grown_child.vhd");
+        let data = pattern(4096, 4);
+
+        drop(VhdImage::create_dynamic(base.as_str(), 2).unwrap());
+        drop(VhdImage::create_diff(child.as_str(), base.as_str()).unwrap());
+
+        let mut img = VhdImage::open_with_mode(child.as_str(), OpenMode::ReadWrite).unwrap();
+        img.resize(6 << 20, &VhdJournal::default_path(&child)).unwrap();
+        img.write_all_at(5 << 20, &data).unwrap();
+        drop(img);
+
+        VhdImage::coalesce(child.as_str()).unwrap();
+
+        let img = VhdImage::open(base.as_str()).unwrap();
+        assert_eq!(img.capacity().unwrap(), 6 << 20);
+        assert!(read(&img, 5 << 20, data.len()) == data);
+        drop(img);
+
+        std::fs::remove_file(child).unwrap();
+        std::fs::remove_file(base).unwrap();
+    }
+
+    #[test]
+    fn coalesce_grown_revert_test() {
+        let base = temp_path("rvhd_coalesce_grown_revert_base.vhd");
+        let child = temp_path("rvhd_coalesce_grown_revert_child.vhd");
+
+        drop(VhdImage::create_dynamic(base.as_str(), 2).unwrap());
+        drop(VhdImage::create_diff(child.as_str(), base.as_str()).unwrap());
+        let original = std::fs::read(&base).unwrap();
+
+        let mut img = VhdImage::open_with_mode(child.as_str(), OpenMode::ReadWrite).unwrap();
+        img.resize(6 << 20, &VhdJournal::default_path(&child)).unwrap();
+        img.write_all_at(5 << 20, &pattern(4096, 5)).unwrap();
+        let table_offset = img.sparse_header().unwrap().table_offset();
+        drop(img);
+
+        // the block of the child cannot be read once the parent is grown
+        let file = VhdFile::open(&child, OpenMode::ReadWrite).unwrap();
+        file.write_all_at(table_offset + 2 * 4, &0x00FF_FFFF_u32.to_be_bytes()).unwrap();
+        drop(file);
+
+        assert!(VhdImage::coalesce(child.as_str()).is_err());
+        // the growth is reverted with the rest
+        assert!(std::fs::read(&base).unwrap() == original);
+        assert!(!std::path::Path::new(&VhdJournal::default_path(&base)).exists());
+
+        std::fs::remove_file(child).unwrap();
+        std::fs::remove_file(base).unwrap();
+    }
+
+    #[test]
+    fn coalesce_not_diff_test() {
+        let path = temp_path("rvhd_coalesce_not_diff.vhd");
+        drop(VhdImage::create_dynamic(path.as_str(), 2).unwrap());
+
+        assert!(matches!(VhdImage::coalesce(path.as_str()), Err(VhdError::NeedDiffImage)));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
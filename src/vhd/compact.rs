@@ -0,0 +1,240 @@
+use super::*;
+use crate::{math, sizes, Result, VhdError, VhdFile, WriteAt};
+
+// the end of the header, BAT and parent locators, where the first block may start, a locator
+// ending beyond the file is damaged and ignored
+fn data_start(header: &VhdHeader, data_offset: u64, file_size: u64) -> u64 {
+    let header_end = data_offset + std::mem::size_of::<VhdHeader>() as u64;
+    let bat_end = header.table_offset() + math::round_up(header.max_bat_size() as u64 * 4, sizes::SECTOR_U64);
+
+    header
+        .prt_loc()
+        .iter()
+        .filter(|locator| locator.prt_loc_code() != sparse::PLAT_CODE_NONE)
+        .filter_map(|locator| locator.prt_loc_offset().checked_add(locator.prt_loc_space_bytes()))
+        .filter(|locator_end| *locator_end <= file_size)
+        .fold(std::cmp::max(header_end, bat_end), std::cmp::max)
+}
+
+// whether the allocated block `index` reads as zeros, its bitmap is looked at first
+fn is_zero_block(img: &VhdImage, index: usize, buffer: &mut [u8]) -> Result<bool> {
+    if let Some((_, bitmap)) = img.sparse_block_bitmap(index)? {
+        if bitmap.iter().all(|b| *b == 0) {
+            return Ok(true);
+        }
+    }
+
+    img.sparse_block_data(index, buffer)?;
+    Ok(buffer.iter().all(|b| *b == 0))
+}
+
+fn compact_dynamic(img: &VhdImage, journal: &VhdJournal, file: &VhdFile) -> Result<()> {
+    let header = img.sparse_header().unwrap();
+    let block_size = header.block_size() as u64;
+    let block_len = sparse::bitmap_size(header.block_size()) as u64 + block_size;
+    let mut bat = bat::VhdBat::read(file, header.table_offset(), header.max_bat_size())?;
+
+    // the allocated blocks in file order
+    let mut blocks = Vec::new();
+    for index in 0..header.max_bat_size() as usize {
+        let block_id = bat.block_id(index)?;
+        if block_id != bat::DD_BLOCK_UNUSED {
+            blocks.push((block_id as u64 * sizes::SECTOR_U64, index));
+        }
+    }
+    blocks.sort_unstable();
+
+    // the kept blocks are packed after the metadata in the same order, the file is rewritten
+    // from the first block dropped or moved
+    let mut buffer = vec![0_u8; block_size as usize];
+    let mut end = data_start(header, img.footer().data_offset(), file.size()?);
+    let mut moved_blocks = Vec::new();
+    let mut rewritten_from = None;
+    for &(offset, index) in &blocks {
+        if offset < end {
+            return Err(VhdError::UnexpectedBlockId(index, bat.block_id(index)?));
+        }
+
+        if is_zero_block(img, index, &mut buffer)? {
+            bat.set_block_id(index, bat::DD_BLOCK_UNUSED)?;
+            rewritten_from.get_or_insert(offset);
+        } else {
+            if offset != end {
+                bat.set_block_id(index, (end / sizes::SECTOR_U64) as u32)?;
+                moved_blocks.push((offset, end));
+                rewritten_from.get_or_insert(offset);
+            }
+            end += block_len;
+        }
+    }
+
+    // everything is journaled before the first write, as the journal flushes the image
+    if let Some(from) = rewritten_from {
+        for &(_, index) in blocks.iter().filter(|(offset, _)| *offset >= from) {
+            journal.add_block(img, index, VHD_JOURNAL_METADATA | VHD_JOURNAL_DATA)?;
+        }
+    }
+
+    // blocks only move towards the start of the file, so a forward copy is safe
+    for (from, to) in moved_blocks {
+        resize::copy_range(file, from, to, block_len)?;
+    }
+    bat.write(file, header.table_offset())?;
+
+    file.write_all_at(end, &img.footer().to_bytes())?;
+    file.set_len(end + FOOTER_SIZE)
+}
+
+impl VhdImage {
+    /// Removes the blocks of a dynamic image that read as zeros, including the ones left empty
+    /// by [`VhdImage::discard`], and packs the others to shrink the file.
+    ///
+    /// The kept blocks keep their order and are moved to close the gaps, then the BAT and the
+    /// trailing footer are rewritten and the file is truncated. The changes are protected by a
+    /// journal at `jpath`, reverted if the compaction fails and left behind if it is interrupted,
+    /// see [`VhdJournal::open`]. Returns the number of bytes the file shrank by.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{OpenMode, VhdImage, VhdJournal, WriteAt};
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_compact.vhd");
+    /// let path = path.to_str().unwrap();
+    /// let img = VhdImage::create_dynamic(path, 4).unwrap();
+    /// img.write_all_at(0, &[0_u8; 4096]).unwrap();
+    /// drop(img);
+    ///
+    /// let mut img = VhdImage::open_with_mode(path, OpenMode::ReadWrite).unwrap();
+    /// let reclaimed = img.compact(&VhdJournal::default_path(path)).unwrap();
+    /// assert!(reclaimed >= 2 << 20);
+    /// # drop(img);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
+    pub fn compact(&mut self, jpath: &str) -> Result<u64> {
+        if self.disk_type() != VhdType::Dynamic {
+            return Err(VhdError::NeedDynamicImage);
+        }
+
+        let file_size = self.file_size()?;
+        self.journaled(jpath, compact_dynamic)?;
+
+        Ok(file_size.saturating_sub(self.file_size()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OpenMode, ReadAt};
+    use crate::vhd::test_util::temp_path;
+
+    fn pattern(len: usize, seed: usize) -> Vec<u8> {
+        (0..len).map(|i| ((i + seed) % 251) as u8 + 1).collect()
+    }
+
+    #[test]
+    fn compact_test() {
+        let path = temp_path("rvhd_compact.vhd");
+        let jpath = VhdJournal::default_path(&path);
+
+        // blocks 0 and 3 are written with zeros, block 2 is discarded
+        let img = VhdImage::create_dynamic(path.as_str(), 12).unwrap();
+        for index in 0..6_u64 {
+            img.write_all_at(index << 21, &pattern(8192, index as usize)).unwrap();
+        }
+        img.write_all_at(0, &[0_u8; 8192]).unwrap();
+        img.write_all_at(3 << 21, &[0_u8; 8192]).unwrap();
+        img.discard(2 << 21, 2 << 20).unwrap();
+        drop(img);
+
+        let mut img = VhdImage::open_with_mode(path.as_str(), OpenMode::ReadWrite).unwrap();
+        let file_size = img.file_size().unwrap();
+        let block_len = (2 << 20) + 512;
+        assert_eq!(img.compact(&jpath).unwrap(), 3 * block_len);
+        assert_eq!(img.file_size().unwrap(), file_size - 3 * block_len);
+        assert!(!std::path::Path::new(&jpath).exists());
+
+        let bat = img.sparse_bat().unwrap().read().unwrap();
+        let kept: Vec<bool> = (0..6).map(|index| bat.block_id(index).unwrap() != bat::DD_BLOCK_UNUSED).collect();
+        assert_eq!(kept, [false, true, false, false, true, true]);
+        drop(bat);
+        drop(img);
+
+        let img = VhdImage::open(path.as_str()).unwrap();
+        let mut buffer = vec![0_u8; 8192];
+        for index in 0..6_u64 {
+            img.read_exact_at(index << 21, &mut buffer).unwrap();
+            if kept[index as usize] {
+                assert!(buffer == pattern(8192, index as usize));
+            } else {
+                assert!(buffer.iter().all(|b| *b == 0));
+            }
+        }
+        drop(img);
+        assert!(VhdImage::check(path.as_str()).unwrap().is_ok());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn compact_nothing_test() {
+        let path = temp_path("rvhd_compact_nothing.vhd");
+        let jpath = VhdJournal::default_path(&path);
+
+        let img = VhdImage::create_dynamic(path.as_str(), 4).unwrap();
+        img.write_all_at(2 << 20, &pattern(4096, 1)).unwrap();
+        drop(img);
+
+        let mut img = VhdImage::open_with_mode(path.as_str(), OpenMode::ReadWrite).unwrap();
+        assert_eq!(img.compact(&jpath).unwrap(), 0);
+        let mut buffer = vec![0_u8; 4096];
+        img.read_exact_at(2 << 20, &mut buffer).unwrap();
+        assert!(buffer == pattern(4096, 1));
+        drop(img);
+        assert!(VhdImage::check(path.as_str()).unwrap().is_ok());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn compact_bogus_locator_test() {
+        let path = temp_path("rvhd_compact_bogus_locator.vhd");
+        let jpath = VhdJournal::default_path(&path);
+
+        let img = VhdImage::create_dynamic(path.as_str(), 4).unwrap();
+        img.write_all_at(0, &[0_u8; 4096]).unwrap();
+        img.write_all_at(2 << 20, &pattern(4096, 1)).unwrap();
+        drop(img);
+
+        let file = VhdFile::open(&path, OpenMode::ReadWrite).unwrap();
+        let mut header = VhdHeader::read(&file, DEFAULT_HEADER_OFFSET).unwrap();
+        header.set_locator(0, sparse::PLAT_CODE_W2KU, u64::MAX - 100, 512, 16);
+        header.write(&file, DEFAULT_HEADER_OFFSET).unwrap();
+        drop(file);
+
+        // the locator cannot be journaled, the image is left as it was
+        let mut img = VhdImage::open_with_mode(path.as_str(), OpenMode::ReadWrite).unwrap();
+        let file_size = img.file_size().unwrap();
+        assert!(img.compact(&jpath).is_err());
+        assert!(!std::path::Path::new(&jpath).exists());
+        assert_eq!(img.file_size().unwrap(), file_size);
+        let mut buffer = vec![0_u8; 4096];
+        img.read_exact_at(2 << 20, &mut buffer).unwrap();
+        assert!(buffer == pattern(4096, 1));
+        drop(img);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn compact_not_dynamic_test() {
+        let path = temp_path("rvhd_compact_not_dynamic.vhd");
+        drop(VhdImage::create_fixed(path.as_str(), 2).unwrap());
+
+        let mut img = VhdImage::open_with_mode(path.as_str(), OpenMode::ReadWrite).unwrap();
+        let res = img.compact(&VhdJournal::default_path(&path));
+        assert!(matches!(res, Err(VhdError::NeedDynamicImage)));
+        drop(img);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use super::*;
+use crate::{math, sizes, Disk, Flush, ReadAt, Result, VhdError, WriteAt};
+
+/// Amount of data handled by a conversion.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct ConvertStats {
+    /// bytes actually copied to the target
+    pub copied: u64,
+    /// all-zero bytes that were not written to the target
+    pub skipped: u64,
+}
+
+// fill `buffer` as much as possible, a short count means EOF
+fn read_chunk(file: &mut File, buffer: &mut [u8]) -> Result<usize> {
+    let mut readed = 0_usize;
+    while readed < buffer.len() {
+        match file.read(&mut buffer[readed..])? {
+            0 => break,
+            n => readed += n,
+        }
+    }
+
+    Ok(readed)
+}
+
+pub(crate) fn is_zeroes(data: &[u8]) -> bool {
+    data.iter().all(|b| *b == 0)
+}
+
+// write the non-zero sectors of `data` at `pos`, seeking over the zero ones
+fn write_sparse(raw: &mut File, pos: u64, data: &[u8], stats: &mut ConvertStats) -> Result<()> {
+    let sector = sizes::SECTOR as usize;
+    let mut run_start = None;
+
+    for (i, chunk) in data.chunks(sector).enumerate() {
+        let start = i * sector;
+        if is_zeroes(chunk) {
+            if let Some(run) = run_start.take() {
+                raw.seek(SeekFrom::Start(pos + run as u64))?;
+                raw.write_all(&data[run..start])?;
+                stats.copied += (start - run) as u64;
+            }
+            stats.skipped += chunk.len() as u64;
+        } else if run_start.is_none() {
+            run_start = Some(start);
+        }
+    }
+
+    if let Some(run) = run_start {
+        raw.seek(SeekFrom::Start(pos + run as u64))?;
+        raw.write_all(&data[run..])?;
+        stats.copied += (data.len() - run) as u64;
+    }
+
+    Ok(())
+}
+
+impl VhdImage {
+    /// Converts the raw disk image `raw_path` to a new `vhd_type` image at `path`.
+    ///
+    /// The image capacity is the raw size rounded up the same way as [`VhdImage::create_fixed`]
+    /// and [`VhdImage::create_dynamic`] do. Blocks of the raw image that are entirely zero are
+    /// not written, so they are never allocated in a dynamic image.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{VhdImage, VhdType};
+    ///
+    /// let dir = std::env::temp_dir();
+    /// let raw = dir.join("rvhd_doc_convert.img");
+    /// let vhd = dir.join("rvhd_doc_convert.vhd");
+    /// std::fs::write(&raw, vec![0x5A_u8; 4096]).unwrap();
+    ///
+    /// let stats = VhdImage::convert_raw(raw.to_str().unwrap(), vhd.to_str().unwrap(), VhdType::Dynamic).unwrap();
+    /// assert_eq!(stats.copied, 4096);
+    /// # std::fs::remove_file(raw).unwrap();
+    /// # std::fs::remove_file(vhd).unwrap();
+    /// ```
+    pub fn convert_raw<S: Into<String>>(raw_path: S, path: S, vhd_type: VhdType) -> Result<ConvertStats> {
+        Self::convert_raw_with(raw_path, path, vhd_type, &VhdCreateOptions::default())
+    }
+
+    /// Converts the raw disk image `raw_path` creating the new image with `options`,
+    /// e.g. with [`AzureMode::Pad`] to get a fixed image ready to be uploaded to Azure.
+    pub fn convert_raw_with<S: Into<String>>(
+        raw_path: S,
+        path: S,
+        vhd_type: VhdType,
+        options: &VhdCreateOptions,
+    ) -> Result<ConvertStats> {
+        if vhd_type == VhdType::Diff {
+            return Err(VhdError::NeedFixedOrDynamicImage);
+        }
+
+        let mut raw = File::open(raw_path.into())?;
+        let raw_size = raw.metadata()?.len();
+        let size_mb = math::ceil(raw_size, sizes::MIB);
+
+        let img = match vhd_type {
+            VhdType::Fixed => Self::create_fixed_with(path, size_mb, options)?,
+            _ => Self::create_dynamic_with(path, size_mb, options)?,
+        };
+
+        let block_size = match img.sparse_header() {
+            Some(header) => header.block_size(),
+            None => DD_BLOCKSIZE_DEFAULT,
+        };
+
+        let mut stats = ConvertStats::default();
+        let mut buffer = vec![0_u8; block_size as usize];
+        let mut offset = 0_u64;
+        loop {
+            let n = read_chunk(&mut raw, &mut buffer)?;
+            if n == 0 {
+                break;
+            }
+
+            let data = &buffer[..n];
+            if is_zeroes(data) {
+                stats.skipped += n as u64;
+            } else {
+                img.write_all_at(offset, data)?;
+                stats.copied += n as u64;
+            }
+
+            offset += n as u64;
+        }
+
+        debug_assert!(offset <= img.capacity()?);
+        img.flush()?;
+
+        Ok(stats)
+    }
+
+    /// Exports the virtual disk content to the raw image `raw_path`.
+    ///
+    /// Unallocated blocks of a dynamic image and all-zero sectors are not written, the output
+    /// file is sparse where the host file system supports it. Differencing images are read
+    /// through their parent, so only all-zero sectors are skipped.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{Disk, VhdImage, WriteAt};
+    ///
+    /// let dir = std::env::temp_dir();
+    /// let vhd = dir.join("rvhd_doc_export.vhd");
+    /// let raw = dir.join("rvhd_doc_export.img");
+    /// let img = VhdImage::create_dynamic(vhd.to_str().unwrap(), 4).unwrap();
+    /// img.write_all_at(4096, &[0x5A_u8; 512]).unwrap();
+    ///
+    /// let stats = img.export_raw(raw.to_str().unwrap()).unwrap();
+    /// assert_eq!(stats.copied, 512);
+    /// assert_eq!(stats.copied + stats.skipped, img.capacity().unwrap());
+    /// # drop(img);
+    /// # std::fs::remove_file(raw).unwrap();
+    /// # std::fs::remove_file(vhd).unwrap();
+    /// ```
+    pub fn export_raw<S: Into<String>>(&self, raw_path: S) -> Result<ConvertStats> {
+        let mut raw = File::create(raw_path.into())?;
+        let capacity = self.capacity()?;
+        let block_size = match self.sparse_header() {
+            Some(header) => header.block_size() as u64,
+            None => DD_BLOCKSIZE_DEFAULT as u64,
+        };
+
+        let mut stats = ConvertStats::default();
+        let mut buffer = vec![0_u8; block_size as usize];
+        let mut offset = 0_u64;
+        while offset < capacity {
+            let len = std::cmp::min(block_size, capacity - offset) as usize;
+
+            let unallocated = match (self.disk_type(), self.sparse_bat()) {
+                (VhdType::Dynamic, Some(bat)) => {
+                    let block_index = (offset / block_size) as usize;
+                    bat.read().unwrap().block_id(block_index)? == bat::DD_BLOCK_UNUSED
+                }
+                _ => false,
+            };
+
+            if unallocated {
+                stats.skipped += len as u64;
+            } else {
+                let data = &mut buffer[..len];
+                self.read_exact_at(offset, data)?;
+                write_sparse(&mut raw, offset, data, &mut stats)?;
+            }
+
+            offset += len as u64;
+        }
+
+        // the trailing zeroes are only seeked over
+        raw.set_len(capacity)?;
+
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ReadAt;
+    use crate::vhd::test_util::temp_path;
+
+    // 3 blocks of raw data: data, zeroes, data (short)
+    fn write_raw(path: &str) -> Vec<u8> {
+        let block = DD_BLOCKSIZE_DEFAULT as usize;
+        let mut raw = vec![0_u8; block * 2 + 4096];
+        for (i, b) in raw[..block].iter_mut().enumerate() {
+            *b = (i % 251) as u8;
+        }
+        raw[block * 2 + 100] = 0xA5;
+
+        std::fs::write(path, &raw).unwrap();
+        raw
+    }
+
+    #[test]
+    fn convert_raw_dynamic_test() {
+        let raw_path = temp_path("rvhd_convert_raw_dynamic.img");
+        let vhd_path = temp_path("rvhd_convert_raw_dynamic.vhd");
+        let raw = write_raw(&raw_path);
+
+        let stats = VhdImage::convert_raw(raw_path.as_str(), vhd_path.as_str(), VhdType::Dynamic).unwrap();
+        assert_eq!(stats.copied, DD_BLOCKSIZE_DEFAULT as u64 + 4096);
+        assert_eq!(stats.skipped, DD_BLOCKSIZE_DEFAULT as u64);
+
+        let img = VhdImage::open(vhd_path.as_str()).unwrap();
+        assert_eq!(img.disk_type(), VhdType::Dynamic);
+        assert_eq!(img.capacity().unwrap(), 6 << 20);
+
+        let bat = img.sparse_bat().unwrap().read().unwrap();
+        assert_ne!(bat.block_id(0).unwrap(), bat::DD_BLOCK_UNUSED);
+        assert_eq!(bat.block_id(1).unwrap(), bat::DD_BLOCK_UNUSED);
+        assert_ne!(bat.block_id(2).unwrap(), bat::DD_BLOCK_UNUSED);
+        drop(bat);
+
+        let mut data = vec![0xFF_u8; raw.len()];
+        img.read_exact_at(0, &mut data).unwrap();
+        assert!(data == raw);
+
+        drop(img);
+        std::fs::remove_file(raw_path).unwrap();
+        std::fs::remove_file(vhd_path).unwrap();
+    }
+
+    #[test]
+    fn convert_raw_fixed_test() {
+        let raw_path = temp_path("rvhd_convert_raw_fixed.img");
+        let vhd_path = temp_path("rvhd_convert_raw_fixed.vhd");
+        let raw = write_raw(&raw_path);
+
+        let stats = VhdImage::convert_raw(raw_path.as_str(), vhd_path.as_str(), VhdType::Fixed).unwrap();
+        assert_eq!(stats.copied + stats.skipped, raw.len() as u64);
+
+        let img = VhdImage::open(vhd_path.as_str()).unwrap();
+        assert_eq!(img.disk_type(), VhdType::Fixed);
+        assert_eq!(img.file_size().unwrap(), (6 << 20) + sizes::SECTOR_U64);
+
+        let mut data = vec![0xFF_u8; raw.len()];
+        img.read_exact_at(0, &mut data).unwrap();
+        assert!(data == raw);
+
+        drop(img);
+        std::fs::remove_file(raw_path).unwrap();
+        std::fs::remove_file(vhd_path).unwrap();
+    }
+
+    #[test]
+    fn export_raw_test() {
+        let raw_path = temp_path("rvhd_export_raw.img");
+        let vhd_path = temp_path("rvhd_export_raw.vhd");
+        let out_path = temp_path("rvhd_export_raw.out");
+        let raw = write_raw(&raw_path);
+        VhdImage::convert_raw(raw_path.as_str(), vhd_path.as_str(), VhdType::Dynamic).unwrap();
+
+        let img = VhdImage::open(vhd_path.as_str()).unwrap();
+        let stats = img.export_raw(out_path.as_str()).unwrap();
+        assert_eq!(stats.copied + stats.skipped, 6 << 20);
+        // the first block has a zero in every 251 bytes only, the last one a single non-zero sector
+        assert_eq!(stats.copied, DD_BLOCKSIZE_DEFAULT as u64 + sizes::SECTOR_U64);
+
+        let out = std::fs::read(&out_path).unwrap();
+        assert_eq!(out.len(), 6 << 20);
+        assert!(out[..raw.len()] == raw[..]);
+        assert!(is_zeroes(&out[raw.len()..]));
+
+        drop(img);
+        std::fs::remove_file(raw_path).unwrap();
+        std::fs::remove_file(vhd_path).unwrap();
+        std::fs::remove_file(out_path).unwrap();
+    }
+
+    #[test]
+    fn convert_raw_azure_test() {
+        let raw_path = temp_path("rvhd_convert_raw_azure.img");
+        let vhd_path = temp_path("rvhd_convert_raw_azure.vhd");
+        let raw = write_raw(&raw_path);
+
+        let options = VhdCreateOptions::default().azure(AzureMode::Pad);
+        VhdImage::convert_raw_with(raw_path.as_str(), vhd_path.as_str(), VhdType::Fixed, &options).unwrap();
+
+        let img = VhdImage::open(vhd_path.as_str()).unwrap();
+        assert!(img.azure_violations().unwrap().is_empty());
+
+        let mut data = vec![0xFF_u8; raw.len()];
+        img.read_exact_at(0, &mut data).unwrap();
+        assert!(data == raw);
+
+        drop(img);
+        std::fs::remove_file(raw_path).unwrap();
+        std::fs::remove_file(vhd_path).unwrap();
+    }
+
+    #[test]
+    fn convert_raw_diff_test() {
+        let res = VhdImage::convert_raw("raw.img", "diff.vhd", VhdType::Diff);
+        assert!(matches!(res, Err(VhdError::NeedFixedOrDynamicImage)));
+    }
+}
@@ -1,18 +1,19 @@
 use super::*;
-use crate::{ImageExtent, ReadAt, WriteAt, Flush, SeekAt, VhdFile, sizes};
+use crate::{ImageExtent, ReadAt, WriteAt, Flush, SeekAt, VhdFile};
 
 
-pub struct FixedExtent {
+pub(crate) struct FixedExtent {
     file: VhdFile,
     file_path: String,
     last_block_pos: u64,    
+    footer_size: u64,
 }
 
 // read_at and write_at offset args should be valid as they checked in the VhdImage
 
 macro_rules! debug_check {
     ($s:ident, $offset:ident, $data:ident) => {
-        debug_assert!(($offset + $data.len() as u64) <= $s.file.size().unwrap() - crate::sizes::SECTOR_U64);
+        debug_assert!(($offset + $data.len() as u64) <= $s.last_block_pos);
     };
 }
 
@@ -59,7 +60,15 @@ impl VhdImageExtent for FixedExtent {
     fn write_footer(&self, footer: &VhdFooter) -> Result<()> {
         let bytes = footer.to_bytes();                     
 
-        self.file.write_all_at(self.last_block_pos, &bytes)
+        self.file.write_all_at(self.last_block_pos, &bytes[..self.footer_size as usize])
+    }
+
+    fn footer_size(&self) -> u64 {
+        self.footer_size
+    }
+
+    fn set_footer_size(&mut self, footer_size: u64) {
+        self.footer_size = footer_size;
     }
 
     fn sparse_header(&self) -> Option<&VhdHeader> {
@@ -74,50 +83,92 @@ impl VhdImageExtent for FixedExtent {
         None
     }
 
-    fn parent_locator_data(&self, index: usize) -> Option<Vec<u8>> {
+    fn parent_locator_data(&self, _index: usize) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn read_parent_locator(&self, _index: usize) -> Option<VhdLocator> {
         None
     }
 
-    fn sparse_bat(&self) -> Option<&RefCell<bat::VhdBat>> {
+    fn parent(&self) -> Option<&VhdImage> {
         None
     }
 
-    fn sparse_block_bitmap(&self, bat_block_index: usize) -> Option<(u64, &RefCell<Vec<u8>>)> {
+    fn set_parent(&mut self, _parent: VhdImage) {}
+
+    fn sparse_bat(&self) -> Option<&RwLock<bat::VhdBat>> {
         None
     }
 
-    fn sparse_block_data(&self, bat_block_index: usize, buffer: &mut [u8]) -> Result<u64> {
+    fn sparse_block_bitmap(&self, _bat_block_index: usize) -> Result<Option<(u64, Vec<u8>)>> {
+        Ok(None)
+    }
+
+    fn set_bitmap_cache_size(&self, _blocks: usize) -> Result<()> {
+        Ok(())
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> Result<()> {
+        self.file.punch_hole(offset, len)
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64) -> Result<()> {
+        self.file.write_zeros(offset, len)
+    }
+
+    fn set_detect_zeroes(&self, _detect: bool) {}
+
+    fn sparse_block_data(&self, _bat_block_index: usize, _buffer: &mut [u8]) -> Result<u64> {
         Ok(0)
     }
+
+    fn read_vectored_at(&self, mut offset: u64, buffers: &mut [&mut [u8]]) -> Result<usize> {
+        for buffer in buffers.iter_mut() {
+            self.file.read_exact_at(offset, buffer)?;
+            offset += buffer.len() as u64;
+        }
+
+        Ok(buffers.iter().map(|buffer| buffer.len()).sum())
+    }
+
+    fn write_vectored_at(&self, mut offset: u64, buffers: &[&[u8]]) -> Result<usize> {
+        for data in buffers {
+            self.file.write_all_at(offset, data)?;
+            offset += data.len() as u64;
+        }
+
+        Ok(buffers.iter().map(|data| data.len()).sum())
+    }
 }
 
 impl FixedExtent {
-    fn new(file: VhdFile, file_path: String, last_block_pos: u64) -> Self {
-        Self { file, file_path, last_block_pos }
+    fn new(file: VhdFile, file_path: String, last_block_pos: u64, footer_size: u64) -> Self {
+        Self { file, file_path, last_block_pos, footer_size }
     }    
 
-    pub(crate) fn open(file: VhdFile, file_path: String) -> Result<Self> {
+    pub(crate) fn open(file: VhdFile, file_path: String, footer_size: u64) -> Result<Self> {
         let file_size = file.size()?;
-        let last_block_pos = file_size - sizes::SECTOR_U64;
+        let last_block_pos = file_size - footer_size;
         
-        Ok(Self::new(file, file_path, last_block_pos))
+        Ok(Self::new(file, file_path, last_block_pos, footer_size))
     }
 
     pub(crate) fn create(file_path: String, footer: &VhdFooter) -> Result<Self> {
         let file = VhdFile::create(&file_path, footer.current_size())?;
 
-        let blks = footer.current_size() >> 21;
         // FIXME: write 2meg once
         let data = [0x00_u8; 4096];
         let mut pos = 0_u64;
 
-        let data_count = (blks * DD_BLOCKSIZE_DEFAULT as u64) >> 12;        
-        for _ in 0..data_count {
-            file.write_all_at(pos, &data)?;
-            pos += 4096;
+        let size = footer.current_size();
+        while pos < size {
+            let n = std::cmp::min(data.len() as u64, size - pos) as usize;
+            file.write_all_at(pos, &data[..n])?;
+            pos += n as u64;
         }        
 
-        let this = Self::new(file, file_path, pos);
+        let this = Self::new(file, file_path, pos, FOOTER_SIZE);
         this.write_footer(footer)?;        
 
         Ok(this)
@@ -9,6 +9,17 @@ pub struct DiskGeometry {
     sectors_per_track: u8,
 }
 
+impl DiskGeometry {
+    fn with_capacity(size: u64) -> Self {
+        let geo = geometry::Geometry::with_vhd_capacity(size);
+        DiskGeometry {
+            cylinders: geo.cylinders as u16,
+            heads: geo.heads as u8,
+            sectors_per_track: geo.sectors_per_track as u8,
+        }
+    }
+}
+
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
 pub struct VhdFooter {
@@ -46,23 +57,28 @@ pub struct VhdFooter {
     reserved: [u8; 427],
 }
 
+/// Size of the footer in bytes
+pub const FOOTER_SIZE: u64 = 512;
+/// Size of the footer of images made before Microsoft Virtual PC 2004
+pub const LEGACY_FOOTER_SIZE: u64 = 511;
+
 /// VHD cookie string
 const HD_COOKIE: u64 = 0x7869_7463_656E_6F63; // big endian "conectix"
 
 /// Feature fields in VhdFooter
-const HD_NO_FEATURES: u32 = 0x0000_0000;
-const HD_TEMPORARY: u32 = 0x0000_0001;
 const HD_RESERVED: u32 = 0x0000_0002;
 
 /// Version field in VhdFooter
 const HD_FF_VERSION: u32 = 0x0001_0000;
 
 const HD_CR_OS_WINDOWS: u32 = 0x6B32_6957; /* (Wi2k) big endian */
-const HD_CR_OS_MAC: u32 = 0x2063_614D; /* (Mac ) big endian */
 
 const HD_CR_APP: u32 = 0x6468_7672; /* rvhd big endian*/
 const HD_CR_VERSION: u32 = 0x0001_0000;
 
+const HD_CR_APP_HYPERV: u32 = 0x206E_6977; /* "win " big endian */
+const HD_CR_VERSION_HYPERV: u32 = 0x000A_0000;
+
 impl VhdFooter {
     pub fn swap_bytes(&mut self) {
         self.features = self.features.swap_bytes();
@@ -88,12 +104,7 @@ impl VhdFooter {
         let vhd_time = vhd_time();
         let vhd_uuid = Uuid::new_v4();
 
-        let geo = geometry::Geometry::with_vhd_capacity(size);
-        let vhd_geo = DiskGeometry {
-            cylinders: geo.cylinders as u16,
-            heads: geo.heads as u8,
-            sectors_per_track: geo.sectors_per_track as u8,
-        };
+        let vhd_geo = DiskGeometry::with_capacity(size);
 
         use num_traits::ToPrimitive;
         let disk_type = disk_type.to_u32().unwrap();
@@ -120,9 +131,42 @@ impl VhdFooter {
     }
 
     pub fn read(stream: &impl ReadAt, pos: u64) -> Result<Self> {
-        let mut footer = unsafe { StructBuffer::<VhdFooter>::new() };
+        let footer = Self::read_unchecked(stream, pos)?;
+        if !footer.checksum_valid() {
+            return Err(VhdError::InvalidHeaderChecksum);
+        }
+
+        Ok(footer)
+    }
+
+    /// Reads the footer at the end of a `file_size` bytes image and returns it with its size.
+    ///
+    /// The last 512 bytes are probed first, then the last 511 bytes holding the footer
+    /// of images made before Virtual PC 2004.
+    pub fn read_trailing(stream: &impl ReadAt, file_size: u64) -> Result<(Self, u64)> {
+        if file_size < FOOTER_SIZE {
+            return Err(VhdError::FileTooSmall);
+        }
+
+        match Self::read(stream, file_size - FOOTER_SIZE) {
+            Ok(footer) => Ok((footer, FOOTER_SIZE)),
+            Err(e) => match Self::read_sized(stream, file_size - LEGACY_FOOTER_SIZE, LEGACY_FOOTER_SIZE) {
+                Ok(footer) if footer.checksum_valid() => Ok((footer, LEGACY_FOOTER_SIZE)),
+                _ => Err(e),
+            },
+        }
+    }
+
+    /// Reads a footer checking its cookie and disk type only, so a bad checksum can be repaired.
+    pub(crate) fn read_unchecked(stream: &impl ReadAt, pos: u64) -> Result<Self> {
+        Self::read_sized(stream, pos, FOOTER_SIZE)
+    }
+
+    // the missing bytes of a legacy footer are the last reserved ones, they stay zeroed
+    fn read_sized(stream: &impl ReadAt, pos: u64, size: u64) -> Result<Self> {
+        let mut footer = StructBuffer::<VhdFooter>::zeroed();
         stream.read_exact_at(pos, unsafe {
-           footer.as_byte_slice_mut() 
+           &mut footer.as_byte_slice_mut()[..size as usize]
         })?;
 
         if HD_COOKIE != footer.cookie {
@@ -131,15 +175,10 @@ impl VhdFooter {
 
         footer.swap_bytes();
 
-        let checksum = calc_header_checksum!(footer);
-        if footer.checksum != checksum {
-            return Err(VhdError::InvalidHeaderChecksum);
+        let disk_type: Option<VhdType> = num_traits::FromPrimitive::from_u32(footer.disk_type);
+        if disk_type.is_none() {
+            return Err(VhdError::UnknownVhdType(footer.disk_type));
         }
-        
-        let disk_type: VhdType = match num_traits::FromPrimitive::from_u32(footer.disk_type) {
-            Some(kind) => kind,
-            _ => return Err(VhdError::UnknownVhdType(footer.disk_type)),
-        };
 
         Ok(footer.copy())
     }
@@ -152,6 +191,56 @@ impl VhdFooter {
         slice.to_vec()
     }
 
+    /// Marks the image as created by Hyper-V, so every tool takes the size from `curr_size`
+    /// instead of recomputing it from the geometry.
+    pub(crate) fn set_hyperv_creator(&mut self) {
+        self.crtr_app = HD_CR_APP_HYPERV;
+        self.crtr_ver = HD_CR_VERSION_HYPERV;
+        self.crtr_os = HD_CR_OS_WINDOWS;
+        self.update_checksum();
+    }
+
+    /// Sets `curr_size` and the matching geometry, `orig_size` is kept.
+    pub(crate) fn set_current_size(&mut self, size: u64) {
+        self.curr_size = size;
+        self.geometry = DiskGeometry::with_capacity(size);
+        self.update_checksum();
+    }
+
+    /// The footer a raw disk image of `size` bytes is opened with: a fixed image with a nil UUID
+    /// and time stamp, as recorded in the header of its differencing images.
+    pub(crate) fn for_raw(size: u64) -> Self {
+        let mut footer = Self::new(size, VhdType::Fixed);
+        footer.uuid = Uuid::nil();
+        footer.timestamps = 0;
+        footer.update_checksum();
+        footer
+    }
+
+    pub(crate) fn set_hidden(&mut self, hidden: bool) {
+        self.reserved[0] = hidden as u8;
+        self.update_checksum();
+    }
+
+    /// Sets the time stamp to now.
+    pub(crate) fn touch(&mut self) {
+        self.timestamps = vhd_time();
+        self.update_checksum();
+    }
+
+    fn calc_checksum(&self) -> u32 {
+        let footer = unsafe { StructBuffer::<VhdFooter>::with_value(self) };
+        calc_header_checksum!(footer)
+    }
+
+    pub(crate) fn checksum_valid(&self) -> bool {
+        self.checksum == self.calc_checksum()
+    }
+
+    pub(crate) fn update_checksum(&mut self) {
+        self.checksum = self.calc_checksum();
+    }
+
     pub fn geometry(&self) -> Geometry {
         Geometry { 
             cylinders: self.geometry.cylinders as u64,
@@ -165,6 +254,10 @@ impl VhdFooter {
         &self.uuid
     }
 
+    pub fn original_size(&self) -> u64 {
+        self.orig_size
+    }
+
     pub fn current_size(&self) -> u64 {
         self.curr_size
     }
@@ -180,10 +273,15 @@ impl VhdFooter {
     pub fn timestamps(&self) -> u32 {
         self.timestamps
     } 
+
+    /// The first reserved byte holds the `hidden` flag used by blktap/vhd-util.
+    pub fn hidden(&self) -> bool {
+        self.reserved[0] != 0
+    }
     
     pub fn cookie(&self) -> &str {        
         let cookie = unsafe {
-            std::slice::from_raw_parts(&self.cookie as *const _ as *const u8, 8)
+            std::slice::from_raw_parts(std::ptr::addr_of!(self.cookie) as *const u8, 8)
         };
 
         std::str::from_utf8(cookie).unwrap()
@@ -192,7 +290,7 @@ impl VhdFooter {
     pub fn crtr_app(&self) -> &str {
         //let crtr_app = self.crtr_app;
         let crtr_app = unsafe {
-            std::slice::from_raw_parts(&self.crtr_app as *const _ as *const u8, 4)
+            std::slice::from_raw_parts(std::ptr::addr_of!(self.crtr_app) as *const u8, 4)
         };
 
         std::str::from_utf8(crtr_app).unwrap()
@@ -201,7 +299,7 @@ impl VhdFooter {
     pub fn crtr_os(&self) -> &str {
         //let crtr_os = self.crtr_os;
         let crtr_os = unsafe {
-            std::slice::from_raw_parts(&self.crtr_os as *const _ as *const u8, 4)
+            std::slice::from_raw_parts(std::ptr::addr_of!(self.crtr_os) as *const u8, 4)
         };
 
         std::str::from_utf8(crtr_os).unwrap()
@@ -229,19 +327,19 @@ impl std::fmt::Display for VhdFooter {
 {:<20}: {:#08X}
 {:<20}: {}\n",
             "Cookie",  self.cookie(),
-            "Features", self.features,
-            "File format version",  self.ff_version >> 16, self.ff_version >> 24,
-            "Data offset", self.data_offset,
-            "Timestamp", self.timestamps,
+            "Features", { self.features },
+            "File format version",  { self.ff_version } >> 16, { self.ff_version } >> 24,
+            "Data offset", { self.data_offset },
+            "Timestamp", { self.timestamps },
             "Creator Application", self.crtr_app(),
-            "Creator version", self.crtr_ver >> 16, self.crtr_ver >> 24,
+            "Creator version", { self.crtr_ver } >> 16, { self.crtr_ver } >> 24,
             "Creator OS", self.crtr_os(),
-            "Original disk size", self.orig_size >> 20, self.orig_size,
-            "Current disk size", self.curr_size >> 20, self.curr_size,
-            "Geometry", self.geometry.cylinders, self.geometry.heads, self.geometry.sectors_per_track,
+            "Original disk size", { self.orig_size } >> 20, { self.orig_size },
+            "Current disk size", { self.curr_size } >> 20, { self.curr_size },
+            "Geometry", { self.geometry.cylinders }, self.geometry.heads, self.geometry.sectors_per_track,
             "Disk type", vhd_type_str(self.disk_type()),
-            "Checksum", self.checksum,
-            "UUID", self.uuid().to_string(),
+            "Checksum", { self.checksum },
+            "UUID", self.uuid(),
         );
 
         f.write_str(&footer)
@@ -1,31 +1,35 @@
-use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
 
 use super::*;
-use crate::{Uuid, math, Result, sizes, ReadAt, WriteAt, Flush, VhdError, Disk, DiskImage, Geometry, VhdFile, SeekAt};
+use crate::{Uuid, math, Result, sizes, ReadAt, WriteAt, Flush, VhdError, Disk, DiskImage, Geometry, VhdFile, SeekAt, OpenMode};
 
 
 pub use sparse::VhdHeader;
 
+/// A VHD disk image (fixed, dynamic or differencing).
+///
+/// The image implements [`Disk`] and [`DiskImage`], so the virtual disk content is
+/// accessed with [`ReadAt::read_at`] and [`WriteAt::write_at`]. The footer is rewritten
+/// on [`Flush::flush`] and when the image is dropped, unless the image is opened read-only.
+///
+/// The image is `Send` and `Sync`: it may be shared between threads, which read and write
+/// it concurrently. Blocks are allocated once even when several threads write to them.
 pub struct VhdImage {
     footer: VhdFooter,
-    extent: Box<dyn VhdImageExtent>
+    extent: Box<dyn VhdImageExtent>,
+    mode: OpenMode,
 }
 
 impl Drop for VhdImage {
     fn drop(&mut self) {
-        let res = self.flush();
-        //debug_assert!(res.ok());
+        let _ = self.flush();
     }
 }
 
 impl ReadAt for VhdImage {
     fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<usize> {
-        println!("self.capacity: {}", self.capacity()?);
         match math::bound_to(self.capacity()?, offset, data.len()) {
-            Some(data_len) => {
-                println!("data_len: {}, offset: {}, buf len: {}", data_len, offset, data.len());
-                self.extent.read_at(offset, &mut data[..data_len])
-            },
+            Some(data_len) => self.extent.read_at(offset, &mut data[..data_len]),
             None => Err(VhdError::ReadBeyondEOD),
         }
     }
@@ -33,6 +37,8 @@ impl ReadAt for VhdImage {
 
 impl WriteAt for VhdImage {
     fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize> {
+        self.check_writable()?;
+
         match math::bound_to(self.capacity()?, offset, data.len()) {
             Some(data_len) => self.extent.write_at(offset, &data[..data_len]),
             None => Err(VhdError::WriteBeyondEOD),
@@ -42,6 +48,10 @@ impl WriteAt for VhdImage {
 
 impl Flush for VhdImage {
     fn flush(&self) -> Result<()> {
+        if self.mode == OpenMode::ReadOnly {
+            return Ok(());
+        }
+
         self.extent.write_footer(&self.footer)?;
         self.extent.flush()
     }
@@ -65,6 +75,50 @@ impl Disk for VhdImage {
     fn physical_sector_size(&self) -> Result<u32> {
         Ok(sizes::SECTOR)
     }
+
+    /// The buffers are read with a single request, so the runs they cover are merged
+    /// into as few file reads as possible.
+    fn read_vectored_at(&self, offset: u64, buffers: &mut [&mut [u8]]) -> Result<usize> {
+        let len = buffers.iter().map(|buffer| buffer.len()).sum();
+        let mut left = match math::bound_to(self.capacity()?, offset, len) {
+            Some(data_len) => data_len,
+            None => return Err(VhdError::ReadBeyondEOD),
+        };
+
+        // the buffers past the end of the disk are cut
+        let mut buffers: Vec<&mut [u8]> = buffers
+            .iter_mut()
+            .map(|buffer| {
+                let n = std::cmp::min(buffer.len(), left);
+                left -= n;
+                &mut buffer[..n]
+            })
+            .collect();
+        self.extent.read_vectored_at(offset, &mut buffers)
+    }
+
+    /// The buffers are written with a single request, so the runs they cover are merged
+    /// into as few file writes as possible.
+    fn write_vectored_at(&self, offset: u64, buffers: &[&[u8]]) -> Result<usize> {
+        self.check_writable()?;
+
+        let len = buffers.iter().map(|data| data.len()).sum();
+        let mut left = match math::bound_to(self.capacity()?, offset, len) {
+            Some(data_len) => data_len,
+            None => return Err(VhdError::WriteBeyondEOD),
+        };
+
+        // the buffers past the end of the disk are cut
+        let buffers: Vec<&[u8]> = buffers
+            .iter()
+            .map(|data| {
+                let n = std::cmp::min(data.len(), left);
+                left -= n;
+                &data[..n]
+            })
+            .collect();
+        self.extent.write_vectored_at(offset, &buffers)
+    }
 }
 
 impl DiskImage for VhdImage {
@@ -72,16 +126,20 @@ impl DiskImage for VhdImage {
     const EXT: &'static [&'static str] = &["vhd"];
 
     fn backing_files(&self) -> Box<dyn std::iter::Iterator<Item = String>> {
-        self.extent.backing_files()
+        let files: Vec<String> = self.chain().map(|img| img.file_path()).collect();
+        Box::new(files.into_iter())
     }
 
     fn storage_size(&self) -> Result<u64> {
-        self.extent.storage_size()
+        self.chain().map(|img| img.file_size()).sum()
     }
 }
 
+/// Maximum number of images in a differencing chain, the child included.
+pub const MAX_CHAIN_DEPTH: usize = 16;
+
 const MAX_VHD_SIZE: u64 = 2040 * sizes::GIB;
-fn check_max_size(size: u64) -> Result<()> {
+pub(super) fn check_max_size(size: u64) -> Result<()> {
     if size > MAX_VHD_SIZE {
         return Err(VhdError::DiskSizeTooBig);
     }
@@ -89,90 +147,512 @@ fn check_max_size(size: u64) -> Result<()> {
     Ok(())
 }
 
+/// Options used when creating an image.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct VhdCreateOptions {
+    /// Block size of dynamic and differencing images, a power of two of at least one sector.
+    /// Sizes given in megabytes are also rounded up to a multiple of it.
+    pub block_size: u32,
+    /// Round the size up to the nearest size the VHD CHS geometry describes exactly,
+    /// as required by Hyper-V and Azure.
+    pub chs_size: bool,
+    /// Azure upload rules, which only allow fixed images and take precedence over `chs_size`.
+    pub azure: AzureMode,
+}
+
+impl Default for VhdCreateOptions {
+    fn default() -> Self {
+        Self {
+            block_size: DD_BLOCKSIZE_DEFAULT,
+            chs_size: false,
+            azure: AzureMode::Off,
+        }
+    }
+}
+
+impl VhdCreateOptions {
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn chs_size(mut self, chs_size: bool) -> Self {
+        self.chs_size = chs_size;
+        self
+    }
+
+    pub fn azure(mut self, azure: AzureMode) -> Self {
+        self.azure = azure;
+        self
+    }
+
+    // the size of a new disk, in whole sectors
+    fn disk_size(&self, size: u64) -> Result<u64> {
+        let size = math::round_up(size, sizes::SECTOR_U64);
+        if self.azure != AzureMode::Off {
+            self.azure.disk_size(size)
+        } else if self.chs_size {
+            Ok(Geometry::vhd_chs_capacity(size))
+        } else {
+            Ok(size)
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        if !sparse::is_valid_block_size(self.block_size) {
+            return Err(VhdError::InvalidBlockSize(self.block_size));
+        }
+
+        Ok(())
+    }
+}
+
 impl VhdImage {
+    /// Creates a fixed image of `size_mb` megabytes, rounded up to a whole number of 2 MiB blocks.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{Disk, VhdImage, VhdType};
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_create_fixed.vhd");
+    /// let img = VhdImage::create_fixed(path.to_str().unwrap(), 3).unwrap();
+    /// assert_eq!(img.disk_type(), VhdType::Fixed);
+    /// assert_eq!(img.capacity().unwrap(), 4 << 20);
+    /// # drop(img);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
     pub fn create_fixed<S: Into<String>>(path: S, size_mb: u64) -> Result<Self> {        
-        let size = size_mb << 20;
-        let blks = math::ceil(size, DD_BLOCKSIZE_DEFAULT as u64) as u64;
-        let size = blks << 21;        
+        Self::create_fixed_with(path, size_mb, &VhdCreateOptions::default())
+    }
+
+    /// Creates a fixed image of `size_mb` megabytes, rounded up to a multiple of `options.block_size`.
+    pub fn create_fixed_with<S: Into<String>>(path: S, size_mb: u64, options: &VhdCreateOptions) -> Result<Self> {
+        options.check()?;
+        let size = math::round_up(size_mb << 20, options.block_size as u64);
+        Self::create_fixed_bytes(path, size, options)
+    }
+
+    /// Creates a fixed image of `size` bytes, rounded up to a whole number of sectors
+    /// and to the CHS geometry if `options.chs_size` is set.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{Disk, VhdCreateOptions, VhdImage};
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_create_fixed_bytes.vhd");
+    /// let img = VhdImage::create_fixed_bytes(path.to_str().unwrap(), 1_000_448, &VhdCreateOptions::default()).unwrap();
+    /// assert_eq!(img.capacity().unwrap(), 1_000_448);
+    /// assert_eq!(img.file_size().unwrap(), 1_000_448 + 512);
+    /// # drop(img);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
+    pub fn create_fixed_bytes<S: Into<String>>(path: S, size: u64, options: &VhdCreateOptions) -> Result<Self> {
+        options.check()?;
+        let size = options.disk_size(size)?;
 
         check_max_size(size)?;
 
         let path = path.into();               
-        let footer = VhdFooter::new(size, VhdType::Fixed);
+        let mut footer = VhdFooter::new(size, VhdType::Fixed);
+        if options.azure != AzureMode::Off {
+            footer.set_hyperv_creator();
+        }
         let extent: Box<dyn VhdImageExtent> = Box::new(FixedExtent::create(path, &footer)?);             
 
         Ok(VhdImage {
             footer,
             extent,
+            mode: OpenMode::ReadWrite,
         })
     }
 
+    /// Creates a dynamic image of `size_mb` megabytes, rounded up to a whole number of 2 MiB blocks.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{Disk, VhdImage, VhdType};
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_create_dynamic.vhd");
+    /// let img = VhdImage::create_dynamic(path.to_str().unwrap(), 8).unwrap();
+    /// assert_eq!(img.disk_type(), VhdType::Dynamic);
+    /// assert_eq!(img.capacity().unwrap(), 8 << 20);
+    /// assert_eq!(img.sparse_header().unwrap().max_bat_size(), 4);
+    /// # drop(img);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
     pub fn create_dynamic<S: Into<String>>(path: S, size_mb: u64) -> Result<Self> {
-        let size = size_mb << 20;
-        let blks = math::ceil(size, DD_BLOCKSIZE_DEFAULT as u64) as u64;
-        let size = blks << 21;
+        Self::create_dynamic_with(path, size_mb, &VhdCreateOptions::default())
+    }
+
+    /// Creates a dynamic image of `size_mb` megabytes with blocks of `options.block_size` bytes.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{Disk, VhdCreateOptions, VhdImage};
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_create_dynamic_with.vhd");
+    /// let options = VhdCreateOptions::default().block_size(512 << 10);
+    /// let img = VhdImage::create_dynamic_with(path.to_str().unwrap(), 3, &options).unwrap();
+    /// assert_eq!(img.capacity().unwrap(), 3 << 20);
+    /// assert_eq!(img.sparse_header().unwrap().block_size(), 512 << 10);
+    /// assert_eq!(img.sparse_header().unwrap().max_bat_size(), 6);
+    /// # drop(img);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
+    pub fn create_dynamic_with<S: Into<String>>(path: S, size_mb: u64, options: &VhdCreateOptions) -> Result<Self> {
+        options.check()?;
+        let size = math::round_up(size_mb << 20, options.block_size as u64);
+        Self::create_dynamic_bytes(path, size, options)
+    }
+
+    /// Creates a dynamic image of `size` bytes, rounded up to a whole number of sectors
+    /// and to the CHS geometry if `options.chs_size` is set. The last block may be partially used.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{Disk, Geometry, VhdCreateOptions, VhdImage};
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_create_dynamic_bytes.vhd");
+    /// let options = VhdCreateOptions::default().chs_size(true);
+    /// let img = VhdImage::create_dynamic_bytes(path.to_str().unwrap(), 10 << 20, &options).unwrap();
+    /// let capacity = img.capacity().unwrap();
+    /// assert!(capacity >= 10 << 20);
+    /// assert_eq!(img.geometry().unwrap().capacity(), capacity);
+    /// assert_eq!(img.footer().original_size(), capacity);
+    /// # drop(img);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
+    pub fn create_dynamic_bytes<S: Into<String>>(path: S, size: u64, options: &VhdCreateOptions) -> Result<Self> {
+        options.check()?;
+        options.azure.check_type(VhdType::Dynamic)?;
+        let size = options.disk_size(size)?;
 
         check_max_size(size)?;
 
         let path = path.into();
         let footer = VhdFooter::new(size, VhdType::Dynamic);
-        let extent: Box<dyn VhdImageExtent> = Box::new(SparseExtent::create(path, &footer, None)?);
+        let extent: Box<dyn VhdImageExtent> = Box::new(SparseExtent::create(path, &footer, options.block_size, None)?);
 
         Ok(VhdImage {
             footer,
             extent,
+            mode: OpenMode::ReadWrite,
         })
     }
 
+    /// Creates a differencing image on top of the existing dynamic or differencing image `parent`.
+    ///
+    /// Both paths must be absolute, the parent locators store the path of `parent` relative to `path`.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{Disk, VhdImage, VhdType};
+    ///
+    /// let dir = std::env::temp_dir();
+    /// let parent = dir.join("rvhd_doc_diff_parent.vhd");
+    /// let child = dir.join("rvhd_doc_diff_child.vhd");
+    /// drop(VhdImage::create_dynamic(parent.to_str().unwrap(), 4).unwrap());
+    ///
+    /// let img = VhdImage::create_diff(child.to_str().unwrap(), parent.to_str().unwrap()).unwrap();
+    /// assert_eq!(img.disk_type(), VhdType::Diff);
+    /// assert_eq!(img.capacity().unwrap(), 4 << 20);
+    /// assert_eq!(img.sparse_header().unwrap().prt_name().trim_end_matches('\0'), "rvhd_doc_diff_parent.vhd");
+    /// # drop(img);
+    /// # std::fs::remove_file(child).unwrap();
+    /// # std::fs::remove_file(parent).unwrap();
+    /// ```
     pub fn create_diff<S: Into<String>>(path: S, parent: S) -> Result<Self> {
-        let path = path.into();
-        let parent_path = parent.into();
+        Self::create_diff_with(path, parent, &VhdCreateOptions::default())
+    }
 
-        if !std::path::Path::new(&parent_path).exists() {
-            return Err(VhdError::ParentNotExist);
-        }
+    /// Creates a differencing image with blocks of `options.block_size` bytes, the capacity is the parent one.
+    ///
+    /// The parent may be a fixed, dynamic or differencing image, see [`VhdImage::snapshot`] for more choices.
+    pub fn create_diff_with<S: Into<String>>(path: S, parent: S, options: &VhdCreateOptions) -> Result<Self> {
+        Self::snapshot(path, parent, &VhdSnapshotOptions::default().create(*options))
+    }
 
-        let parent_img = Self::open(parent_path)?;
-        match parent_img.disk_type() {
-            VhdType::Fixed => return Err(VhdError::ParentNotDynamic),
-            _ => (),
-        };
+    pub(crate) fn create_diff_of(path: String, parent: VhdImage, options: &VhdCreateOptions) -> Result<Self> {
+        options.check()?;
+        options.azure.check_type(VhdType::Diff)?;
+
+        if parent.chain().count() >= MAX_CHAIN_DEPTH {
+            return Err(VhdError::ChainTooDeep);
+        }
 
-        let size = parent_img.capacity()?;
+        let size = parent.capacity()?;
         let footer = VhdFooter::new(size, VhdType::Diff);
-        let extent: Box<dyn VhdImageExtent> = Box::new(SparseExtent::create(path, &footer, Some(parent_img))?);
+        let extent: Box<dyn VhdImageExtent> = Box::new(SparseExtent::create(path, &footer, options.block_size, Some(parent))?);
 
         Ok(VhdImage {
             footer,
             extent,
+            mode: OpenMode::ReadWrite,
         })
     }
     
+    /// Opens an existing image, the image type is taken from its footer.
+    ///
+    /// The parent of a differencing image is looked up through its W2ru and W2ku parent
+    /// locators, then through the parent name relative to the image directory. It must have
+    /// the UUID recorded in the image header, and is opened the same way up to the end of the chain.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{VhdImage, VhdType};
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_open.vhd");
+    /// let id = *VhdImage::create_dynamic(path.to_str().unwrap(), 2).unwrap().id();
+    ///
+    /// let img = VhdImage::open(path.to_str().unwrap()).unwrap();
+    /// assert_eq!(img.disk_type(), VhdType::Dynamic);
+    /// assert_eq!(img.id(), &id);
+    /// println!("{}", img.footer());
+    /// println!("{}", img.sparse_header().unwrap());
+    /// # drop(img);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
     pub fn open<S: Into<String>>(path: S) -> Result<Self> {
-        let path = path.into();
-        let file = VhdFile::open(&path)?;
+        Self::open_with_mode(path, OpenMode::ReadOnly)
+    }
+
+    /// Opens an existing image with the given `mode`, parent images are always opened read-only.
+    ///
+    /// Writes to a [`OpenMode::ReadOnly`] image fail with [`VhdError::ReadOnly`].
+    /// Images with a journal left at [`VhdJournal::default_path`] by an interrupted operation
+    /// fail with [`VhdError::JournalPending`] until the journal is reverted or committed.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{OpenMode, VhdError, VhdImage, WriteAt};
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_open_mode.vhd");
+    /// drop(VhdImage::create_dynamic(path.to_str().unwrap(), 2).unwrap());
+    ///
+    /// let img = VhdImage::open_with_mode(path.to_str().unwrap(), OpenMode::ReadOnly).unwrap();
+    /// assert!(matches!(img.write_at(0, &[1_u8; 512]), Err(VhdError::ReadOnly)));
+    /// drop(img);
+    ///
+    /// let img = VhdImage::open_with_mode(path.to_str().unwrap(), OpenMode::Exclusive).unwrap();
+    /// assert_eq!(img.write_at(0, &[1_u8; 512]).unwrap(), 512);
+    /// assert!(matches!(
+    ///     VhdImage::open_with_mode(path.to_str().unwrap(), OpenMode::Exclusive),
+    ///     Err(VhdError::ImageLocked)
+    /// ));
+    /// # drop(img);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
+    pub fn open_with_mode<S: Into<String>>(path: S, mode: OpenMode) -> Result<Self> {
+        Self::open_chain(path.into(), mode, 0)
+    }
+
+    /// Opens the raw disk image `path` read-only, as a footerless fixed image with a nil UUID.
+    pub(crate) fn open_raw(path: String) -> Result<Self> {
+        let file = VhdFile::open(&path, OpenMode::ReadOnly)?;
         let file_size = file.size()?;
+        let footer = VhdFooter::for_raw(file_size - file_size % sizes::SECTOR_U64);
+        let extent: Box<dyn VhdImageExtent> = Box::new(FixedExtent::open(file, path, 0)?);
+
+        Ok(Self { footer, extent, mode: OpenMode::ReadOnly })
+    }
 
-        if file_size < sizes::SECTOR_U64 {
-            return Err(VhdError::FileTooSmall);
+    fn open_chain(path: String, mode: OpenMode, depth: usize) -> Result<Self> {
+        if depth >= MAX_CHAIN_DEPTH {
+            return Err(VhdError::ChainTooDeep);
         }
 
-        let footer_pos = file_size - sizes::SECTOR_U64;
-        let footer = VhdFooter::read(&file, footer_pos)?;
+        let journal_path = VhdJournal::default_path(&path);
+        if Path::new(&journal_path).exists() {
+            return Err(VhdError::JournalPending(journal_path));
+        }
+
+        Self::open_file(path, mode, depth)
+    }
+
+    // opens the image whatever journal is pending, its owner only may do so
+    fn open_file(path: String, mode: OpenMode, depth: usize) -> Result<Self> {
+        let file = VhdFile::open(&path, mode)?;
+        let file_size = file.size()?;
+
         // Note: Versions previous to Microsoft Virtual PC 2004 create disk images that have a 511-byte disk footer.
         // So the hard disk footer can exist in the last 511 or 512 bytes of the file that holds the hard disk image.
-        // At the moment rdisk does not support files with 511-bytes footer.
+        let (footer, footer_size) = VhdFooter::read_trailing(&file, file_size)?;
 
         let extent: Box<dyn VhdImageExtent> = match footer.disk_type() {
-            VhdType::Fixed => Box::new(FixedExtent::open(file, path)?),
-            VhdType::Dynamic | VhdType::Diff => Box::new(SparseExtent::open(file, path, footer.data_offset())?),
+            VhdType::Fixed => Box::new(FixedExtent::open(file, path, footer_size)?),
+            VhdType::Dynamic | VhdType::Diff => Box::new(SparseExtent::open(file, path, footer.data_offset(), footer_size)?),
         };
 
-        Ok(Self { footer, extent })
+        let mut img = Self { footer, extent, mode };
+        if img.disk_type() == VhdType::Diff {
+            let parent = img.open_parent(depth + 1)?;
+            img.extent.set_parent(parent);
+        }
+
+        Ok(img)
     }    
+
+    // candidate parent paths: W2ru, W2ku, and the parent name in the image directory
+    fn parent_candidates(&self) -> Vec<PathBuf> {
+        let file_path = self.file_path();
+        let dir = Path::new(&file_path).parent().unwrap_or_else(|| Path::new(""));
+        let header = self.sparse_header().unwrap();
+        let mut candidates = Vec::new();
+
+        for code in [sparse::PLAT_CODE_W2RU, sparse::PLAT_CODE_W2KU, sparse::PLAT_CODE_MACX] {
+            for (index, locator) in header.prt_loc().iter().enumerate() {
+                if locator.prt_loc_code() != code {
+                    continue;
+                }
+
+                let locator = self.extent.read_parent_locator(index);
+                if let Some(locator_path) = locator.as_ref().and_then(|locator| locator.path()) {
+                    // W2ku and W2ru locators hold Windows paths
+                    let locator_path = locator_path.replace('\\', std::path::MAIN_SEPARATOR_STR);
+                    let candidate = dir
+                        .join(locator_path)
+                        .components()
+                        .filter(|c| *c != std::path::Component::CurDir)
+                        .collect();
+                    candidates.push(candidate);
+                }
+            }
+        }
+
+        let prt_name = header.prt_name();
+        let prt_name = prt_name.trim_end_matches('\0');
+        if !prt_name.is_empty() {
+            candidates.push(dir.join(prt_name));
+        }
+
+        candidates
+    }
+
+    fn open_parent(&self, depth: usize) -> Result<Self> {
+        let prt_uuid = self.sparse_header().unwrap().prt_uuid();
+        let mut uuid_mismatch = false;
+        let mut open_error = None;
+
+        for candidate in self.parent_candidates() {
+            if !candidate.is_file() {
+                continue;
+            }
+
+            // a nil parent UUID marks a raw parent; a stale locator may point to a file
+            // that does not open, the next candidates are tried then
+            let candidate = candidate.to_string_lossy().into_owned();
+            let parent = if prt_uuid.is_nil() {
+                Self::open_raw(candidate)
+            } else {
+                Self::open_chain(candidate, OpenMode::ReadOnly, depth)
+            };
+            match parent {
+                Ok(parent) if *parent.id() == prt_uuid => return Ok(parent),
+                Ok(_) => uuid_mismatch = true,
+                Err(e) => {
+                    open_error.get_or_insert(e);
+                }
+            }
+        }
+
+        if uuid_mismatch {
+            Err(VhdError::ParentUuidMismatch)
+        } else {
+            Err(open_error.unwrap_or(VhdError::ParentNotExist))
+        }
+    }
 }
 
 impl VhdImage {
+    pub fn open_mode(&self) -> OpenMode {
+        self.mode
+    }
+
+    /// Returns `true` for a raw disk image opened as the parent of a differencing image.
+    pub fn is_raw(&self) -> bool {
+        self.footer_size() == 0
+    }
+
+    /// Returns `true` if the image has the 511-byte footer of images made before Virtual PC 2004.
+    pub fn has_legacy_footer(&self) -> bool {
+        self.footer_size() == LEGACY_FOOTER_SIZE
+    }
+
+    pub(crate) fn footer_size(&self) -> u64 {
+        self.extent.footer_size()
+    }
+
+    /// Writes a standard 512-byte footer from the next flush on, the file grows by one byte.
+    /// Images with a legacy footer otherwise keep it.
+    pub fn upgrade_footer(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.extent.set_footer_size(FOOTER_SIZE);
+
+        Ok(())
+    }
+
+    /// Runs `op` on a raw handle of the image file under a journal at `jpath`, then reopens the image.
+    /// The journal is reverted if `op` fails; if the revert fails too the image is left read-only.
+    pub(crate) fn journaled<F>(&mut self, jpath: &str, op: F) -> Result<()>
+    where
+        F: FnOnce(&VhdImage, &VhdJournal, &VhdFile) -> Result<()>,
+    {
+        self.check_writable()?;
+
+        let journal = VhdJournal::create(self, jpath)?;
+        let file = VhdFile::open(&self.file_path(), OpenMode::ReadWrite)?;
+        let res = op(self, &journal, &file).and_then(|_| file.sync());
+        drop(file);
+
+        // the cached metadata is stale from now on and must never be flushed
+        let mode = self.mode;
+        self.mode = OpenMode::ReadOnly;
+
+        match res {
+            Ok(()) => {
+                journal.commit()?;
+                self.reopen(mode)
+            }
+            Err(e) => {
+                journal.revert()?;
+                self.reopen(mode)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Drops the image without flushing its stale cached metadata, as after a journal revert.
+    pub(crate) fn drop_stale(mut self) {
+        self.mode = OpenMode::ReadOnly;
+    }
+
+    /// Reopens the image after its file was changed through another handle under a journal
+    /// still pending, without flushing the stale cached metadata.
+    pub(crate) fn reload(&mut self) -> Result<()> {
+        let path = self.file_path();
+        let mode = self.mode;
+        self.mode = OpenMode::ReadOnly;
+
+        // the stale image is dropped first, releasing its lock
+        *self = Self::open_file(path.clone(), OpenMode::ReadOnly, 0)?;
+        *self = Self::open_file(path, mode, 0)?;
+
+        Ok(())
+    }
+
+    pub(crate) fn footer_mut(&mut self) -> &mut VhdFooter {
+        &mut self.footer
+    }
+
+    fn reopen(&mut self, mode: OpenMode) -> Result<()> {
+        let path = self.file_path();
+
+        // the stale image is dropped first, releasing its lock
+        *self = Self::open_with_mode(path.as_str(), OpenMode::ReadOnly)?;
+        *self = Self::open_with_mode(path, mode)?;
+
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        match self.mode {
+            OpenMode::ReadOnly => Err(VhdError::ReadOnly),
+            _ => Ok(()),
+        }
+    }
+
     pub fn disk_type(&self) -> VhdType {
         self.footer.disk_type()
     }
@@ -181,10 +661,37 @@ impl VhdImage {
         self.footer.uuid()
     }
 
+    /// Returns the hard disk footer of the image.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{VhdImage, VhdType};
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_footer.vhd");
+    /// let img = VhdImage::create_fixed(path.to_str().unwrap(), 2).unwrap();
+    /// let footer = img.footer();
+    /// assert_eq!(footer.cookie(), "conectix");
+    /// assert_eq!(footer.disk_type(), VhdType::Fixed);
+    /// assert_eq!(footer.current_size(), 2 << 20);
+    /// # drop(img);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
     pub fn footer(&self) -> &VhdFooter {
         &self.footer
     }
 
+    /// Returns the dynamic disk header, `None` for fixed images.
+    ///
+    /// ```
+    /// use rvhd_util_convert::VhdImage;
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_sparse_header.vhd");
+    /// let img = VhdImage::create_dynamic(path.to_str().unwrap(), 2).unwrap();
+    /// let header = img.sparse_header().unwrap();
+    /// assert_eq!(header.cookie(), "cxsparse");
+    /// assert_eq!(header.block_size(), 2 << 20);
+    /// # drop(img);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
     pub fn sparse_header(&self) -> Option<&VhdHeader> {
         self.extent.sparse_header()
     }
@@ -197,30 +704,186 @@ impl VhdImage {
         self.extent.parent_locator()
     }    
 
+    /// Returns the parent of a differencing image.
+    pub fn parent(&self) -> Option<&VhdImage> {
+        self.extent.parent()
+    }
+
+    /// Iterates over the image and all its ancestors, from the child to the root.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{VhdImage, VhdType};
+    ///
+    /// let dir = std::env::temp_dir();
+    /// let base = dir.join("rvhd_doc_chain_base.vhd");
+    /// let child = dir.join("rvhd_doc_chain_child.vhd");
+    /// drop(VhdImage::create_dynamic(base.to_str().unwrap(), 2).unwrap());
+    /// drop(VhdImage::create_diff(child.to_str().unwrap(), base.to_str().unwrap()).unwrap());
+    ///
+    /// let img = VhdImage::open(child.to_str().unwrap()).unwrap();
+    /// let types: Vec<VhdType> = img.chain().map(|img| img.disk_type()).collect();
+    /// assert_eq!(types, [VhdType::Diff, VhdType::Dynamic]);
+    /// # drop(img);
+    /// # std::fs::remove_file(child).unwrap();
+    /// # std::fs::remove_file(base).unwrap();
+    /// ```
+    pub fn chain(&self) -> impl Iterator<Item = &VhdImage> {
+        std::iter::successors(Some(self), |img| img.parent())
+    }
+
     pub fn file_size(&self) -> Result<u64> {
         self.extent.storage_size()
     }
 
+    /// Returns the decoded parent locators of a differencing image, in header order.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{VhdImage, VhdLocator};
+    ///
+    /// let dir = std::env::temp_dir();
+    /// let parent = dir.join("rvhd_doc_locators_parent.vhd");
+    /// let child = dir.join("rvhd_doc_locators_child.vhd");
+    /// drop(VhdImage::create_dynamic(parent.to_str().unwrap(), 2).unwrap());
+    ///
+    /// let img = VhdImage::create_diff(child.to_str().unwrap(), parent.to_str().unwrap()).unwrap();
+    /// let locators = img.parent_locators();
+    /// assert_eq!(locators[0], VhdLocator::W2ku(parent.to_str().unwrap().replace('/', "\\")));
+    /// assert_eq!(locators[1], VhdLocator::W2ru(".\\rvhd_doc_locators_parent.vhd".to_string()));
+    /// # drop(img);
+    /// # std::fs::remove_file(child).unwrap();
+    /// # std::fs::remove_file(parent).unwrap();
+    /// ```
+    pub fn parent_locators(&self) -> Vec<VhdLocator> {
+        let count = self.sparse_header().map_or(0, |header| header.prt_loc().len());
+        (0..count).filter_map(|index| self.extent.read_parent_locator(index)).collect()
+    }
+
     pub fn parent_locator_data(&self, index: usize) -> Option<Vec<u8>> {
         self.extent.parent_locator_data(index)
     }
 
-    pub fn sparse_bat(&self) -> Option<&RefCell<bat::VhdBat>> {
+    pub(crate) fn sparse_bat(&self) -> Option<&RwLock<bat::VhdBat>> {
         self.extent.sparse_bat()
     }
 
-    pub fn sparse_block_bitmap(&self, bat_block_index: usize) -> Option<(u64, &RefCell<Vec<u8>>)> {
+    /// Returns the file offset and a copy of the sector bitmap of a block, None if the block
+    /// is not allocated or the image is fixed.
+    pub fn sparse_block_bitmap(&self, bat_block_index: usize) -> Result<Option<(u64, Vec<u8>)>> {
         self.extent.sparse_block_bitmap(bat_block_index)
     }
 
+    /// Reads the content of an allocated block into `buffer`, up to the block size. Sectors that
+    /// are not present read as zeros, or from the parent of a differencing image. Returns the
+    /// file offset of the block data.
     pub fn sparse_block_data(&self, bat_block_index: usize, buffer: &mut [u8]) -> Result<u64> {
         self.extent.sparse_block_data(bat_block_index, buffer)
     }
+
+    /// Tells the image that `len` bytes from `offset` are no longer used, like a TRIM command.
+    ///
+    /// The range reads as zeros afterwards. Dynamic images clear the bitmap bits of the whole
+    /// sectors in the range and release the blocks left empty; their space in the file is
+    /// released by the file system where supported and reclaimed by compaction. Differencing
+    /// images write zeros like [`VhdImage::write_zeroes`], so the parent data stays hidden.
+    /// Fixed images punch holes in the file.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{ReadAt, VhdImage, WriteAt};
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_discard.vhd");
+    /// let img = VhdImage::create_dynamic(path.to_str().unwrap(), 4).unwrap();
+    /// img.write_all_at(0, &[1_u8; 4096]).unwrap();
+    ///
+    /// img.discard(0, 2 << 20).unwrap();
+    /// let mut buffer = [1_u8; 4096];
+    /// img.read_exact_at(0, &mut buffer).unwrap();
+    /// assert_eq!(buffer, [0_u8; 4096]);
+    /// # drop(img);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
+    pub fn discard(&self, offset: u64, len: u64) -> Result<()> {
+        self.check_writable()?;
+
+        match offset.checked_add(len) {
+            Some(end) if end <= self.capacity()? => self.extent.discard(offset, len),
+            _ => Err(VhdError::WriteBeyondEOD),
+        }
+    }
+
+    /// Zeroes `len` bytes from `offset` without allocating blocks that already read as zeros.
+    ///
+    /// Unallocated blocks of dynamic images are left as they are. Unallocated blocks of
+    /// differencing images are allocated only when the parent has non-zero data in the range.
+    /// Allocated blocks and fixed images are written with zeros.
+    ///
+    /// ```
+    /// use rvhd_util_convert::VhdImage;
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_write_zeroes.vhd");
+    /// let img = VhdImage::create_dynamic(path.to_str().unwrap(), 4).unwrap();
+    /// let size = img.file_size().unwrap();
+    ///
+    /// img.write_zeroes(0, 4 << 20).unwrap();
+    /// assert_eq!(img.file_size().unwrap(), size);
+    /// # drop(img);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
+    pub fn write_zeroes(&self, offset: u64, len: u64) -> Result<()> {
+        self.check_writable()?;
+
+        match offset.checked_add(len) {
+            Some(end) if end <= self.capacity()? => self.extent.write_zeroes(offset, len),
+            _ => Err(VhdError::WriteBeyondEOD),
+        }
+    }
+
+    /// Sets whether [`WriteAt::write_at`] looks for zeros, off by default. When set, the parts
+    /// of a write covering a block with zeros only are written like [`VhdImage::write_zeroes`],
+    /// at the cost of scanning the data. Fixed images ignore it.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{VhdImage, WriteAt};
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_detect_zeroes.vhd");
+    /// let img = VhdImage::create_dynamic(path.to_str().unwrap(), 4).unwrap();
+    /// let size = img.file_size().unwrap();
+    ///
+    /// img.set_detect_zeroes(true);
+    /// img.write_all_at(0, &vec![0_u8; 2 << 20]).unwrap();
+    /// assert_eq!(img.file_size().unwrap(), size);
+    /// # drop(img);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
+    pub fn set_detect_zeroes(&self, detect: bool) {
+        self.extent.set_detect_zeroes(detect)
+    }
+
+    /// Sets the number of block bitmaps the image keeps cached, at least one, by default
+    /// [`DEFAULT_BITMAP_CACHE_SIZE`]. Changed bitmaps are written back when evicted and on flush.
+    /// Fixed images have no bitmaps, parent images keep their own cache size.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{VhdImage, WriteAt};
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_bitmap_cache.vhd");
+    /// let img = VhdImage::create_dynamic(path.to_str().unwrap(), 8).unwrap();
+    /// img.set_bitmap_cache_size(1).unwrap();
+    /// for offset in [0, 2 << 20, 0, 4 << 20] {
+    ///     img.write_all_at(offset, &[1_u8; 512]).unwrap();
+    /// }
+    /// # drop(img);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
+    pub fn set_bitmap_cache_size(&self, blocks: usize) -> Result<()> {
+        self.extent.set_bitmap_cache_size(blocks)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::DiskRequest;
+    use crate::vhd::test_util::temp_path;
 
     #[test]
     fn create_fixed_test() {
@@ -251,7 +914,447 @@ mod tests {
         assert_eq!(vhd_dynamic.footer().current_size(), 2 << 20);        
 
         println!("{}", vhd_dynamic.footer());
-        println!("{}", vhd_dynamic.sparse_header().as_deref().unwrap());
+        println!("{}", vhd_dynamic.sparse_header().unwrap());
+    }
+
+    #[test]
+    fn write_read_dynamic_test() {
+        let path = std::env::temp_dir().join("rvhd_write_read_dynamic.vhd");
+        let path = path.to_str().unwrap();
+        let vhd_dynamic = VhdImage::create_dynamic(path, 4).unwrap();
+
+        // unaligned write crossing a sector and a block boundary
+        let data: Vec<u8> = (0..3000).map(|i| (i % 255) as u8 + 1).collect();
+        let offset = (2 << 20) - 1000;
+        vhd_dynamic.write_all_at(offset, &data).unwrap();
+        vhd_dynamic.write_all_at(10, &data[..5]).unwrap();
+        drop(vhd_dynamic);
+
+        let vhd_dynamic = VhdImage::open(path).unwrap();
+        let mut buffer = vec![0xFF_u8; 3000];
+        vhd_dynamic.read_exact_at(offset, &mut buffer).unwrap();
+        assert_eq!(buffer, data);
+
+        let mut buffer = vec![0xFF_u8; 1024];
+        vhd_dynamic.read_exact_at(0, &mut buffer).unwrap();
+        assert_eq!(&buffer[10..15], &data[..5]);
+        assert!(buffer[..10].iter().chain(&buffer[15..]).all(|b| *b == 0));
+
+        drop(vhd_dynamic);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn block_size_test() {
+        let path = temp_path("rvhd_block_size.vhd");
+        let options = VhdCreateOptions::default().block_size(512 << 10);
+        let img = VhdImage::create_dynamic_with(path.as_str(), 3, &options).unwrap();
+
+        let data: Vec<u8> = (0..(1 << 20)).map(|i| (i % 253) as u8).collect();
+        img.write_all_at((512 << 10) - 512, &data).unwrap();
+        drop(img);
+
+        let img = VhdImage::open(path.as_str()).unwrap();
+        let header = img.sparse_header().unwrap();
+        assert_eq!(header.block_size(), 512 << 10);
+        assert_eq!(header.max_bat_size(), 6);
+
+        let bat = img.sparse_bat().unwrap().read().unwrap();
+        let allocated = (0..6).filter(|i| bat.block_id(*i).unwrap() != bat::DD_BLOCK_UNUSED).count();
+        assert_eq!(allocated, 3);
+        drop(bat);
+
+        let mut buffer = vec![0_u8; data.len()];
+        img.read_exact_at((512 << 10) - 512, &mut buffer).unwrap();
+        assert!(buffer == data);
+        drop(img);
+
+        // a header with an invalid block size is refused
+        let file = VhdFile::open(&path, OpenMode::ReadWrite).unwrap();
+        let (header, _) = VhdHeader::new(3 << 20, DEFAULT_TABLE_OFFSET, 3000, &path, &None).unwrap();
+        header.write(&file, DEFAULT_HEADER_OFFSET).unwrap();
+        drop(file);
+        assert!(matches!(VhdImage::open(path.as_str()), Err(VhdError::InvalidBlockSize(3000))));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn byte_size_test() {
+        let path = temp_path("rvhd_byte_size.vhd");
+        let size = (3 << 20) + 3 * sizes::SECTOR_U64;
+        let img = VhdImage::create_dynamic_bytes(path.as_str(), size - 100, &VhdCreateOptions::default()).unwrap();
+        assert_eq!(img.capacity().unwrap(), size);
+        assert_eq!(img.sparse_header().unwrap().max_bat_size(), 2);
+
+        img.write_all_at(size - 512, &[7_u8; 512]).unwrap();
+        assert!(matches!(img.write_at(size + 512, &[7_u8; 512]), Err(VhdError::WriteBeyondEOD)));
+        drop(img);
+
+        let img = VhdImage::open(path.as_str()).unwrap();
+        assert_eq!(img.footer().original_size(), size);
+        assert_eq!(img.footer().current_size(), size);
+
+        let mut buffer = [0_u8; 1024];
+        assert_eq!(img.read_at(size - 512, &mut buffer).unwrap(), 512);
+        assert!(buffer[..512].iter().all(|b| *b == 7));
+
+        drop(img);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_block_size_test() {
+        for block_size in [0, 256, 3 << 20] {
+            let options = VhdCreateOptions::default().block_size(block_size);
+            let res = VhdImage::create_dynamic_with("invalid.vhd", 4, &options);
+            assert!(matches!(res, Err(VhdError::InvalidBlockSize(n)) if n == block_size));
+        }
+    }
+
+    #[test]
+    fn open_mode_test() {
+        let path = temp_path("rvhd_open_mode.vhd");
+        drop(VhdImage::create_dynamic(path.as_str(), 4).unwrap());
+        let created = std::fs::read(&path).unwrap();
+
+        let img = VhdImage::open(path.as_str()).unwrap();
+        assert_eq!(img.open_mode(), OpenMode::ReadOnly);
+        assert!(matches!(img.write_all_at(0, &[1_u8; 512]), Err(VhdError::ReadOnly)));
+        img.flush().unwrap();
+        drop(img);
+        // the footer is never rewritten
+        assert!(std::fs::read(&path).unwrap() == created);
+
+        let img = VhdImage::open_with_mode(path.as_str(), OpenMode::ReadWrite).unwrap();
+        img.write_all_at(2 << 20, &[1_u8; 512]).unwrap();
+        drop(img);
+
+        let img = VhdImage::open(path.as_str()).unwrap();
+        let mut buffer = [0_u8; 512];
+        img.read_exact_at(2 << 20, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|b| *b == 1));
+
+        drop(img);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn vectored_io_test() {
+        let base = temp_path("rvhd_vectored_base.vhd");
+        let child = temp_path("rvhd_vectored_child.vhd");
+        let block_size = 64 << 10;
+
+        let img = VhdImage::create_dynamic(base.as_str(), 2).unwrap();
+        img.write_all_at(0, &[0xEE_u8; 1 << 20]).unwrap();
+        drop(img);
+
+        let options = VhdCreateOptions::default().block_size(block_size);
+        let img = VhdImage::create_diff_with(child.as_str(), base.as_str(), &options).unwrap();
+        img.write_all_at(1024, &[1_u8; 512]).unwrap();
+
+        // an unaligned write over three blocks, physically adjacent once allocated
+        let data: Vec<u8> = (0..(2 * block_size as usize + 1000)).map(|i| (i % 251) as u8).collect();
+        let offset = block_size as u64 - 300;
+        let (head, tail) = data.split_at(700);
+        let (middle, tail) = tail.split_at(block_size as usize);
+        assert_eq!(img.write_vectored_at(offset, &[head, middle, tail]).unwrap(), data.len());
+
+        let mut first = vec![0_u8; 1000];
+        let mut second = vec![0_u8; data.len() - 1000];
+        assert_eq!(img.read_vectored_at(offset, &mut [&mut first, &mut second]).unwrap(), data.len());
+        assert!(first == data[..1000] && second == data[1000..]);
+
+        // partial sectors and parent runs split between buffers
+        let (head, tail) = data.split_at(100);
+        assert_eq!(img.write_vectored_at(offset, &[head, tail]).unwrap(), data.len());
+        let mut first = vec![0_u8; 600];
+        let mut second = vec![0_u8; 500];
+        let mut third = vec![0_u8; data.len() - 100];
+        assert_eq!(img.read_vectored_at(offset - 1000, &mut [&mut first, &mut second, &mut third]).unwrap(), data.len() + 1000);
+        assert!(first.iter().chain(&second[..400]).all(|b| *b == 0xEE));
+        assert!(second[400..] == data[..100] && third == data[100..]);
+        drop(img);
+
+        let img = VhdImage::open(child.as_str()).unwrap();
+        let mut buffer = vec![0_u8; data.len() + 600];
+        img.read_exact_at(offset - 300, &mut buffer).unwrap();
+        assert!(buffer[..300].iter().all(|b| *b == 0xEE));
+        assert!(buffer[300..300 + data.len()] == data);
+        assert!(buffer[300 + data.len()..].iter().all(|b| *b == 0xEE));
+
+        // runs of the child and of the parent within a block
+        let mut buffer = vec![0_u8; 2048];
+        img.read_exact_at(0, &mut buffer).unwrap();
+        assert!(buffer[..1024].iter().chain(&buffer[1536..]).all(|b| *b == 0xEE));
+        assert!(buffer[1024..1536].iter().all(|b| *b == 1));
+        drop(img);
+        assert!(VhdImage::check(child.as_str()).unwrap().is_ok());
+
+        std::fs::remove_file(child).unwrap();
+        std::fs::remove_file(base).unwrap();
+    }
+
+    #[test]
+    fn submit_test() {
+        let path = temp_path("rvhd_submit.vhd");
+        let img = VhdImage::create_dynamic(path.as_str(), 4).unwrap();
+
+        let (mut first, mut second, mut third) = ([0_u8; 512], [0_u8; 512], [0_u8; 512]);
+        let mut read = [0_u8; 2048];
+        let mut requests = [
+            DiskRequest::Write(4096, &[1_u8; 512]),
+            DiskRequest::Write(4608, &[2_u8; 512]),
+            DiskRequest::Write(2 << 20, &[3_u8; 100]),
+            DiskRequest::Read(4096, &mut first),
+            DiskRequest::Read(4608, &mut second),
+            DiskRequest::Read((2 << 20) - 512, &mut third),
+            DiskRequest::Read(2 << 20, &mut read),
+        ];
+        img.submit(&mut requests).unwrap();
+
+        assert_eq!(first, [1_u8; 512]);
+        assert_eq!(second, [2_u8; 512]);
+        assert_eq!(third, [0_u8; 512]);
+        assert!(read[..100].iter().all(|b| *b == 3) && read[100..].iter().all(|b| *b == 0));
+
+        // reads beyond the end of the disk fail
+        let mut requests = [DiskRequest::Read((4 << 20) - 512, &mut read)];
+        assert!(img.submit(&mut requests).is_err());
+
+        drop(img);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn discard_fixed_test() {
+        let path = temp_path("rvhd_discard_fixed.vhd");
+        let img = VhdImage::create_fixed(path.as_str(), 2).unwrap();
+        img.write_all_at(0, &[1_u8; 1 << 20]).unwrap();
+
+        img.discard(4096, 64 << 10).unwrap();
+        assert!(matches!(img.discard(1 << 20, 2 << 20), Err(VhdError::WriteBeyondEOD)));
+        drop(img);
+
+        let img = VhdImage::open(path.as_str()).unwrap();
+        let mut buffer = vec![0_u8; 1 << 20];
+        img.read_exact_at(0, &mut buffer).unwrap();
+        assert!(buffer[..4096].iter().all(|b| *b == 1));
+        assert!(buffer[4096..(68 << 10)].iter().all(|b| *b == 0));
+        assert!(buffer[(68 << 10)..].iter().all(|b| *b == 1));
+        assert!(matches!(img.discard(0, 512), Err(VhdError::ReadOnly)));
+        drop(img);
+        assert!(VhdImage::check(path.as_str()).unwrap().is_ok());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn concurrent_write_test() {
+        let base = temp_path("rvhd_concurrent_base.vhd");
+        let child = temp_path("rvhd_concurrent_child.vhd");
+        let block_size = 64 << 10;
+
+        let img = VhdImage::create_dynamic(base.as_str(), 2).unwrap();
+        img.write_all_at(0, &[0xEE_u8; 2 << 20]).unwrap();
+        drop(img);
+
+        // every thread allocates its own blocks, and writes single bytes of shared sectors
+        let options = VhdCreateOptions::default().block_size(block_size);
+        let img = VhdImage::create_diff_with(child.as_str(), base.as_str(), &options).unwrap();
+        std::thread::scope(|scope| {
+            for thread in 0..8_u64 {
+                let img = &img;
+                scope.spawn(move || {
+                    for block in (thread..32).step_by(8) {
+                        let offset = block * block_size as u64;
+                        img.write_all_at(offset + 512, &[thread as u8 + 1; 4096]).unwrap();
+                        img.write_all_at(offset + thread, &[thread as u8 + 1]).unwrap();
+                        img.write_all_at(thread, &[thread as u8 + 1]).unwrap();
+                    }
+                });
+            }
+        });
+        drop(img);
+
+        let img = VhdImage::open(child.as_str()).unwrap();
+        let bat = img.sparse_bat().unwrap().read().unwrap();
+        let mut block_ids: Vec<u32> = (0..32).map(|i| bat.block_id(i).unwrap()).collect();
+        drop(bat);
+        block_ids.sort_unstable();
+        block_ids.dedup();
+        assert_eq!(block_ids.len(), 32);
+        assert!(!block_ids.contains(&bat::DD_BLOCK_UNUSED));
+
+        let mut buffer = vec![0_u8; 512 + 4096 + 512];
+        for block in 0..32_u64 {
+            let value = (block % 8) as u8 + 1;
+            img.read_exact_at(block * block_size as u64, &mut buffer).unwrap();
+            assert_eq!(buffer[(block % 8) as usize], value);
+            assert!(buffer[512..4608].iter().all(|b| *b == value));
+            assert!(buffer[4608..].iter().all(|b| *b == 0xEE));
+        }
+        img.read_exact_at(0, &mut buffer[..512]).unwrap();
+        assert_eq!(&buffer[..9], &[1, 2, 3, 4, 5, 6, 7, 8, 0xEE]);
+        drop(img);
+        assert!(VhdImage::check(child.as_str()).unwrap().is_ok());
+
+        std::fs::remove_file(child).unwrap();
+        std::fs::remove_file(base).unwrap();
+    }
+
+    // drop the last footer byte, a reserved one, as Virtual PC did before 2004
+    fn make_legacy(path: &str) -> u64 {
+        let file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+        let size = file.metadata().unwrap().len() - 1;
+        file.set_len(size).unwrap();
+        size
+    }
+
+    #[test]
+    fn legacy_footer_test() {
+        let fixed = temp_path("rvhd_legacy_fixed.vhd");
+        let dynamic = temp_path("rvhd_legacy_dynamic.vhd");
+
+        let img = VhdImage::create_fixed(fixed.as_str(), 2).unwrap();
+        img.write_all_at((2 << 20) - 512, &[7_u8; 512]).unwrap();
+        drop(img);
+        drop(VhdImage::create_dynamic(dynamic.as_str(), 4).unwrap());
+
+        for path in [&fixed, &dynamic] {
+            let legacy_size = make_legacy(path);
+            assert!(VhdImage::check(path.as_str()).unwrap().is_ok());
+
+            // the legacy footer is kept when writing...
+            let img = VhdImage::open_with_mode(path.as_str(), OpenMode::ReadWrite).unwrap();
+            assert!(img.has_legacy_footer());
+            img.write_all_at(1 << 20, &[1_u8; 512]).unwrap();
+            let mut buffer = [0_u8; 512];
+            img.read_exact_at((2 << 20) - 512, &mut buffer).unwrap();
+            drop(img);
+
+            let img = VhdImage::open(path.as_str()).unwrap();
+            assert!(img.has_legacy_footer());
+            img.read_exact_at(1 << 20, &mut buffer).unwrap();
+            assert!(buffer.iter().all(|b| *b == 1));
+            let file_size = img.file_size().unwrap();
+            assert_eq!(file_size % 512, 511);
+            assert!(file_size >= legacy_size);
+            drop(img);
+
+            // ...unless the image is upgraded
+            let mut img = VhdImage::open_with_mode(path.as_str(), OpenMode::ReadWrite).unwrap();
+            img.upgrade_footer().unwrap();
+            drop(img);
+
+            let img = VhdImage::open(path.as_str()).unwrap();
+            assert!(!img.has_legacy_footer());
+            assert_eq!(img.file_size().unwrap(), file_size + 1);
+            drop(img);
+        }
+
+        let img = VhdImage::open(fixed.as_str()).unwrap();
+        let mut buffer = [0_u8; 512];
+        img.read_exact_at((2 << 20) - 512, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|b| *b == 7));
+        drop(img);
+
+        std::fs::remove_file(fixed).unwrap();
+        std::fs::remove_file(dynamic).unwrap();
+    }
+
+    #[test]
+    fn open_diff_chain_test() {
+        let base = temp_path("rvhd_chain_base.vhd");
+        let mid = temp_path("rvhd_chain_mid.vhd");
+        let leaf = temp_path("rvhd_chain_leaf.vhd");
+
+        let img = VhdImage::create_dynamic(base.as_str(), 4).unwrap();
+        img.write_all_at(0, &[1_u8; 1024]).unwrap();
+        drop(img);
+        let img = VhdImage::create_diff(mid.as_str(), base.as_str()).unwrap();
+        img.write_all_at(512, &[2_u8; 512]).unwrap();
+        drop(img);
+        let img = VhdImage::create_diff(leaf.as_str(), mid.as_str()).unwrap();
+        img.write_all_at(3 << 20, &[3_u8; 512]).unwrap();
+        drop(img);
+
+        let img = VhdImage::open(leaf.as_str()).unwrap();
+        assert_eq!(img.chain().count(), 3);
+        assert_eq!(img.parent().unwrap().parent().unwrap().disk_type(), VhdType::Dynamic);
+        assert_eq!(img.backing_files().collect::<Vec<_>>(), [leaf.clone(), mid.clone(), base.clone()]);
+
+        let mut buffer = vec![0_u8; 1536];
+        img.read_exact_at(0, &mut buffer).unwrap();
+        assert!(buffer[..512].iter().all(|b| *b == 1));
+        assert!(buffer[512..1024].iter().all(|b| *b == 2));
+        assert!(buffer[1024..].iter().all(|b| *b == 0));
+
+        img.read_exact_at(3 << 20, &mut buffer[..512]).unwrap();
+        assert!(buffer[..512].iter().all(|b| *b == 3));
+
+        drop(img);
+        for path in [leaf, mid, base] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn open_diff_uuid_mismatch_test() {
+        let base = temp_path("rvhd_mismatch_base.vhd");
+        let leaf = temp_path("rvhd_mismatch_leaf.vhd");
+
+        drop(VhdImage::create_dynamic(base.as_str(), 2).unwrap());
+        drop(VhdImage::create_diff(leaf.as_str(), base.as_str()).unwrap());
+        // a new parent at the same place has another UUID
+        drop(VhdImage::create_dynamic(base.as_str(), 2).unwrap());
+
+        assert!(matches!(VhdImage::open(leaf.as_str()), Err(VhdError::ParentUuidMismatch)));
+
+        std::fs::remove_file(base).unwrap();
+        assert!(matches!(VhdImage::open(leaf.as_str()), Err(VhdError::ParentNotExist)));
+        std::fs::remove_file(leaf).unwrap();
+    }
+
+    #[test]
+    fn open_diff_stale_locator_test() {
+        let dir = std::env::temp_dir().join("rvhd_stale_locator");
+        let moved = dir.join("moved");
+        std::fs::create_dir_all(&moved).unwrap();
+        let base = dir.join("base.vhd").to_string_lossy().into_owned();
+        let leaf = dir.join("leaf.vhd").to_string_lossy().into_owned();
+
+        let img = VhdImage::create_dynamic(base.as_str(), 2).unwrap();
+        img.write_all_at(0, &[1_u8; 512]).unwrap();
+        drop(img);
+        drop(VhdImage::create_diff(leaf.as_str(), base.as_str()).unwrap());
+
+        // the relative W2ru locator of the moved child finds a file that is not a VHD,
+        // the absolute W2ku locator still finds the parent
+        let moved_leaf = moved.join("leaf.vhd");
+        std::fs::rename(&leaf, &moved_leaf).unwrap();
+        std::fs::write(moved.join("base.vhd"), [0_u8; 4096]).unwrap();
+
+        let img = VhdImage::open(moved_leaf.to_str().unwrap()).unwrap();
+        assert_eq!(img.parent().unwrap().file_path(), base);
+        let mut buffer = [0_u8; 512];
+        img.read_exact_at(0, &mut buffer).unwrap();
+        assert_eq!(buffer, [1_u8; 512]);
+        drop(img);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn create_diff_relative_path_test() {
+        let base = temp_path("rvhd_relative_base.vhd");
+        drop(VhdImage::create_dynamic(base.as_str(), 2).unwrap());
+
+        let result = VhdImage::create_diff("rvhd_relative_leaf.vhd", base.as_str());
+        assert!(matches!(result, Err(VhdError::FilePathNeedAbsolute)));
+        assert!(!Path::new("rvhd_relative_leaf.vhd").exists());
+
+        std::fs::remove_file(base).unwrap();
     }
 
     #[test]
@@ -267,7 +1370,7 @@ mod tests {
         assert_eq!(vhd_diff.footer().current_size(), 2 << 20);        
 
         println!("{}", vhd_diff.footer());
-        println!("{}", vhd_diff.sparse_header().as_deref().unwrap());
+        println!("{}", vhd_diff.sparse_header().unwrap());
         println!("{}", vhd_diff.parent_locator().unwrap());
     }
 }
\ No newline at end of file
@@ -1,6 +1,5 @@
-use crate::vhd::calc_header_bytes_checksum;
-use crate::{Uuid, sizes, StructBuffer, ReadAt, Result, AsByteSliceMut, VhdError, AsByteSlice, VhdFile, WriteAt, SeekAt, Flush, math};
-use super::{VhdType, VhdImage, VhdFooter, VhdHeader};
+use crate::{Uuid, sizes, StructBuffer, ReadAt, Result, AsByteSliceMut, VhdError, AsByteSlice, VhdFile, WriteAt, Flush, math, OpenMode};
+use super::{bat, VhdType, VhdImage, VhdHeader, VhdFooter, FOOTER_SIZE};
 use std::cell::RefCell;
 use std::mem;
 
@@ -10,6 +9,7 @@ pub const VHD_JOURNAL_DATA:u32 = 0x02;
 
 #[derive(Debug, Copy, Clone, FromPrimitive, ToPrimitive, Eq, PartialEq)]
 //#[warn(non_camel_case_types)]
+#[allow(clippy::enum_variant_names)] // named after the vhd-util entry types
 enum VhdJournalEntryType {
     VhdJournalEntryTypeFooterP = 0x01,
     VhdJournalEntryTypeFooterC = 0x02,
@@ -36,11 +36,16 @@ struct VhdJournalHeader {
     pad: [u8; 448],
 }
 
-struct VhdJournal {
+/// Undo journal of an image modification, in the `vhd-util` journal layout.
+///
+/// The journal saves the raw on-disk bytes of the footers, header, parent locators and BAT
+/// when created, and the blocks passed to [`VhdJournal::add_block`]. [`VhdJournal::revert`]
+/// writes them back and restores the file size, [`VhdJournal::commit`] deletes the journal.
+pub struct VhdJournal {
     jfile: VhdFile,
     jfile_path: String,
+    vhd_file: VhdFile,
     vhd_journal_header: RefCell<VhdJournalHeader>,
-    vhd_image: VhdImage,
 }
 
 #[repr(C, packed)]
@@ -83,7 +88,7 @@ impl VhdJournalHeader {
 }
 
 impl VhdJournalEntry {
-    fn new(etype: VhdJournalEntryType, size: u32, offset: u64) -> Self {
+    fn new(etype: VhdJournalEntryType, size: u32, offset: u64, data: &[u8]) -> Self {
         use num_traits::ToPrimitive;
         let etype = etype.to_u32().unwrap();
 
@@ -95,12 +100,24 @@ impl VhdJournalEntry {
         entry.checksum = 0;
         entry.reserved = 0;
 
-        let checksum = calc_header_bytes_checksum(&entry);
-        entry.checksum = checksum;
+        entry.checksum = Self::checksum(&entry, data);
 
         entry.copy()
     }
 
+    // one's complement sum of the entry, with a zero checksum, and of its data
+    fn checksum(entry: &StructBuffer<VhdJournalEntry>, data: &[u8]) -> u32 {
+        let mut copied = entry.clone();
+        copied.checksum = 0;
+
+        let sum = unsafe { copied.as_byte_slice() }.iter().chain(data).fold(0_u32, |sum, b| sum.wrapping_add(*b as u32));
+        !sum
+    }
+
+    fn is_data(&self) -> bool {
+        self.etype == VhdJournalEntryType::VhdJournalEntryTypeData as u32
+    }
+
     fn swap_bytes(&mut self) {
         self.etype = self.etype.swap_bytes();
         self.size = self.size.swap_bytes();
@@ -110,184 +127,272 @@ impl VhdJournalEntry {
 }
 
 impl VhdJournal {
-    pub fn create<S: Into<String>>(img: VhdImage, jpath: S) -> Result<Self> {
+    /// The journal path used for the image at `path`, checked by [`VhdImage::open`].
+    pub fn default_path(path: &str) -> String {
+        format!("{}.journal", path)
+    }
+
+    /// Creates the journal `jpath` of `img`, saving its metadata.
+    ///
+    /// `img` is flushed first, so the saved metadata is the one on disk. Fails with
+    /// [`VhdError::JournalPending`] if `jpath` already exists, as it may hold the only copy
+    /// of the metadata of an interrupted operation.
+    pub fn create<S: Into<String>>(img: &VhdImage, jpath: S) -> Result<Self> {
+        img.flush()?;
+
         let jpath = jpath.into();
-        let jfile = VhdFile::create(&jpath, 0)?;
+        let vhd_file = VhdFile::open(&img.file_path(), OpenMode::ReadWrite)?;
+        let jfile = match VhdFile::create_new(&jpath) {
+            Err(VhdError::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Err(VhdError::JournalPending(jpath));
+            }
+            res => res?,
+        };
         let off = img.file_size()?;        
 
         let mut header = VhdJournalHeader::new();
-        header.uuid = img.id().clone();        
-        header.vhd_footer_offset = off - mem::size_of::<VhdFooter>() as u64;
+        header.uuid = *img.id();        
+        header.vhd_footer_offset = off - img.footer_size();
         header.journal_eof = mem::size_of::<VhdJournalHeader>() as u64;
 
-        println!("header.uuid: {}, footer_offset: {}, journal_eof: {}",
-            header.uuid.to_string(), header.vhd_footer_offset, header.journal_eof);
-
         let this = VhdJournal {
             jfile,
-            jfile_path: jpath,
+            jfile_path: jpath.clone(),
+            vhd_file,
             vhd_journal_header: RefCell::new(header),
-            vhd_image: img,
         };        
         
-        this.journal_write_header()?;
-        this.journal_add_metadata()?;
+        // nothing was changed yet, an incomplete journal is removed
+        let res = this.journal_write_header()
+            .and_then(|_| this.journal_add_metadata(img))
+            .and_then(|_| this.jfile.sync());
+        if let Err(e) = res {
+            drop(this);
+            let _ = std::fs::remove_file(&jpath);
+            return Err(e);
+        }
 
         Ok(this)
     }
 
-    pub fn open<S: Into<String>>(img: &VhdImage, jpath: S) -> Result<Self> {
-        todo!("open");
-    }
+    /// Opens the existing journal `jpath` of the image at `path`, validating every entry.
+    ///
+    /// The image itself is not opened, so a journal left by an interrupted operation
+    /// can be reverted even if the image is unusable. A footer of the image still
+    /// readable, the trailing one or else the backup one, must carry the journal UUID.
+    pub fn open<S: Into<String>>(path: S, jpath: S) -> Result<Self> {
+        let jpath = jpath.into();
+        let jfile = VhdFile::open(&jpath, OpenMode::ReadWrite)?;
+        let vhd_file = VhdFile::open(&path.into(), OpenMode::ReadWrite)?;
 
-    pub fn add_block(&self, bat_block_index: usize, mode: u32) -> Result<()> {
-        match self.vhd_image.disk_type() {
-            VhdType::Fixed => return Err(VhdError::NeedDyncOrDiffImage),
-            _ => (),
+        let mut header = unsafe { StructBuffer::<VhdJournalHeader>::new() };
+        jfile.read_exact_at(0, unsafe { header.as_byte_slice_mut() })?;
+        if header.cookie != VHD_JOURNAL_HEADER_COOKIE {
+            return Err(VhdError::InvalidJournalHeader);
         }
-        
-        if (mode | VHD_JOURNAL_METADATA) == VHD_JOURNAL_METADATA {
-            let pos = self.vhd_journal_header.borrow().journal_eof;
-
-            let (offset, bitmap) = self.vhd_image.sparse_block_bitmap(bat_block_index).unwrap();
-            let entry = VhdJournalEntry::new(
-                VhdJournalEntryType::VhdJournalEntryTypeData, 
-                bitmap.borrow().len() as u32,
-                offset);
-            self.journal_update(pos, entry, unsafe { bitmap.borrow().as_byte_slice() })?;
+        header.swap_bytes();
+
+        if header.journal_eof > jfile.size()? {
+            return Err(VhdError::InvalidJournalHeader);
         }
 
-        if (mode | VHD_JOURNAL_DATA) == VHD_JOURNAL_DATA {
-            let pos = self.vhd_journal_header.borrow().journal_eof;
-            let img_header = self.vhd_image.sparse_header().unwrap();
-            let mut buffer = Vec::with_capacity(img_header.block_size() as usize);
-            let offset = self.vhd_image.sparse_block_data(bat_block_index, &mut buffer)?;
-
-            let entry = VhdJournalEntry::new(
-                VhdJournalEntryType::VhdJournalEntryTypeData, 
-                img_header.block_size(),
-                offset);
-            self.journal_update(pos, entry, &buffer)?;
+        // an interrupted operation may have overwritten the trailing footer
+        let footer = VhdFooter::read_trailing(&vhd_file, vhd_file.size()?)
+            .map(|(footer, _)| footer)
+            .or_else(|_| VhdFooter::read(&vhd_file, 0));
+        if let Ok(footer) = footer {
+            if *footer.uuid() != { header.uuid } {
+                return Err(VhdError::JournalImageMismatch);
+            }
         }
 
-        Ok(())
-    }
+        let this = VhdJournal {
+            jfile,
+            jfile_path: jpath,
+            vhd_file,
+            vhd_journal_header: RefCell::new(header.copy()),
+        };
+
+        let entries = this.entries()?;
+        let metadata_entries = entries.iter().filter(|(entry, _)| !entry.is_data()).count();
+        if metadata_entries != { header.journal_metadata_entries } as usize
+            || entries.len() - metadata_entries != { header.journal_data_entries } as usize
+        {
+            return Err(VhdError::InvalidJournalHeader);
+        }
 
-    pub fn commit(&self) -> Result<()> {
-        todo!("commit");
+        Ok(this)
     }
 
-    pub fn revert(&self) -> Result<()> {
-        todo!("revert");
-    }    
+    /// Saves the bitmap (`VHD_JOURNAL_METADATA`) and/or the data (`VHD_JOURNAL_DATA`)
+    /// of an allocated block. Unallocated blocks have nothing to save, reverting the BAT frees them.
+    pub fn add_block(&self, img: &VhdImage, bat_block_index: usize, mode: u32) -> Result<()> {
+        let (header, bat) = match (img.sparse_header(), img.sparse_bat()) {
+            (Some(header), Some(bat)) => (header, bat),
+            _ => return Err(VhdError::NeedDyncOrDiffImage),
+        };
 
-    fn journal_write_header(&self) -> Result<()> {  
-        let jheader = self.vhd_journal_header.clone().into_inner();         
-        let mut sheader = unsafe { StructBuffer::<VhdJournalHeader>::with_value(&jheader) };
-        sheader.swap_bytes();
+        img.flush()?;
 
-        //println!("{:?}", header.copy());
+        let block_id = bat.read().unwrap().block_id(bat_block_index)?;
+        if block_id == bat::DD_BLOCK_UNUSED {
+            return Ok(());
+        }
 
-        self.jfile.write_all_at(0, sheader.buffer())
-    }
+        let bitmap_offset = block_id as u64 * sizes::SECTOR_U64;
+        let bitmap_size = super::sparse::bitmap_size(header.block_size());
 
-    fn journal_add_metadata(&self) -> Result<()> {        
-        self.journal_add_footer()?;
-        
-        match self.vhd_image.disk_type() {
-            VhdType::Fixed => return Ok(()),
-            _ => (),
+        if mode & VHD_JOURNAL_METADATA != 0 {
+            self.journal_add_raw(VhdJournalEntryType::VhdJournalEntryTypeData, bitmap_offset, bitmap_size)?;
         }
 
-        self.journal_add_header()?;
-        self.journal_add_locators()?;
-        self.journal_add_bat()?;        
-        
-        Ok(())
+        if mode & VHD_JOURNAL_DATA != 0 {
+            let data_offset = bitmap_offset + bitmap_size as u64;
+            self.journal_add_raw(VhdJournalEntryType::VhdJournalEntryTypeData, data_offset, header.block_size())?;
+        }
+
+        self.jfile.sync()
     }
 
-    fn journal_add_footer(&self) -> Result<()> {
-        let pos = self.vhd_journal_header.borrow().journal_eof;
-        let offset = self.vhd_journal_header.borrow().vhd_footer_offset;
+    /// Saves `size` bytes of the image file at `offset`, for changes outside of sparse blocks
+    /// such as the data of a fixed image.
+    pub fn add_data(&self, offset: u64, size: u32) -> Result<()> {
+        self.journal_add_raw(VhdJournalEntryType::VhdJournalEntryTypeData, offset, size)?;
+        self.jfile.sync()
+    }
+
+    /// Keeps the image changes and deletes the journal.
+    ///
+    /// The journaled image must be flushed first.
+    pub fn commit(self) -> Result<()> {
+        self.vhd_file.sync()?;
 
-        let footer = unsafe { StructBuffer::<VhdFooter>::with_value(self.vhd_image.footer()) };        
-        let entry = VhdJournalEntry::new(
-            VhdJournalEntryType::VhdJournalEntryTypeFooterP, 
-            mem::size_of::<VhdFooter>() as u32, 
-            offset);
-        self.journal_update(pos, entry, footer.buffer())?;
+        let jfile_path = self.jfile_path.clone();
+        drop(self);
+        std::fs::remove_file(jfile_path)?;
 
-        match self.vhd_image.disk_type() {
-            VhdType::Fixed => return Ok(()),
-            _ => (),
+        Ok(())
+    }
+
+    /// Writes the saved footers, header, locators, BAT and blocks back, restores the image
+    /// file size and deletes the journal.
+    ///
+    /// Any [`VhdImage`] of the journaled file must be dropped without a flush: its cached metadata
+    /// is stale and flushing it would undo the revert.
+    pub fn revert(self) -> Result<()> {
+        let footer_offset = self.vhd_journal_header.borrow().vhd_footer_offset;
+        let mut footer_size = FOOTER_SIZE;
+
+        for (entry, data) in self.entries()? {
+            if entry.etype == VhdJournalEntryType::VhdJournalEntryTypeFooterP as u32 {
+                footer_size = data.len() as u64;
+            }
+
+            self.vhd_file.write_all_at(entry.offset, &data)?;
         }
 
-        let pos = self.vhd_journal_header.borrow().journal_eof;        
+        self.vhd_file.set_len(footer_offset + footer_size)?;
+        self.vhd_file.sync()?;
+
+        let jfile_path = self.jfile_path.clone();
+        drop(self);
+        std::fs::remove_file(jfile_path)?;
 
-        //self.vhd_image.read_exact_at(0, unsafe { footer.as_byte_slice_mut() })?;
-        let entry = VhdJournalEntry::new(
-            VhdJournalEntryType::VhdJournalEntryTypeFooterC, 
-            mem::size_of::<VhdFooter>() as u32, 
-            0);
-        self.journal_update(pos, entry, footer.buffer())?;
-        
         Ok(())
     }
 
-    fn journal_add_header(&self) -> Result<()> {
-        let offset = self.vhd_image.footer().data_offset();
-        let pos = self.vhd_journal_header.borrow().journal_eof;
+    // reads and validates all the entries with their data
+    fn entries(&self) -> Result<Vec<(VhdJournalEntry, Vec<u8>)>> {
+        let journal_eof = self.vhd_journal_header.borrow().journal_eof;
+        let mut pos = mem::size_of::<VhdJournalHeader>() as u64;
+        let mut entries = Vec::new();
+
+        while pos < journal_eof {
+            let mut entry = unsafe { StructBuffer::<VhdJournalEntry>::new() };
+            self.jfile.read_exact_at(pos, unsafe { entry.as_byte_slice_mut() })?;
+            if entry.cookie != VHD_JOURNAL_ENTRY_COOKIE {
+                return Err(VhdError::InvalidJournalEntry(pos));
+            }
+            entry.swap_bytes();
 
-        let header = unsafe { StructBuffer::<VhdHeader>::with_value(self.vhd_image.sparse_header().unwrap()) };
-        //self.vhd_image.read_exact_at(offset, unsafe { header.as_byte_slice_mut() })?;
+            let data_pos = pos + mem::size_of::<VhdJournalEntry>() as u64;
+            if data_pos + entry.size as u64 > journal_eof {
+                return Err(VhdError::InvalidJournalEntry(pos));
+            }
 
-        let entry = VhdJournalEntry::new(
-            VhdJournalEntryType::VhdJournalEntryTypeHeader, 
-            mem::size_of::<VhdHeader>() as u32, 
-            offset);
-        self.journal_update(pos, entry, header.buffer())?;
+            let mut data = vec![0_u8; entry.size as usize];
+            self.jfile.read_exact_at(data_pos, &mut data)?;
 
-        Ok(())
+            let etype: Option<VhdJournalEntryType> = num_traits::FromPrimitive::from_u32(entry.etype);
+            if etype.is_none() || entry.checksum != VhdJournalEntry::checksum(&entry, &data) {
+                return Err(VhdError::InvalidJournalEntry(pos));
+            }
+
+            pos = data_pos + entry.size as u64;
+            entries.push((entry.copy(), data));
+        }
+
+        Ok(entries)
     }
 
-    fn journal_add_locators(&self) -> Result<()> {        
-        let img_header = self.vhd_image.sparse_header().unwrap();
+    fn journal_write_header(&self) -> Result<()> {  
+        let jheader = self.vhd_journal_header.clone().into_inner();         
+        let mut sheader = unsafe { StructBuffer::<VhdJournalHeader>::with_value(&jheader) };
+        sheader.swap_bytes();
 
-        for (i, locator) in img_header.prt_loc().iter().enumerate() {
-            if locator.prt_loc_code() != super::sparse::PLAT_CODE_NONE {
-                let pos = self.vhd_journal_header.borrow().journal_eof;
-                let data = self.vhd_image.parent_locator_data(i).unwrap();
+        self.jfile.write_all_at(0, sheader.buffer())
+    }
+
+    fn journal_add_metadata(&self, img: &VhdImage) -> Result<()> {        
+        self.journal_add_footer(img)?;
+        
+        let header = match img.sparse_header() {
+            Some(header) => header,
+            None => return Ok(()),
+        };
 
-                let entry = VhdJournalEntry::new(
-                    VhdJournalEntryType::VhdJournalEntryTypeLocator, 
-                    locator.prt_loc_space(), 
-                    locator.prt_loc_offset());
+        // header
+        self.journal_add_raw(
+            VhdJournalEntryType::VhdJournalEntryTypeHeader,
+            img.footer().data_offset(),
+            mem::size_of::<VhdHeader>() as u32)?;
 
-                self.journal_update(pos, entry, &data)?;
+        // locators
+        for locator in header.prt_loc() {
+            if locator.prt_loc_code() != super::sparse::PLAT_CODE_NONE {
+                self.journal_add_raw(
+                    VhdJournalEntryType::VhdJournalEntryTypeLocator,
+                    locator.prt_loc_offset(),
+                    locator.prt_loc_space_bytes() as u32)?;
             }
         }
 
+        // bat
+        let size = math::round_up(header.max_bat_size() as u64 * 4, sizes::SECTOR_U64);
+        self.journal_add_raw(VhdJournalEntryType::VhdJournalEntryTypeBat, header.table_offset(), size as u32)?;
+        
         Ok(())
     }
 
-    fn journal_add_bat(&self) -> Result<()> {
+    fn journal_add_footer(&self, img: &VhdImage) -> Result<()> {
+        let offset = self.vhd_journal_header.borrow().vhd_footer_offset;
+        self.journal_add_raw(VhdJournalEntryType::VhdJournalEntryTypeFooterP, offset, img.footer_size() as u32)?;
+
+        if img.disk_type() == VhdType::Fixed {
+            return Ok(());
+        }
+
+        self.journal_add_raw(VhdJournalEntryType::VhdJournalEntryTypeFooterC, 0, FOOTER_SIZE as u32)
+    }
+
+    // saves `size` bytes of the image file at `offset`
+    fn journal_add_raw(&self, etype: VhdJournalEntryType, offset: u64, size: u32) -> Result<()> {
         let pos = self.vhd_journal_header.borrow().journal_eof;
-        let img_header = self.vhd_image.sparse_header().unwrap();
-
-        let size = math::round_up((img_header.max_bat_size() * 4) as usize, sizes::SECTOR as usize);
-        let entry = VhdJournalEntry::new(
-            VhdJournalEntryType::VhdJournalEntryTypeBat, 
-            size as u32, 
-            img_header.table_offset());
-
-        let data = self.vhd_image.sparse_bat().unwrap().borrow();
-        let data = data.bat_data();
-        let data = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const u8, data.len() * 4)
-        };
-        self.journal_update(pos, entry, data)?;
+        let mut data = vec![0_u8; size as usize];
+        self.vhd_file.read_exact_at(offset, &mut data)?;
 
-        Ok(())
+        let entry = VhdJournalEntry::new(etype, size, offset, &data);
+        self.journal_update(pos, entry, &data)
     }
 
     fn journal_update(&self, pos: u64, entry: VhdJournalEntry, entry_data: &[u8]) -> Result<()> {
@@ -328,34 +433,180 @@ impl VhdJournal {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::vhd::test_util::temp_path;
 
     #[test]
     fn fixed_journal_new_test() {
         let img = VhdImage::open("D:\\123.vhd").unwrap();
 
-        let journal = VhdJournal::create(img, "D:\\123_journal").unwrap();
+        let journal = VhdJournal::create(&img, "D:\\123_journal").unwrap();
 
-        assert_eq!(journal.vhd_journal_header.borrow().journal_metadata_entries, 1);
-        assert_eq!(journal.vhd_journal_header.borrow().journal_metadata_offset, mem::size_of::<VhdJournalHeader>() as u64);
+        assert_eq!({ journal.vhd_journal_header.borrow().journal_metadata_entries }, 1);
+        assert_eq!({ journal.vhd_journal_header.borrow().journal_metadata_offset }, mem::size_of::<VhdJournalHeader>() as u64);
+        drop(journal);
+        std::fs::remove_file("D:\\123_journal").unwrap();
     }
 
     #[test]
     fn dynamic_journal_new_test() {
         let img = VhdImage::open("D:\\456.vhd").unwrap();
 
-        let journal = VhdJournal::create(img, "D:\\456_journal").unwrap();
+        let journal = VhdJournal::create(&img, "D:\\456_journal").unwrap();
 
-        assert_eq!(journal.vhd_journal_header.borrow().journal_metadata_entries, 4);
-        assert_eq!(journal.vhd_journal_header.borrow().journal_metadata_offset, mem::size_of::<VhdJournalHeader>() as u64);        
+        assert_eq!({ journal.vhd_journal_header.borrow().journal_metadata_entries }, 4);
+        assert_eq!({ journal.vhd_journal_header.borrow().journal_metadata_offset }, mem::size_of::<VhdJournalHeader>() as u64);        
+        drop(journal);
+        std::fs::remove_file("D:\\456_journal").unwrap();
     }
 
     #[test]
     fn diff_journal_new_test() {
         let img = VhdImage::open("D:\\567.vhd").unwrap();
 
-        let journal = VhdJournal::create(img, "D:\\567_journal").unwrap();
+        let journal = VhdJournal::create(&img, "D:\\567_journal").unwrap();
+
+        assert_eq!({ journal.vhd_journal_header.borrow().journal_metadata_entries }, 6);
+        assert_eq!({ journal.vhd_journal_header.borrow().journal_metadata_offset }, mem::size_of::<VhdJournalHeader>() as u64);
+        drop(journal);
+        std::fs::remove_file("D:\\567_journal").unwrap();
+    }
+
+    fn create_dynamic(path: &str) -> VhdImage {
+        let img = VhdImage::create_dynamic(path, 8).unwrap();
+        img.write_all_at(0, &[1_u8; 4096]).unwrap();
+        img
+    }
+
+    #[test]
+    fn journal_revert_test() {
+        let path = temp_path("rvhd_journal_revert.vhd");
+        let jpath = VhdJournal::default_path(&path);
+        let img = create_dynamic(&path);
+        drop(img);
+        let original = std::fs::read(&path).unwrap();
+
+        let img = VhdImage::open_with_mode(path.as_str(), OpenMode::ReadWrite).unwrap();
+        let journal = VhdJournal::create(&img, jpath.as_str()).unwrap();
+        journal.add_block(&img, 0, VHD_JOURNAL_METADATA | VHD_JOURNAL_DATA).unwrap();
+        // an unallocated block has nothing to save
+        journal.add_block(&img, 2, VHD_JOURNAL_METADATA | VHD_JOURNAL_DATA).unwrap();
+        assert_eq!({ journal.vhd_journal_header.borrow().journal_data_entries }, 2);
+
+        // overwrite block 0 and allocate block 2
+        img.write_all_at(0, &[2_u8; 4096]).unwrap();
+        img.write_all_at(4 << 20, &[3_u8; 4096]).unwrap();
+        drop(img);
+        drop(journal);
+
+        // interrupted: the image cannot be opened until the journal is handled
+        assert!(matches!(VhdImage::open(path.as_str()), Err(VhdError::JournalPending(_))));
+
+        VhdJournal::open(path.as_str(), jpath.as_str()).unwrap().revert().unwrap();
+        assert!(!std::path::Path::new(&jpath).exists());
+        assert!(std::fs::read(&path).unwrap() == original);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn journal_commit_test() {
+        let path = temp_path("rvhd_journal_commit.vhd");
+        let jpath = VhdJournal::default_path(&path);
+        let img = create_dynamic(&path);
+
+        let journal = VhdJournal::create(&img, jpath.as_str()).unwrap();
+        img.write_all_at(4 << 20, &[3_u8; 4096]).unwrap();
+        img.flush().unwrap();
+        journal.commit().unwrap();
+        drop(img);
+
+        assert!(!std::path::Path::new(&jpath).exists());
+        let img = VhdImage::open(path.as_str()).unwrap();
+        let mut buffer = [0_u8; 4096];
+        img.read_exact_at(4 << 20, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|b| *b == 3));
+        drop(img);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn journal_fixed_revert_test() {
+        let path = temp_path("rvhd_journal_fixed.vhd");
+        let jpath = temp_path("rvhd_journal_fixed.journal");
+        let img = VhdImage::create_fixed(path.as_str(), 2).unwrap();
+        let original = std::fs::read(&path).unwrap();
+
+        let journal = VhdJournal::create(&img, jpath.as_str()).unwrap();
+        assert!(matches!(journal.add_block(&img, 0, VHD_JOURNAL_DATA), Err(VhdError::NeedDyncOrDiffImage)));
+        drop(img);
+        // the footer is lost and the file grown
+        let file = VhdFile::open(&path, OpenMode::ReadWrite).unwrap();
+        file.write_all_at((2 << 20) + 4096, &[0_u8; 512]).unwrap();
+        drop(file);
+
+        journal.revert().unwrap();
+        assert!(std::fs::read(&path).unwrap() == original);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn journal_exists_test() {
+        let path = temp_path("rvhd_journal_exists.vhd");
+        let jpath = VhdJournal::default_path(&path);
+        let img = create_dynamic(&path);
+
+        // a second journal must not overwrite the first one
+        let journal = VhdJournal::create(&img, jpath.as_str()).unwrap();
+        let saved = std::fs::read(&jpath).unwrap();
+        assert!(matches!(VhdJournal::create(&img, jpath.as_str()), Err(VhdError::JournalPending(_))));
+        assert!(std::fs::read(&jpath).unwrap() == saved);
+
+        journal.commit().unwrap();
+        drop(img);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn journal_other_image_test() {
+        let path = temp_path("rvhd_journal_other.vhd");
+        let other_path = temp_path("rvhd_journal_other_2.vhd");
+        let jpath = temp_path("rvhd_journal_other.journal");
+        let img = create_dynamic(&path);
+        let other = create_dynamic(&other_path);
+        drop(VhdJournal::create(&img, jpath.as_str()).unwrap());
+
+        let res = VhdJournal::open(other_path.as_str(), jpath.as_str());
+        assert!(matches!(res, Err(VhdError::JournalImageMismatch)));
+        // the image of the journal still opens it
+        drop(VhdJournal::open(path.as_str(), jpath.as_str()).unwrap());
+
+        drop(img);
+        drop(other);
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(other_path).unwrap();
+        std::fs::remove_file(jpath).unwrap();
+    }
 
-        assert_eq!(journal.vhd_journal_header.borrow().journal_metadata_entries, 6);
-        assert_eq!(journal.vhd_journal_header.borrow().journal_metadata_offset, mem::size_of::<VhdJournalHeader>() as u64);
+    #[test]
+    fn journal_invalid_entry_test() {
+        let path = temp_path("rvhd_journal_invalid.vhd");
+        let jpath = temp_path("rvhd_journal_invalid.journal");
+        let img = create_dynamic(&path);
+        drop(VhdJournal::create(&img, jpath.as_str()).unwrap());
+
+        // a changed byte in the first entry data
+        let jfile = VhdFile::open(&jpath, OpenMode::ReadWrite).unwrap();
+        let pos = (mem::size_of::<VhdJournalHeader>() + mem::size_of::<VhdJournalEntry>()) as u64;
+        jfile.write_all_at(pos + 100, &[0xAA_u8]).unwrap();
+        drop(jfile);
+
+        let res = VhdJournal::open(path.as_str(), jpath.as_str());
+        assert!(matches!(res, Err(VhdError::InvalidJournalEntry(512))));
+
+        drop(img);
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(jpath).unwrap();
     }
 }
@@ -1,6 +1,6 @@
-use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::time::{SystemTime, UNIX_EPOCH};
 use crate::{AsByteSlice, ImageExtent, ImageExtentOps, Result};
-use std::cell::{RefCell, Ref};
+use std::sync::RwLock;
 
 pub(crate) fn calc_header_bytes_checksum<T: AsByteSlice>(header: &T) -> u32 {
     let mut new_checksum = 0_u32;
@@ -30,14 +30,6 @@ pub(crate) fn vhd_time() -> u32 {
     }
 }
 
-// FIXME: need third-part crate
-pub(crate) fn vhd_time_str(time: u32) -> Result<()> {
-    let _sys_time = SystemTime::now();    
-    let _dur = Duration::from_secs(time as u64 + VHD_EPOCH_START as u64);
-
-    Ok(())
-}
-
 pub(crate) fn vhd_type_str(vhd_type: VhdType) -> String {
     match vhd_type {
         VhdType::Fixed => String::from("Fixed"),
@@ -57,24 +49,56 @@ pub use footer::*;
 pub mod image;
 pub use image::*;
 
-pub mod fixed;
-pub use fixed::*;
+mod fixed;
+use fixed::*;
 
-pub mod sparse;
-pub use sparse::*;
+pub(crate) mod sparse;
+use sparse::*;
+pub use sparse::{VhdHeader, VhdParentLocator, VhdLocator, DD_BLOCKSIZE_DEFAULT, DEFAULT_BITMAP_CACHE_SIZE};
+pub use sparse::{PLAT_CODE_NONE, PLAT_CODE_W2RU, PLAT_CODE_W2KU, PLAT_CODE_MACX};
 
 pub mod journal;
-pub use journal::*;
-
-trait VhdImageExtent: ImageExtent + ImageExtentOps {
+pub use journal::{VhdJournal, VHD_JOURNAL_METADATA, VHD_JOURNAL_DATA};
+#[cfg(test)]
+mod test_util;
+
+pub mod convert;
+pub use convert::*;
+pub mod azure;
+pub use azure::*;
+pub mod check;
+pub use check::*;
+pub mod repair;
+pub use repair::*;
+mod resize;
+mod compact;
+pub mod coalesce;
+pub use coalesce::*;
+pub mod snapshot;
+pub use snapshot::*;
+mod modify;
+
+trait VhdImageExtent: ImageExtent + ImageExtentOps + Send + Sync {
     fn write_footer(&self, footer: &VhdFooter) -> Result<()>;
+    /// Size of the trailing footer, 511 bytes for images made before Virtual PC 2004.
+    fn footer_size(&self) -> u64;
+    fn set_footer_size(&mut self, footer_size: u64);
     fn sparse_header(&self) -> Option<&VhdHeader>;
     fn file_path(&self) -> String;
     fn parent_locator(&self) -> Option<String>;
     fn parent_locator_data(&self, index: usize) -> Option<Vec<u8>>;
-    fn sparse_bat(&self) -> Option<&RefCell<bat::VhdBat>>;
-    fn sparse_block_bitmap(&self, bat_block_index: usize) -> Option<(u64, &RefCell<Vec<u8>>)>;
+    fn read_parent_locator(&self, index: usize) -> Option<VhdLocator>;
+    fn parent(&self) -> Option<&VhdImage>;
+    fn set_parent(&mut self, parent: VhdImage);
+    fn sparse_bat(&self) -> Option<&RwLock<bat::VhdBat>>;
+    fn sparse_block_bitmap(&self, bat_block_index: usize) -> Result<Option<(u64, Vec<u8>)>>;
+    fn set_bitmap_cache_size(&self, blocks: usize) -> Result<()>;
+    fn discard(&self, offset: u64, len: u64) -> Result<()>;
+    fn write_zeroes(&self, offset: u64, len: u64) -> Result<()>;
+    fn set_detect_zeroes(&self, detect: bool);
     fn sparse_block_data(&self, bat_block_index: usize, buffer: &mut [u8]) -> Result<u64>;
+    fn read_vectored_at(&self, offset: u64, buffers: &mut [&mut [u8]]) -> Result<usize>;
+    fn write_vectored_at(&self, offset: u64, buffers: &[&[u8]]) -> Result<usize>;
 }
 
 #[derive(Debug, Copy, Clone, FromPrimitive, ToPrimitive, Eq, PartialEq)]
@@ -0,0 +1,187 @@
+use super::*;
+use crate::{math, sizes, OpenMode, Result, VhdError, VhdFile, WriteAt};
+
+impl VhdImage {
+    /// Points the differencing image at `path` to the parent image `parent`, like `vhd-util modify -p`.
+    ///
+    /// The parent must have the UUID recorded in the image header, unless `force` is set, in which
+    /// case its UUID and time stamp are recorded instead. The parent name and the W2ku, W2ru and
+    /// MacX locators are rewritten, other locators are dropped. Locators are rewritten in place
+    /// when the new path fits, otherwise after the last block. A raw parent is replaced by a raw
+    /// parent.
+    ///
+    /// The image itself does not have to open, so a chain can be repaired after being moved.
+    /// Both paths must be absolute.
+    ///
+    /// ```
+    /// use rvhd_util_convert::VhdImage;
+    ///
+    /// let dir = std::env::temp_dir();
+    /// let parent = dir.join("rvhd_doc_reparent_parent.vhd");
+    /// let moved = dir.join("rvhd_doc_reparent_moved.vhd");
+    /// let child = dir.join("rvhd_doc_reparent_child.vhd");
+    /// drop(VhdImage::create_dynamic(parent.to_str().unwrap(), 2).unwrap());
+    /// drop(VhdImage::create_diff(child.to_str().unwrap(), parent.to_str().unwrap()).unwrap());
+    ///
+    /// std::fs::rename(&parent, &moved).unwrap();
+    /// assert!(VhdImage::open(child.to_str().unwrap()).is_err());
+    ///
+    /// VhdImage::reparent(child.to_str().unwrap(), moved.to_str().unwrap(), false).unwrap();
+    /// assert!(VhdImage::open(child.to_str().unwrap()).is_ok());
+    /// # std::fs::remove_file(child).unwrap();
+    /// # std::fs::remove_file(moved).unwrap();
+    /// ```
+    pub fn reparent<S: Into<String>>(path: S, parent: S, force: bool) -> Result<()> {
+        let path = path.into();
+        let parent_path = parent.into();
+
+        let file = VhdFile::open(&path, OpenMode::ReadWrite)?;
+        let file_size = file.size()?;
+        let (footer, footer_size) = VhdFooter::read_trailing(&file, file_size)?;
+        if footer.disk_type() != VhdType::Diff {
+            return Err(VhdError::NeedDiffImage);
+        }
+        let mut header = VhdHeader::read(&file, footer.data_offset())?;
+
+        if !std::path::Path::new(&parent_path).exists() {
+            return Err(VhdError::ParentNotExist);
+        }
+
+        let parent = if header.prt_uuid().is_nil() {
+            Self::open_raw(parent_path.clone())?
+        } else {
+            Self::open(parent_path.as_str())?
+        };
+
+        if *parent.id() != header.prt_uuid() {
+            if !force {
+                return Err(VhdError::ParentUuidMismatch);
+            }
+            header.set_parent_id(*parent.id(), parent.footer().timestamps());
+        }
+        drop(parent);
+
+        let locators = VhdHeader::parent_locators(&path, &parent_path)?;
+        header.set_parent_name(&parent_path);
+
+        // the areas of the old locators are reused in order while they are large enough
+        let areas: Vec<(u64, u64)> = header
+            .prt_loc()
+            .iter()
+            .filter(|locator| locator.prt_loc_code() != sparse::PLAT_CODE_NONE)
+            .map(|locator| (locator.prt_loc_offset(), locator.prt_loc_space_bytes()))
+            .collect();
+        header.clear_locators();
+
+        let data_end = file_size - footer_size;
+        let mut end = data_end;
+        let mut placed = Vec::new();
+        for (index, locator) in locators.iter().enumerate() {
+            let data = locator.encode();
+            let (offset, space) = match areas.get(index) {
+                Some(&(offset, space)) if space >= data.len() as u64 => (offset, space),
+                _ => {
+                    let space = std::cmp::max(math::round_up(data.len() as u64, sizes::SECTOR_U64), sizes::SECTOR_U64);
+                    end += space;
+                    (end - space, space)
+                }
+            };
+            placed.push((locator.code(), offset, space, data));
+        }
+
+        // there is no journal, the footer is moved past the relocated locators before they
+        // overwrite it, so an interrupted rewrite still leaves a trailing footer
+        if end != data_end {
+            file.write_all_at(end, &footer.to_bytes()[..footer_size as usize])?;
+            file.sync()?;
+        }
+
+        for (index, (code, offset, space, data)) in placed.into_iter().enumerate() {
+            let mut buffer = vec![0_u8; space as usize];
+            buffer[..data.len()].copy_from_slice(&data);
+            file.write_all_at(offset, &buffer)?;
+            header.set_locator(index, code, offset, space as u32, data.len() as u32);
+        }
+
+        header.write(&file, footer.data_offset())?;
+        file.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ReadAt, WriteAt};
+    use crate::vhd::test_util::temp_path;
+
+    #[test]
+    fn reparent_moved_test() {
+        let dir = std::env::temp_dir().join("rvhd_reparent_moved");
+        let nested = dir.join("a_much_longer_directory_name_that_makes_the_relative_path_grow").join("more");
+        std::fs::create_dir_all(&nested).unwrap();
+        let parent = temp_path("rvhd_reparent_moved_parent.vhd");
+        let child = dir.join("child.vhd").to_string_lossy().into_owned();
+
+        let img = VhdImage::create_dynamic(parent.as_str(), 2).unwrap();
+        img.write_all_at(0, &[7_u8; 512]).unwrap();
+        drop(img);
+        let img = VhdImage::create_diff(child.as_str(), parent.as_str()).unwrap();
+        img.write_all_at(512, &[8_u8; 512]).unwrap();
+        drop(img);
+
+        // the new relative path no longer fits the 512 bytes of the old locators
+        let moved: String = nested.join("x".repeat(200) + ".vhd").to_string_lossy().into_owned();
+        std::fs::rename(&parent, &moved).unwrap();
+        assert!(matches!(VhdImage::open(child.as_str()), Err(VhdError::ParentNotExist)));
+
+        VhdImage::reparent(child.as_str(), moved.as_str(), false).unwrap();
+        let img = VhdImage::open(child.as_str()).unwrap();
+        assert_eq!(img.parent().unwrap().file_path(), moved);
+        assert_eq!(img.sparse_header().unwrap().prt_name().trim_end_matches('\0'), "x".repeat(200) + ".vhd");
+        let mut buffer = [0_u8; 1024];
+        img.read_exact_at(0, &mut buffer).unwrap();
+        assert!(buffer[..512].iter().all(|b| *b == 7));
+        assert!(buffer[512..].iter().all(|b| *b == 8));
+        drop(img);
+        assert!(VhdImage::check(child.as_str()).unwrap().is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reparent_uuid_mismatch_test() {
+        let parent = temp_path("rvhd_reparent_mismatch_parent.vhd");
+        let other = temp_path("rvhd_reparent_mismatch_other.vhd");
+        let child = temp_path("rvhd_reparent_mismatch_child.vhd");
+
+        drop(VhdImage::create_dynamic(parent.as_str(), 2).unwrap());
+        drop(VhdImage::create_dynamic(other.as_str(), 2).unwrap());
+        drop(VhdImage::create_diff(child.as_str(), parent.as_str()).unwrap());
+
+        let res = VhdImage::reparent(child.as_str(), other.as_str(), false);
+        assert!(matches!(res, Err(VhdError::ParentUuidMismatch)));
+        assert_eq!(VhdImage::open(child.as_str()).unwrap().parent().unwrap().file_path(), parent);
+
+        VhdImage::reparent(child.as_str(), other.as_str(), true).unwrap();
+        let img = VhdImage::open(child.as_str()).unwrap();
+        assert_eq!(img.parent().unwrap().file_path(), other);
+        assert_eq!(img.sparse_header().unwrap().prt_uuid(), *img.parent().unwrap().id());
+        drop(img);
+        assert!(VhdImage::check(child.as_str()).unwrap().is_ok());
+
+        std::fs::remove_file(child).unwrap();
+        std::fs::remove_file(other).unwrap();
+        std::fs::remove_file(parent).unwrap();
+    }
+
+    #[test]
+    fn reparent_not_diff_test() {
+        let path = temp_path("rvhd_reparent_not_diff.vhd");
+        drop(VhdImage::create_dynamic(path.as_str(), 2).unwrap());
+
+        let res = VhdImage::reparent(path.as_str(), path.as_str(), true);
+        assert!(matches!(res, Err(VhdError::NeedDiffImage)));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
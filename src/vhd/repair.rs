@@ -0,0 +1,297 @@
+use super::*;
+use crate::{math, sizes, OpenMode, Result, VhdError, VhdFile, WriteAt};
+
+/// A change made, or only planned in dry-run mode, by [`VhdImage::repair`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VhdRepairAction {
+    FixFooterChecksum,
+    FixHeaderChecksum,
+    /// The BAT entry points to a block whose bitmap is missing from the file.
+    ClearBatEntry { index: usize, block_id: u32 },
+    /// The footer is written after the last allocated block.
+    RestoreFooter { offset: u64 },
+    /// The footer copy at offset 0 is rewritten from the footer.
+    RestoreBackupFooter,
+    /// The file is resized to end with the footer.
+    ResizeFile { size: u64 },
+}
+
+impl std::fmt::Display for VhdRepairAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VhdRepairAction::FixFooterChecksum => f.write_str("fix footer checksum"),
+            VhdRepairAction::FixHeaderChecksum => f.write_str("fix header checksum"),
+            VhdRepairAction::ClearBatEntry { index, block_id } => {
+                write!(f, "clear BAT entry {} pointing to missing sector {:#X}", index, block_id)
+            }
+            VhdRepairAction::RestoreFooter { offset } => write!(f, "write footer at {:#X}", offset),
+            VhdRepairAction::RestoreBackupFooter => f.write_str("write backup footer"),
+            VhdRepairAction::ResizeFile { size } => write!(f, "resize file to {} bytes", size),
+        }
+    }
+}
+
+impl VhdImage {
+    /// Repairs the footers, header checksum and BAT of the image at `path`, like `vhd-util repair`.
+    ///
+    /// A damaged trailing footer of a dynamic or differencing image is recovered from the copy
+    /// at offset 0 and the other way round, and it is moved after the last allocated block.
+    /// With `dry_run` the file is left untouched and the returned actions are only the planned ones.
+    ///
+    /// ```
+    /// use rvhd_util_convert::VhdImage;
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_repair.vhd");
+    /// drop(VhdImage::create_dynamic(path.to_str().unwrap(), 4).unwrap());
+    ///
+    /// let actions = VhdImage::repair(path.to_str().unwrap(), false).unwrap();
+    /// assert!(actions.is_empty());
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
+    pub fn repair<S: Into<String>>(path: S, dry_run: bool) -> Result<Vec<VhdRepairAction>> {
+        let path = path.into();
+        let mode = if dry_run { OpenMode::ReadOnly } else { OpenMode::ReadWrite };
+        let file = VhdFile::open(&path, mode)?;
+        let file_size = file.size()?;
+
+        if file_size < sizes::SECTOR_U64 {
+            return Err(VhdError::FileTooSmall);
+        }
+
+        let mut actions = Vec::new();
+        let trailing = VhdFooter::read_unchecked(&file, file_size - sizes::SECTOR_U64);
+
+        if let Ok(footer) = trailing {
+            if footer.disk_type() == VhdType::Fixed {
+                if !footer.checksum_valid() {
+                    actions.push(VhdRepairAction::FixFooterChecksum);
+                }
+
+                if !dry_run && !actions.is_empty() {
+                    let mut footer = footer;
+                    footer.update_checksum();
+                    file.write_all_at(file_size - sizes::SECTOR_U64, &footer.to_bytes())?;
+                }
+
+                return Ok(actions);
+            }
+        }
+
+        let backup = VhdFooter::read_unchecked(&file, 0);
+        let (mut footer, trailing) = match (trailing, &backup) {
+            (Ok(footer), _) => (footer, Some(footer)),
+            (Err(_), Ok(backup)) => (*backup, None),
+            (Err(e), Err(_)) => return Err(e),
+        };
+
+        if !footer.checksum_valid() {
+            actions.push(VhdRepairAction::FixFooterChecksum);
+            footer.update_checksum();
+        }
+
+        let mut header = VhdHeader::read_unchecked(&file, footer.data_offset())?;
+        if !header.checksum_valid() {
+            actions.push(VhdRepairAction::FixHeaderChecksum);
+            header.update_checksum();
+        }
+
+        // the footer belongs right after the last block, or the metadata if no block is allocated
+        let table_offset = header.table_offset();
+        let table_size = math::round_up(header.max_bat_size() as u64 * 4, sizes::SECTOR_U64);
+        let mut end = std::cmp::max(footer.data_offset() + 2 * sizes::SECTOR_U64, table_offset + table_size);
+        for locator in header.prt_loc() {
+            if locator.prt_loc_code() == sparse::PLAT_CODE_NONE {
+                continue;
+            }
+
+            // a locator beyond the file is damaged and does not move the footer
+            let locator_end = locator.prt_loc_offset().checked_add(locator.prt_loc_space_bytes());
+            if let Some(locator_end) = locator_end.filter(|locator_end| *locator_end <= file_size) {
+                end = std::cmp::max(end, locator_end);
+            }
+        }
+
+        let mut bat = bat::VhdBat::read(&file, table_offset, header.max_bat_size())?;
+        let bitmap_size = sparse::bitmap_size(header.block_size()) as u64;
+        let mut bat_changed = false;
+        for index in 0..header.max_bat_size() as usize {
+            let block_id = bat.block_id(index)?;
+            if block_id == bat::DD_BLOCK_UNUSED {
+                continue;
+            }
+
+            let offset = block_id as u64 * sizes::SECTOR_U64;
+            if offset + bitmap_size > file_size {
+                actions.push(VhdRepairAction::ClearBatEntry { index, block_id });
+                bat.set_block_id(index, bat::DD_BLOCK_UNUSED)?;
+                bat_changed = true;
+            } else {
+                end = std::cmp::max(end, offset + bitmap_size + header.block_size() as u64);
+            }
+        }
+
+        let footer_bytes = footer.to_bytes();
+        let footer_in_place = end + sizes::SECTOR_U64 == file_size
+            && trailing.is_some_and(|trailing| trailing.to_bytes() == footer_bytes);
+        if !footer_in_place {
+            actions.push(VhdRepairAction::RestoreFooter { offset: end });
+        }
+
+        if !backup.is_ok_and(|backup| backup.to_bytes() == footer_bytes) {
+            actions.push(VhdRepairAction::RestoreBackupFooter);
+        }
+
+        if end + sizes::SECTOR_U64 != file_size {
+            actions.push(VhdRepairAction::ResizeFile { size: end + sizes::SECTOR_U64 });
+        }
+
+        if dry_run || actions.is_empty() {
+            return Ok(actions);
+        }
+
+        for action in &actions {
+            match action {
+                VhdRepairAction::FixHeaderChecksum => header.write(&file, footer.data_offset())?,
+                VhdRepairAction::RestoreFooter { offset } => file.write_all_at(*offset, &footer_bytes)?,
+                VhdRepairAction::RestoreBackupFooter => file.write_all_at(0, &footer_bytes)?,
+                VhdRepairAction::ResizeFile { size } => file.set_len(*size)?,
+                _ => {}
+            }
+        }
+
+        if bat_changed {
+            bat.write(&file, table_offset)?;
+        }
+
+        Ok(actions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ReadAt;
+    use crate::vhd::test_util::temp_path;
+
+    fn create_dynamic(path: &str) -> Vec<u8> {
+        let img = VhdImage::create_dynamic(path, 8).unwrap();
+        let data: Vec<u8> = (0..4096).map(|i| (i % 253) as u8).collect();
+        img.write_all_at(3 << 20, &data).unwrap();
+        data
+    }
+
+    #[test]
+    fn repair_trailing_footer_test() {
+        let path = temp_path("rvhd_repair_trailing_footer.vhd");
+        let data = create_dynamic(&path);
+
+        // the footer was overwritten, as during a block allocation
+        let file = VhdFile::open(&path, OpenMode::ReadWrite).unwrap();
+        let file_size = file.size().unwrap();
+        file.write_all_at(file_size - sizes::SECTOR_U64, &[0_u8; 512]).unwrap();
+        drop(file);
+        assert!(matches!(VhdImage::open(path.as_str()), Err(VhdError::InvalidHeaderCookie)));
+
+        let actions = VhdImage::repair(path.as_str(), true).unwrap();
+        assert_eq!(actions, [VhdRepairAction::RestoreFooter { offset: file_size - sizes::SECTOR_U64 }]);
+        assert!(VhdImage::open(path.as_str()).is_err());
+
+        assert_eq!(VhdImage::repair(path.as_str(), false).unwrap(), actions);
+        assert!(VhdImage::repair(path.as_str(), false).unwrap().is_empty());
+
+        let img = VhdImage::open(path.as_str()).unwrap();
+        let mut buffer = vec![0_u8; data.len()];
+        img.read_exact_at(3 << 20, &mut buffer).unwrap();
+        assert!(buffer == data);
+        drop(img);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn repair_truncated_test() {
+        let path = temp_path("rvhd_repair_truncated.vhd");
+        let data = create_dynamic(&path);
+
+        // the footer and the end of the block are lost
+        let file = VhdFile::open(&path, OpenMode::ReadWrite).unwrap();
+        let file_size = file.size().unwrap();
+        file.set_len(file_size - 4096).unwrap();
+        drop(file);
+
+        let actions = VhdImage::repair(path.as_str(), false).unwrap();
+        assert_eq!(actions, [
+            VhdRepairAction::RestoreFooter { offset: file_size - sizes::SECTOR_U64 },
+            VhdRepairAction::ResizeFile { size: file_size },
+        ]);
+
+        assert!(VhdImage::check(path.as_str()).unwrap().is_ok());
+        let img = VhdImage::open(path.as_str()).unwrap();
+        let mut buffer = vec![0_u8; data.len()];
+        img.read_exact_at(3 << 20, &mut buffer).unwrap();
+        assert!(buffer == data);
+        drop(img);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn repair_backup_footer_test() {
+        let path = temp_path("rvhd_repair_backup_footer.vhd");
+        create_dynamic(&path);
+
+        // a flipped bit in the backup footer and a wrong header checksum
+        let file = VhdFile::open(&path, OpenMode::ReadWrite).unwrap();
+        file.write_all_at(100, &[0xFF_u8]).unwrap();
+        file.write_all_at(sizes::SECTOR_U64 + 36, &[0_u8; 4]).unwrap();
+        drop(file);
+
+        let actions = VhdImage::repair(path.as_str(), false).unwrap();
+        assert_eq!(actions, [VhdRepairAction::FixHeaderChecksum, VhdRepairAction::RestoreBackupFooter]);
+        assert!(VhdImage::check(path.as_str()).unwrap().is_ok());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn repair_bogus_locator_test() {
+        let path = temp_path("rvhd_repair_bogus_locator.vhd");
+        create_dynamic(&path);
+
+        // the first parent locator entry: code, space, length, reserved and offset
+        let file = VhdFile::open(&path, OpenMode::ReadWrite).unwrap();
+        let mut header = [0_u8; 1024];
+        file.read_exact_at(DEFAULT_HEADER_OFFSET, &mut header).unwrap();
+        header[576..580].copy_from_slice(&sparse::PLAT_CODE_W2KU.to_be_bytes());
+        header[580..584].copy_from_slice(&512_u32.to_be_bytes());
+        header[584..588].copy_from_slice(&16_u32.to_be_bytes());
+        header[592..600].copy_from_slice(&(u64::MAX - 100).to_be_bytes());
+        header[36..40].fill(0);
+        let checksum = !header.iter().map(|b| *b as u32).sum::<u32>();
+        header[36..40].copy_from_slice(&checksum.to_be_bytes());
+        file.write_all_at(DEFAULT_HEADER_OFFSET, &header).unwrap();
+        drop(file);
+
+        assert!(VhdImage::repair(path.as_str(), false).unwrap().is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn repair_fixed_test() {
+        let path = temp_path("rvhd_repair_fixed.vhd");
+        drop(VhdImage::create_fixed(path.as_str(), 2).unwrap());
+
+        // corrupt the checksum
+        let file = VhdFile::open(&path, OpenMode::ReadWrite).unwrap();
+        let footer_pos = file.size().unwrap() - sizes::SECTOR_U64;
+        file.write_all_at(footer_pos + 64, &[0_u8; 4]).unwrap();
+        drop(file);
+        assert!(matches!(VhdImage::open(path.as_str()), Err(VhdError::InvalidHeaderChecksum)));
+
+        assert_eq!(VhdImage::repair(path.as_str(), false).unwrap(), [VhdRepairAction::FixFooterChecksum]);
+        assert!(VhdImage::open(path.as_str()).is_ok());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,338 @@
+use super::*;
+use crate::{math, sizes, Disk, ReadAt, Result, VhdFile, WriteAt};
+
+// copies are done in chunks of at most this size
+const COPY_CHUNK: u64 = sizes::MIB;
+
+pub(super) fn copy_range(file: &VhdFile, from: u64, to: u64, len: u64) -> Result<()> {
+    let mut buffer = vec![0_u8; std::cmp::min(len, COPY_CHUNK) as usize];
+    let mut done = 0;
+
+    while done < len {
+        let chunk = std::cmp::min(len - done, COPY_CHUNK) as usize;
+        file.read_exact_at(from + done, &mut buffer[..chunk])?;
+        file.write_all_at(to + done, &buffer[..chunk])?;
+        done += chunk as u64;
+    }
+
+    Ok(())
+}
+
+// the size of the journal entries saving the data cut from a fixed image
+const JOURNAL_CHUNK_SIZE: u64 = 16 << 20;
+
+fn resize_fixed(img: &VhdImage, journal: &VhdJournal, file: &VhdFile, size: u64) -> Result<()> {
+    let mut footer = *img.footer();
+    footer.set_current_size(size);
+
+    // the data cut by a shrink is journaled so that a revert brings it back
+    let capacity = img.capacity()?;
+    let mut offset = size;
+    while offset < capacity {
+        let len = std::cmp::min(capacity - offset, JOURNAL_CHUNK_SIZE);
+        journal.add_data(offset, len as u32)?;
+        offset += len;
+    }
+
+    // cutting at the old capacity first drops the old footer and zero fills the new space
+    file.set_len(std::cmp::min(size, capacity))?;
+    file.set_len(size)?;
+    file.write_all_at(size, &footer.to_bytes())
+}
+
+fn resize_sparse(img: &VhdImage, journal: &VhdJournal, file: &VhdFile, size: u64) -> Result<()> {
+    let mut footer = *img.footer();
+    let mut header = *img.sparse_header().unwrap();
+    let block_size = header.block_size() as u64;
+    let bitmap_size = sparse::bitmap_size(header.block_size()) as u64;
+    let old_entries = header.max_bat_size() as usize;
+    let entries = math::ceil(size, block_size) as usize;
+
+    let table_offset = header.table_offset();
+    let table_end = table_offset + math::round_up(entries as u64 * 4, sizes::SECTOR_U64);
+    let mut bat = bat::VhdBat::read(file, table_offset, header.max_bat_size())?;
+
+    // everything is journaled before the first write, as the journal flushes the image;
+    // the partially kept last block of a shrunk image must read zeros if it grows again
+    let last_block = if entries < old_entries && !size.is_multiple_of(block_size) {
+        Some(entries - 1).filter(|index| bat.block_id(*index).is_ok_and(|id| id != bat::DD_BLOCK_UNUSED))
+    } else {
+        None
+    };
+    if let Some(index) = last_block {
+        journal.add_block(img, index, VHD_JOURNAL_METADATA)?;
+    }
+
+    // the blocks the grown BAT would overwrite are moved to the end of the file
+    let mut moved_blocks = Vec::new();
+    for index in 0..old_entries {
+        let block_id = bat.block_id(index)?;
+        if block_id != bat::DD_BLOCK_UNUSED && (block_id as u64 * sizes::SECTOR_U64) < table_end {
+            journal.add_block(img, index, VHD_JOURNAL_METADATA | VHD_JOURNAL_DATA)?;
+            moved_blocks.push(index);
+        }
+    }
+
+    if let Some(index) = last_block {
+        let bitmap_offset = bat.block_id(index)? as u64 * sizes::SECTOR_U64;
+        let mut bitmap = vec![0_u8; bitmap_size as usize];
+        file.read_exact_at(bitmap_offset, &mut bitmap)?;
+
+        let first_sector = ((size % block_size) / sizes::SECTOR_U64) as usize;
+        for sector in first_sector..(block_size / sizes::SECTOR_U64) as usize {
+            bitmap[sector / 8] &= !(0x80 >> (sector % 8));
+        }
+        file.write_all_at(bitmap_offset, &bitmap)?;
+    }
+
+    let mut end = std::cmp::max(img.file_size()? - img.footer_size(), table_end);
+    for (index, locator) in header.prt_loc().to_vec().iter().enumerate() {
+        if locator.prt_loc_code() != sparse::PLAT_CODE_NONE && locator.prt_loc_offset() < table_end {
+            copy_range(file, locator.prt_loc_offset(), end, locator.prt_loc_space_bytes())?;
+            header.set_locator_offset(index, end);
+            end += locator.prt_loc_space_bytes();
+        }
+    }
+
+    for index in moved_blocks {
+        let offset = bat.block_id(index)? as u64 * sizes::SECTOR_U64;
+        copy_range(file, offset, end, bitmap_size + block_size)?;
+        bat.set_block_id(index, (end / sizes::SECTOR_U64) as u32)?;
+        end += bitmap_size + block_size;
+    }
+
+    let mut new_bat = bat::VhdBat::new(entries as u32);
+    for index in 0..std::cmp::min(entries, old_entries) {
+        new_bat.set_block_id(index, bat.block_id(index)?)?;
+    }
+    new_bat.write(file, table_offset)?;
+
+    header.set_max_bat_size(entries as u32);
+    header.write(file, footer.data_offset())?;
+
+    footer.set_current_size(size);
+    let footer_bytes = footer.to_bytes();
+    file.write_all_at(0, &footer_bytes)?;
+    file.write_all_at(end, &footer_bytes)?;
+    file.set_len(end + FOOTER_SIZE)
+}
+
+// resizes the image through `file`, the changes being saved in `journal` first
+pub(super) fn resize_journaled(img: &VhdImage, journal: &VhdJournal, file: &VhdFile, size: u64) -> Result<()> {
+    match img.disk_type() {
+        VhdType::Fixed => resize_fixed(img, journal, file, size),
+        VhdType::Dynamic | VhdType::Diff => resize_sparse(img, journal, file, size),
+    }
+}
+
+impl VhdImage {
+    /// Changes the virtual size of the image to `size` bytes rounded up to a sector, like `vhd-util resize`.
+    ///
+    /// A fixed image gets its data area extended or cut and the footer moved. A dynamic or
+    /// differencing image gets its BAT grown in place, the locators and first blocks in the
+    /// way being moved to the end of the file; a shrunk one drops the BAT entries past the end.
+    /// The changes are protected by a journal at `jpath`, reverted if the resize fails and
+    /// left behind if it is interrupted, see [`VhdJournal::open`].
+    ///
+    /// ```
+    /// use rvhd_util_convert::{Disk, OpenMode, VhdImage, VhdJournal};
+    ///
+    /// let path = std::env::temp_dir().join("rvhd_doc_resize.vhd");
+    /// let path = path.to_str().unwrap();
+    /// drop(VhdImage::create_dynamic(path, 4).unwrap());
+    ///
+    /// let mut img = VhdImage::open_with_mode(path, OpenMode::ReadWrite).unwrap();
+    /// img.resize(64 << 20, &VhdJournal::default_path(path)).unwrap();
+    /// assert_eq!(img.capacity().unwrap(), 64 << 20);
+    /// # drop(img);
+    /// # std::fs::remove_file(path).unwrap();
+    /// ```
+    pub fn resize(&mut self, size: u64, jpath: &str) -> Result<()> {
+        let size = math::round_up(size, sizes::SECTOR_U64);
+        image::check_max_size(size)?;
+
+        if size == self.capacity()? {
+            return Ok(());
+        }
+
+        self.journaled(jpath, |img, journal, file| resize_journaled(img, journal, file, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Flush, Geometry, OpenMode, VhdError};
+    use crate::vhd::test_util::{temp_path, pattern, read};
+
+    #[test]
+    fn resize_fixed_test() {
+        let path = temp_path("rvhd_resize_fixed.vhd");
+        let jpath = VhdJournal::default_path(&path);
+        let data = pattern(4096, 1);
+
+        let mut img = VhdImage::create_fixed(path.as_str(), 2).unwrap();
+        img.write_all_at((2 << 20) - 4096, &data).unwrap();
+
+        img.resize(5 << 20, &jpath).unwrap();
+        assert_eq!(img.capacity().unwrap(), 5 << 20);
+        assert_eq!(img.file_size().unwrap(), (5 << 20) + FOOTER_SIZE);
+        assert!(read(&img, (2 << 20) - 4096, 4096) == data);
+        assert!(read(&img, 2 << 20, 4096).iter().all(|b| *b == 0));
+
+        img.resize(1 << 20, &jpath).unwrap();
+        img.resize(3 << 20, &jpath).unwrap();
+        assert!(read(&img, (2 << 20) - 4096, 4096).iter().all(|b| *b == 0));
+        drop(img);
+
+        let img = VhdImage::open(path.as_str()).unwrap();
+        assert_eq!(img.capacity().unwrap(), 3 << 20);
+        assert_eq!(img.geometry().unwrap().capacity(), Geometry::with_vhd_capacity(3 << 20).capacity());
+        assert!(!std::path::Path::new(&jpath).exists());
+        drop(img);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn resize_fixed_revert_test() {
+        let path = temp_path("rvhd_resize_fixed_revert.vhd");
+        let jpath = VhdJournal::default_path(&path);
+        let data = pattern(4096, 5);
+
+        let img = VhdImage::create_fixed(path.as_str(), 4).unwrap();
+        img.write_all_at(3 << 20, &data).unwrap();
+        drop(img);
+        let original = std::fs::read(&path).unwrap();
+
+        // a failure after the shrink brings the cut data back
+        let mut img = VhdImage::open_with_mode(path.as_str(), OpenMode::ReadWrite).unwrap();
+        let res = img.journaled(&jpath, |img, journal, file| {
+            resize_fixed(img, journal, file, 1 << 20)?;
+            Err(VhdError::WriteZero)
+        });
+        assert!(matches!(res, Err(VhdError::WriteZero)));
+        assert_eq!(img.capacity().unwrap(), 4 << 20);
+        assert!(read(&img, 3 << 20, 4096) == data);
+        drop(img);
+
+        assert!(!std::path::Path::new(&jpath).exists());
+        assert!(std::fs::read(&path).unwrap() == original);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn resize_dynamic_in_place_test() {
+        let path = temp_path("rvhd_resize_dynamic_in_place.vhd");
+        let jpath = VhdJournal::default_path(&path);
+        let data = pattern(8192, 2);
+
+        // the BAT of 2 entries has room for 128 in its sector
+        let mut img = VhdImage::create_dynamic(path.as_str(), 4).unwrap();
+        img.write_all_at(3 << 20, &data).unwrap();
+        img.flush().unwrap();
+        let file_size = img.file_size().unwrap();
+
+        img.resize(200 << 20, &jpath).unwrap();
+        assert_eq!(img.sparse_header().unwrap().max_bat_size(), 100);
+        assert_eq!(img.file_size().unwrap(), file_size);
+        img.write_all_at(199 << 20, &data).unwrap();
+        drop(img);
+
+        let img = VhdImage::open(path.as_str()).unwrap();
+        assert_eq!(img.capacity().unwrap(), 200 << 20);
+        assert!(read(&img, 3 << 20, data.len()) == data);
+        assert!(read(&img, 199 << 20, data.len()) == data);
+        drop(img);
+        assert!(VhdImage::check(path.as_str()).unwrap().is_ok());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn resize_dynamic_relocate_test() {
+        let path = temp_path("rvhd_resize_dynamic_relocate.vhd");
+        let jpath = VhdJournal::default_path(&path);
+
+        let mut img = VhdImage::create_dynamic(path.as_str(), 8).unwrap();
+        for index in 0..4_u64 {
+            img.write_all_at(index << 20 << 1, &pattern(4096, index as usize)).unwrap();
+        }
+
+        // 1024 entries need 8 sectors, the first blocks are in the way
+        img.resize(2 << 30, &jpath).unwrap();
+        assert_eq!(img.sparse_header().unwrap().max_bat_size(), 1024);
+        for index in 0..4_u64 {
+            assert!(read(&img, index << 20 << 1, 4096) == pattern(4096, index as usize));
+        }
+        drop(img);
+
+        assert!(VhdImage::check(path.as_str()).unwrap().is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn resize_dynamic_shrink_test() {
+        let path = temp_path("rvhd_resize_dynamic_shrink.vhd");
+        let jpath = VhdJournal::default_path(&path);
+        let data = pattern(1 << 20, 3);
+
+        let mut img = VhdImage::create_dynamic(path.as_str(), 8).unwrap();
+        img.write_all_at(4 << 20, &data).unwrap();
+        img.write_all_at(6 << 20, &data).unwrap();
+
+        img.resize((4 << 20) + 4096, &jpath).unwrap();
+        assert_eq!(img.sparse_header().unwrap().max_bat_size(), 3);
+        assert!(read(&img, 4 << 20, 4096) == data[..4096]);
+        assert!(matches!(img.read_at(5 << 20, &mut [0_u8; 512]), Err(VhdError::ReadBeyondEOD)));
+
+        img.resize(8 << 20, &jpath).unwrap();
+        assert!(read(&img, 4 << 20, 4096) == data[..4096]);
+        assert!(read(&img, (4 << 20) + 4096, 4096).iter().all(|b| *b == 0));
+        assert!(read(&img, 6 << 20, 4096).iter().all(|b| *b == 0));
+        drop(img);
+
+        assert!(VhdImage::check(path.as_str()).unwrap().is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn resize_diff_test() {
+        let base = temp_path("rvhd_resize_diff_base.vhd");
+        let child = temp_path("rvhd_resize_diff_child.vhd");
+        let jpath = VhdJournal::default_path(&child);
+        let data = pattern(4096, 4);
+
+        let img = VhdImage::create_dynamic(base.as_str(), 2).unwrap();
+        img.write_all_at((2 << 20) - 4096, &data).unwrap();
+        drop(img);
+        drop(VhdImage::create_diff(child.as_str(), base.as_str()).unwrap());
+
+        let mut img = VhdImage::open_with_mode(child.as_str(), OpenMode::ReadWrite).unwrap();
+        // the locators are in the way of the grown BAT
+        img.resize(2 << 30, &jpath).unwrap();
+
+        // the part beyond the parent reads zeros
+        let buffer = read(&img, (2 << 20) - 4096, 8192);
+        assert!(buffer[..4096] == data[..]);
+        assert!(buffer[4096..].iter().all(|b| *b == 0));
+        drop(img);
+
+        assert!(VhdImage::check(child.as_str()).unwrap().is_ok());
+        std::fs::remove_file(child).unwrap();
+        std::fs::remove_file(base).unwrap();
+    }
+
+    #[test]
+    fn resize_read_only_test() {
+        let path = temp_path("rvhd_resize_read_only.vhd");
+        drop(VhdImage::create_dynamic(path.as_str(), 2).unwrap());
+
+        let mut img = VhdImage::open(path.as_str()).unwrap();
+        let res = img.resize(4 << 20, &VhdJournal::default_path(&path));
+        assert!(matches!(res, Err(VhdError::ReadOnly)));
+        drop(img);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
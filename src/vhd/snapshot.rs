@@ -0,0 +1,227 @@
+use std::path::Path;
+
+use super::*;
+use crate::{Flush, OpenMode, Result, VhdError};
+
+/// Options for [`VhdImage::snapshot`].
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct VhdSnapshotOptions {
+    create: VhdCreateOptions,
+    raw_parent: bool,
+    hide_parent: bool,
+    read_only_parent: bool,
+}
+
+impl VhdSnapshotOptions {
+    /// Options of the new differencing image.
+    pub fn create(mut self, create: VhdCreateOptions) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// The parent is a raw disk image rather than a VHD, like `vhd-util snapshot -m`.
+    pub fn raw_parent(mut self, raw_parent: bool) -> Self {
+        self.raw_parent = raw_parent;
+        self
+    }
+
+    /// Sets the blktap `hidden` flag in the footer of a VHD parent.
+    pub fn hide_parent(mut self, hide_parent: bool) -> Self {
+        self.hide_parent = hide_parent;
+        self
+    }
+
+    /// Makes the parent file read-only.
+    pub fn read_only_parent(mut self, read_only_parent: bool) -> Self {
+        self.read_only_parent = read_only_parent;
+        self
+    }
+}
+
+impl VhdImage {
+    /// Creates a differencing image at `path` on top of the fixed, dynamic or differencing image
+    /// `parent`, or of a raw disk image with `options.raw_parent`. The new image is returned
+    /// opened for writing.
+    ///
+    /// The resulting chain must not be deeper than [`MAX_CHAIN_DEPTH`]. The parent is hidden
+    /// and made read-only if asked to once the child is created, so it is left untouched if
+    /// the child cannot be created.
+    ///
+    /// ```
+    /// use rvhd_util_convert::{VhdImage, VhdSnapshotOptions, VhdType};
+    ///
+    /// let dir = std::env::temp_dir();
+    /// let parent = dir.join("rvhd_doc_snapshot_parent.vhd");
+    /// let child = dir.join("rvhd_doc_snapshot_child.vhd");
+    /// drop(VhdImage::create_fixed(parent.to_str().unwrap(), 2).unwrap());
+    ///
+    /// let options = VhdSnapshotOptions::default().hide_parent(true);
+    /// let img = VhdImage::snapshot(child.to_str().unwrap(), parent.to_str().unwrap(), &options).unwrap();
+    /// assert_eq!(img.disk_type(), VhdType::Diff);
+    /// assert!(img.parent().unwrap().footer().hidden());
+    /// # drop(img);
+    /// # std::fs::remove_file(child).unwrap();
+    /// # std::fs::remove_file(parent).unwrap();
+    /// ```
+    pub fn snapshot<S: Into<String>>(path: S, parent: S, options: &VhdSnapshotOptions) -> Result<Self> {
+        let path = path.into();
+        let parent_path = parent.into();
+
+        if !Path::new(&parent_path).exists() {
+            return Err(VhdError::ParentNotExist);
+        }
+
+        let parent = if options.raw_parent {
+            Self::open_raw(parent_path.clone())?
+        } else {
+            Self::open(parent_path.as_str())?
+        };
+        let hide_parent = options.hide_parent && !parent.is_raw() && !parent.footer().hidden();
+
+        let img = Self::create_diff_of(path.clone(), parent, &options.create)?;
+        if !hide_parent && !options.read_only_parent {
+            return Ok(img);
+        }
+        drop(img);
+
+        // the child is removed if the parent cannot be changed
+        if let Err(e) = Self::protect_parent(&parent_path, hide_parent, options.read_only_parent) {
+            let _ = std::fs::remove_file(&path);
+            return Err(e);
+        }
+
+        // reopened to see the changed parent
+        Self::open_with_mode(path.as_str(), OpenMode::ReadWrite)
+    }
+
+    fn protect_parent(parent_path: &str, hide: bool, read_only: bool) -> Result<()> {
+        if hide {
+            let mut img = Self::open_with_mode(parent_path, OpenMode::ReadWrite)?;
+            img.footer_mut().set_hidden(true);
+            img.flush()?;
+        }
+
+        if read_only {
+            let mut permissions = std::fs::metadata(parent_path)?.permissions();
+            permissions.set_readonly(true);
+            std::fs::set_permissions(parent_path, permissions)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Disk, WriteAt};
+    use crate::vhd::test_util::{temp_path, read};
+
+    #[test]
+    fn snapshot_fixed_test() {
+        let base = temp_path("rvhd_snapshot_fixed_base.vhd");
+        let child = temp_path("rvhd_snapshot_fixed_child.vhd");
+
+        let img = VhdImage::create_fixed(base.as_str(), 4).unwrap();
+        img.write_all_at(0, &[1_u8; 4096]).unwrap();
+        drop(img);
+
+        let options = VhdSnapshotOptions::default().read_only_parent(true);
+        let img = VhdImage::snapshot(child.as_str(), base.as_str(), &options).unwrap();
+        assert_eq!(img.capacity().unwrap(), 4 << 20);
+        img.write_all_at(512, &[2_u8; 512]).unwrap();
+        drop(img);
+
+        assert!(std::fs::metadata(&base).unwrap().permissions().readonly());
+
+        let img = VhdImage::open(child.as_str()).unwrap();
+        assert_eq!(img.parent().unwrap().disk_type(), VhdType::Fixed);
+        let buffer = read(&img, 0, 1024);
+        assert!(buffer[..512].iter().all(|b| *b == 1));
+        assert!(buffer[512..].iter().all(|b| *b == 2));
+        drop(img);
+        assert!(VhdImage::check(child.as_str()).unwrap().is_ok());
+
+        std::fs::remove_file(child).unwrap();
+        std::fs::remove_file(base).unwrap();
+    }
+
+    #[test]
+    fn snapshot_diff_hidden_test() {
+        let base = temp_path("rvhd_snapshot_hidden_base.vhd");
+        let middle = temp_path("rvhd_snapshot_hidden_middle.vhd");
+        let child = temp_path("rvhd_snapshot_hidden_child.vhd");
+
+        drop(VhdImage::create_dynamic(base.as_str(), 2).unwrap());
+        drop(VhdImage::create_diff(middle.as_str(), base.as_str()).unwrap());
+
+        let options = VhdSnapshotOptions::default().hide_parent(true);
+        let img = VhdImage::snapshot(child.as_str(), middle.as_str(), &options).unwrap();
+        let types: Vec<VhdType> = img.chain().map(|img| img.disk_type()).collect();
+        assert_eq!(types, [VhdType::Diff, VhdType::Diff, VhdType::Dynamic]);
+        drop(img);
+
+        // hiding the parent keeps the time stamp the child records
+        assert!(VhdImage::open(middle.as_str()).unwrap().footer().hidden());
+        assert!(VhdImage::check(middle.as_str()).unwrap().is_ok());
+        assert!(VhdImage::check(child.as_str()).unwrap().is_ok());
+
+        std::fs::remove_file(child).unwrap();
+        std::fs::remove_file(middle).unwrap();
+        std::fs::remove_file(base).unwrap();
+    }
+
+    #[test]
+    fn snapshot_raw_test() {
+        let raw = temp_path("rvhd_snapshot_raw.img");
+        let child = temp_path("rvhd_snapshot_raw_child.vhd");
+        std::fs::write(&raw, vec![0x5A_u8; 3 << 20]).unwrap();
+
+        // a raw file is not a VHD parent
+        let res = VhdImage::snapshot(child.as_str(), raw.as_str(), &VhdSnapshotOptions::default());
+        assert!(res.is_err());
+
+        let options = VhdSnapshotOptions::default().raw_parent(true);
+        let img = VhdImage::snapshot(child.as_str(), raw.as_str(), &options).unwrap();
+        assert!(img.sparse_header().unwrap().prt_uuid().is_nil());
+        img.write_all_at(0, &[1_u8; 512]).unwrap();
+        drop(img);
+
+        let img = VhdImage::open(child.as_str()).unwrap();
+        assert!(img.parent().unwrap().is_raw());
+        assert_eq!(img.capacity().unwrap(), 3 << 20);
+        let buffer = read(&img, 0, 1024);
+        assert!(buffer[..512].iter().all(|b| *b == 1));
+        assert!(buffer[512..].iter().all(|b| *b == 0x5A));
+        drop(img);
+        assert!(VhdImage::check(child.as_str()).unwrap().is_ok());
+
+        std::fs::remove_file(child).unwrap();
+        std::fs::remove_file(raw).unwrap();
+    }
+
+    #[test]
+    fn snapshot_chain_depth_test() {
+        let paths: Vec<String> = (0..=MAX_CHAIN_DEPTH)
+            .map(|i| temp_path(&format!("rvhd_snapshot_depth_{}.vhd", i)))
+            .collect();
+
+        drop(VhdImage::create_dynamic(paths[0].as_str(), 2).unwrap());
+        for i in 1..MAX_CHAIN_DEPTH {
+            drop(VhdImage::create_diff(paths[i].as_str(), paths[i - 1].as_str()).unwrap());
+        }
+
+        let res = VhdImage::snapshot(
+            paths[MAX_CHAIN_DEPTH].as_str(),
+            paths[MAX_CHAIN_DEPTH - 1].as_str(),
+            &VhdSnapshotOptions::default().hide_parent(true),
+        );
+        assert!(matches!(res, Err(VhdError::ChainTooDeep)));
+        assert!(!Path::new(&paths[MAX_CHAIN_DEPTH]).exists());
+        assert!(!VhdImage::open(paths[MAX_CHAIN_DEPTH - 1].as_str()).unwrap().footer().hidden());
+
+        for path in &paths[..MAX_CHAIN_DEPTH] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -1,67 +1,172 @@
 mod header;
-use std::cell::{RefCell, Ref};
-use std::path::{Path, MAIN_SEPARATOR};
+use std::collections::VecDeque;
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 pub use header::*;
 
-use crate::{AsByteSliceMut, StructBuffer, AsByteSlice};
-use crate::{util, math, sizes, Result, VhdFile, ReadAt, WriteAt, Flush, SeekAt, ImageExtent, ImageExtentOps, VhdError};
+use crate::AsByteSlice;
+use crate::{util, math, sizes, Result, VhdFile, ReadAt, WriteAt, Flush, SeekAt, ImageExtent, ImageExtentOps, VhdError, Disk};
 
-use super::{VhdImage, VhdImageExtent, VhdFooter, DEFAULT_HEADER_OFFSET, DEFAULT_TABLE_OFFSET, VhdType};
+use super::{VhdImage, VhdImageExtent, VhdFooter, FOOTER_SIZE, DEFAULT_HEADER_OFFSET, DEFAULT_TABLE_OFFSET};
 
-pub mod bat;
+pub(crate) mod bat;
 
-pub struct SparseExtent {
+/// Number of locks the blocks are spread over, see [`SparseExtent::block_lock`].
+const BLOCK_LOCKS: usize = 64;
+
+// Lock order: block lock, then the allocation lock or the bitmap cache, then the BAT.
+pub(crate) struct SparseExtent {
     file: VhdFile,
     file_path: String,
     header: VhdHeader,
-    bat: RefCell<bat::VhdBat>,      
-    cached_block_index: RefCell<usize>,
-    cached_bitmap: RefCell<Vec<u8>>,
-    cached_bitmap_dirty: RefCell<bool>,
-    next_block_pos: RefCell<u64>,
+    bat: RwLock<bat::VhdBat>,
+    bitmap_size: u32,
+    bitmap_cache: Mutex<BitmapCache>,
+    // the allocation lock, also held while the trailing footer is written
+    next_block_pos: Mutex<u64>,
+    block_locks: Vec<RwLock<()>>,
+    // whether writes look for zeroed blocks, see [`VhdImage::set_detect_zeroes`]
+    detect_zeroes: AtomicBool,
+    footer_size: u64,
     parent: Option<VhdImage>,
 }
 
+/// Number of block bitmaps an image caches unless set by [`VhdImage::set_bitmap_cache_size`].
+pub const DEFAULT_BITMAP_CACHE_SIZE: usize = 64;
+
+struct CachedBitmap {
+    block_index: usize,
+    bitmap: Vec<u8>,
+    dirty: bool,
+}
+
+// the bitmaps of the most recently used blocks, the most recent first
+struct BitmapCache {
+    entries: VecDeque<CachedBitmap>,
+    capacity: usize,
+}
+
+// A part of the file accessed with a single operation. When blocks are physically adjacent
+// the run spans the bitmap between them: it is skipped on reads and rewritten on writes.
+struct FileRun {
+    file_offset: u64,
+    len: usize,
+    // (offset in the caller buffer, offset in the run, length) of the data
+    pieces: Vec<(usize, usize, usize)>,
+    // (offset in the run, new bitmap) of the bitmaps spanned by a write
+    bitmaps: Vec<(usize, Vec<u8>)>,
+    // (block index, first sector, sectors count) to mark present once written
+    sectors: Vec<(usize, u32, u32)>,
+}
+
+impl FileRun {
+    fn new(file_offset: u64) -> Self {
+        Self { file_offset, len: 0, pieces: Vec::new(), bitmaps: Vec::new(), sectors: Vec::new() }
+    }
+
+    fn end(&self) -> u64 {
+        self.file_offset + self.len as u64
+    }
+}
+
+// (buffer index, start, end) of the parts of the buffers of lengths @lens covering @len bytes
+// from @offset, the buffers being seen one after the other
+fn buffer_parts(lens: impl Iterator<Item = usize>, mut offset: usize, mut len: usize) -> Vec<(usize, usize, usize)> {
+    let mut parts = Vec::new();
+    for (index, buffer_len) in lens.enumerate() {
+        if len == 0 {
+            break;
+        }
+        if offset >= buffer_len {
+            offset -= buffer_len;
+            continue;
+        }
+
+        let n = std::cmp::min(len, buffer_len - offset);
+        parts.push((index, offset, offset + n));
+        offset = 0;
+        len -= n;
+    }
+
+    parts
+}
+
+// the buffers of a vectored read, addressed by the offset in the request
+struct ReadBuffers<'a, 'b>(&'a mut [&'b mut [u8]]);
+
+impl ReadBuffers<'_, '_> {
+    fn len(&self) -> usize {
+        self.0.iter().map(|buffer| buffer.len()).sum()
+    }
+
+    fn slices(&mut self, offset: usize, len: usize) -> Vec<&mut [u8]> {
+        let parts = buffer_parts(self.0.iter().map(|buffer| buffer.len()), offset, len);
+        let mut buffers: Vec<Option<&mut [u8]>> = self.0.iter_mut().map(|buffer| Some(&mut **buffer)).collect();
+        parts.into_iter().map(|(index, start, end)| &mut buffers[index].take().unwrap()[start..end]).collect()
+    }
+
+    fn copy_from(&mut self, offset: usize, data: &[u8]) {
+        let mut done = 0;
+        for slice in self.slices(offset, data.len()) {
+            slice.copy_from_slice(&data[done..done + slice.len()]);
+            done += slice.len();
+        }
+    }
+}
+
+// the buffers of a vectored write, addressed by the offset in the request
+struct WriteBuffers<'a, 'b>(&'a [&'b [u8]]);
+
+impl<'b> WriteBuffers<'_, 'b> {
+    fn len(&self) -> usize {
+        self.0.iter().map(|buffer| buffer.len()).sum()
+    }
+
+    fn slices(&self, offset: usize, len: usize) -> Vec<&'b [u8]> {
+        buffer_parts(self.0.iter().map(|buffer| buffer.len()), offset, len)
+            .into_iter()
+            .map(|(index, start, end)| &self.0[index][start..end])
+            .collect()
+    }
+
+    fn copy_to(&self, offset: usize, buffer: &mut [u8]) {
+        let mut done = 0;
+        for slice in self.slices(offset, buffer.len()) {
+            buffer[done..done + slice.len()].copy_from_slice(slice);
+            done += slice.len();
+        }
+    }
+}
+
 impl ReadAt for SparseExtent {
-    fn read_at(&self, mut offset: u64, mut buffer: &mut [u8]) -> Result<usize> {
-        let mut readed = 0_usize;
-        while !buffer.is_empty() {
-            match self.read_block(offset, buffer)? {
-                0 => break,
-                n => {
-                    buffer = &mut buffer[n..];
-                    offset += n as u64;
-                    readed += n;
-                }
-            }
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        if buffer.is_empty() {
+            return Ok(0);
         }
 
-        Ok(readed)
+        self.read_vectored_at(offset, std::slice::from_mut(&mut &mut *buffer))
     }
 }
 
 impl WriteAt for SparseExtent {
-    fn write_at(&self, mut offset: u64, mut data: &[u8]) -> Result<usize> {
-        let mut written = 0_usize;
-        while !data.is_empty() {
-            match self.write_block(offset, data)? {
-                0 => break,
-                n => {
-                    data = &data[n..];
-                    offset += n as u64;
-                    written += n;
-                }
-            }
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
         }
 
-        Ok(written)
+        self.write_vectored_at(offset, &[data])
     }
 }
 
 impl Flush for SparseExtent {
     fn flush(&self) -> Result<()> {
-        self.save_cached_bitmap()?;
+        let mut cache = self.bitmap_cache.lock().unwrap();
+        for entry in cache.entries.iter_mut() {
+            self.save_bitmap(entry)?;
+        }
+        drop(cache);
+
         self.file.flush()
     }
 }
@@ -89,8 +194,16 @@ impl VhdImageExtent for SparseExtent {
         let bytes = footer.to_bytes();
         self.file.write_all_at(0, &bytes)?;
 
-        let next_block_pos = *self.next_block_pos.borrow();
-        self.file.write_all_at(next_block_pos, &bytes)
+        let next_block_pos = self.next_block_pos.lock().unwrap();
+        self.file.write_all_at(*next_block_pos, &bytes[..self.footer_size as usize])
+    }
+
+    fn footer_size(&self) -> u64 {
+        self.footer_size
+    }
+
+    fn set_footer_size(&mut self, footer_size: u64) {
+        self.footer_size = footer_size;
     }
 
     fn sparse_header(&self) -> Option<&VhdHeader> {
@@ -106,9 +219,10 @@ impl VhdImageExtent for SparseExtent {
 
         for (index, loc) in self.header.prt_loc().iter().enumerate() {
             if loc.prt_loc_code() != PLAT_CODE_NONE {
-                let mut buffer = vec![0_u8; loc.prt_loc_len() as usize];
-                self.file.read_exact_at(loc.prt_loc_offset(), buffer.as_mut_slice()).unwrap();
-                let prt_path = std::str::from_utf8(&buffer).unwrap();
+                let prt_path = match self.header.read_locator(&self.file, index) {
+                    Ok(locator) => locator.path().unwrap_or_default().to_string(),
+                    Err(e) => e.to_string(),
+                };
 
                 let locator = format!(
                     "{:<20}: {}
@@ -141,23 +255,131 @@ impl VhdImageExtent for SparseExtent {
         Some(buffer)
     }
 
-    fn sparse_bat(&self) -> Option<&RefCell<bat::VhdBat>> {
+    fn read_parent_locator(&self, index: usize) -> Option<VhdLocator> {
+        match self.header.prt_loc()[index].prt_loc_code() {
+            PLAT_CODE_NONE => None,
+            _ => self.header.read_locator(&self.file, index).ok(),
+        }
+    }
+
+    fn parent(&self) -> Option<&VhdImage> {
+        self.parent.as_ref()
+    }
+
+    fn set_parent(&mut self, parent: VhdImage) {
+        self.parent = Some(parent);
+    }
+
+    fn sparse_bat(&self) -> Option<&RwLock<bat::VhdBat>> {
         Some(&self.bat)
     }
 
-    fn sparse_block_bitmap(&self, bat_block_index: usize) -> Option<(u64, &RefCell<Vec<u8>>)> {
-        let bitmap_offset = self.calc_bitmap_pos(bat_block_index).unwrap();
-        self.populate_block_bitmap(bat_block_index).unwrap();
+    fn sparse_block_bitmap(&self, bat_block_index: usize) -> Result<Option<(u64, Vec<u8>)>> {
+        match self.with_bitmap(bat_block_index, |entry| entry.bitmap.clone())? {
+            Some(bitmap) => Ok(Some((self.calc_bitmap_pos(bat_block_index)?, bitmap))),
+            None => Ok(None),
+        }
+    }
+
+    fn set_bitmap_cache_size(&self, blocks: usize) -> Result<()> {
+        let mut cache = self.bitmap_cache.lock().unwrap();
+        cache.capacity = std::cmp::max(blocks, 1);
+
+        while cache.entries.len() > cache.capacity {
+            let mut entry = cache.entries.pop_back().unwrap();
+            if let Err(e) = self.save_bitmap(&mut entry) {
+                cache.entries.push_back(entry);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> Result<()> {
+        if self.parent.is_some() {
+            // unallocated sectors would show the parent data
+            return self.write_zeroes(offset, len);
+        }
+
+        let block_size = self.header.block_size() as u64;
+        let end = offset + len;
+        let mut pos = offset;
+        while pos < end {
+            let block_index = (pos / block_size) as usize;
+            let block_start = block_index as u64 * block_size;
+            let block_end = std::cmp::min(end, block_start + block_size);
+
+            let _lock = self.block_lock(block_index).write().unwrap();
+            self.discard_block(block_index, pos - block_start, block_end - block_start)?;
+
+            pos = block_end;
+        }
+
+        Ok(())
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64) -> Result<()> {
+        let block_size = self.header.block_size() as u64;
+        let end = offset + len;
+        let mut pos = offset;
+        while pos < end {
+            let block_index = (pos / block_size) as usize;
+            let block_end = std::cmp::min(end, (block_index as u64 + 1) * block_size);
+
+            let _lock = self.block_lock(block_index).write().unwrap();
+            self.zero_block(pos, (block_end - pos) as usize)?;
+
+            pos = block_end;
+        }
+
+        Ok(())
+    }
 
-        Some((bitmap_offset, &self.cached_bitmap))
+    fn set_detect_zeroes(&self, detect: bool) {
+        self.detect_zeroes.store(detect, Ordering::Relaxed);
     }
 
     fn sparse_block_data(&self, bat_block_index: usize, buffer: &mut [u8]) -> Result<u64> {
+        let block_size = self.header.block_size() as u64;
         let block_offset = self.calc_sector_pos(bat_block_index, 0)?;
-        let _ = self.read_block(block_offset, buffer)?;
+        let len = std::cmp::min(buffer.len() as u64, block_size) as usize;
+
+        let _lock = self.block_lock(bat_block_index).read().unwrap();
+        self.read_runs(bat_block_index as u64 * block_size, &mut ReadBuffers(&mut [&mut buffer[..len]]))?;
 
         Ok(block_offset)
     }
+
+    fn read_vectored_at(&self, offset: u64, buffers: &mut [&mut [u8]]) -> Result<usize> {
+        let mut buffers = ReadBuffers(buffers);
+        let len = buffers.len();
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let _locks: Vec<_> = self.block_locks_of(offset, len).into_iter().map(|lock| lock.read().unwrap()).collect();
+        self.read_runs(offset, &mut buffers)?;
+
+        Ok(len)
+    }
+
+    fn write_vectored_at(&self, offset: u64, buffers: &[&[u8]]) -> Result<usize> {
+        let buffers = WriteBuffers(buffers);
+        let len = buffers.len();
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let _locks: Vec<_> = self.block_locks_of(offset, len).into_iter().map(|lock| lock.write().unwrap()).collect();
+        if self.detect_zeroes.load(Ordering::Relaxed) {
+            self.write_runs_detecting_zeroes(offset, &buffers)?;
+        } else {
+            self.write_runs(offset, &buffers)?;
+        }
+
+        Ok(len)
+    }
 }
 
 impl SparseExtent {
@@ -166,16 +388,21 @@ impl SparseExtent {
             file,
             file_path,
             header,
-            bat: RefCell::new(bat),            
-            cached_block_index: RefCell::new(usize::MAX),
-            cached_bitmap: RefCell::new(vec![0_u8; bitmap_size as usize]),
-            cached_bitmap_dirty: RefCell::new(false),
-            next_block_pos: RefCell::new(next_block_pos),
+            bat: RwLock::new(bat),
+            bitmap_size,
+            bitmap_cache: Mutex::new(BitmapCache {
+                entries: VecDeque::new(),
+                capacity: DEFAULT_BITMAP_CACHE_SIZE,
+            }),
+            next_block_pos: Mutex::new(next_block_pos),
+            block_locks: (0..BLOCK_LOCKS).map(|_| RwLock::new(())).collect(),
+            detect_zeroes: AtomicBool::new(false),
+            footer_size: FOOTER_SIZE,
             parent: None,
         }
     }
 
-    pub(crate) fn open(file: VhdFile, file_path: String, data_offset: u64) -> Result<Self> {
+    pub(crate) fn open(file: VhdFile, file_path: String, data_offset: u64, footer_size: u64) -> Result<Self> {
         let header = VhdHeader::read(&file, data_offset)?;
         let file_size = file.size()?;
 
@@ -184,132 +411,168 @@ impl SparseExtent {
         }
 
         let bat = bat::VhdBat::read(&file, header.table_offset(), header.max_bat_size())?;
-        let bitmap_size = math::round_up(math::ceil(header.block_size(), sizes::SECTOR * 8), sizes::SECTOR);         
+        let bitmap_size = bitmap_size(header.block_size());         
         
-        let next_block_pos = file_size - sizes::SECTOR_U64;
+        let next_block_pos = file_size - footer_size;
+
+        let mut this = Self::new(file, file_path, header, bat, bitmap_size, next_block_pos);
+        this.footer_size = footer_size;
 
-        Ok(Self::new(file, file_path, header, bat, bitmap_size, next_block_pos))
+        Ok(this)
     }
 
-    pub(crate) fn create(file_path: String, footer: &VhdFooter, parent: Option<VhdImage>) -> Result<Self> {
-        let (header, relative_utf16_path) = VhdHeader::new(footer.current_size(), DEFAULT_TABLE_OFFSET, DD_BLOCKSIZE_DEFAULT, &file_path, &parent);
+    pub(crate) fn create(file_path: String, footer: &VhdFooter, block_size: u32, parent: Option<VhdImage>) -> Result<Self> {
+        let (header, locators) = VhdHeader::new(footer.current_size(), DEFAULT_TABLE_OFFSET, block_size, &file_path, &parent)?;
         let bat = bat::VhdBat::new(header.max_bat_size());
-        let bitmap_size = math::round_up(math::ceil(header.block_size(), sizes::SECTOR * 8), sizes::SECTOR);        
+        let bitmap_size = bitmap_size(header.block_size());        
         
         let file = VhdFile::create(&file_path, footer.current_size())?;
         header.write(&file, DEFAULT_HEADER_OFFSET)?;
         let bat_size = bat.write(&file, DEFAULT_TABLE_OFFSET)?;
         let mut next_block_pos = DEFAULT_TABLE_OFFSET + bat_size as u64;
-        if parent.is_some() {
-            for i in 0..2 as usize {
-                // write W2ku and W2ru
-                let locator_size = header.write_locator(&file, i, &relative_utf16_path)?;
-                next_block_pos += locator_size as u64;
-            }            
-        } 
-
-        let this = Self::new(file, file_path, header, bat, bitmap_size, next_block_pos);
+        for (i, locator) in locators.iter().enumerate() {
+            // write W2ku, W2ru and MacX
+            let locator_size = header.write_locator(&file, i, locator)?;
+            next_block_pos += locator_size as u64;
+        }
+
+        let mut this = Self::new(file, file_path, header, bat, bitmap_size, next_block_pos);
         this.write_footer(footer)?;
+        this.parent = parent;
 
         Ok(this)
     }    
 }
 
-const INVALID_CACHE_INDEX: usize = usize::max_value();
+/// Size of the sector bitmap in front of every block, padded to a whole sector.
+pub(crate) fn bitmap_size(block_size: u32) -> u32 {
+    math::round_up(math::ceil(block_size, sizes::SECTOR * 8), sizes::SECTOR)
+}
 
 fn calc_sector_mask(sector_in_block: usize) -> u8 {
     1 << (7 - (sector_in_block % 8) as u8)
 }
 
-impl SparseExtent {
-    fn populate_block_bitmap(&self, index: usize) -> Result<bool> {
-        if *self.cached_block_index.borrow() == index {
-            return Ok(true);
+/// Returns whether sector `start` is present in the block `bitmap`, and the number of sectors
+/// from it, up to `max`, in the same state. The bitmap is scanned 64 sectors at a time.
+pub(crate) fn sector_run(bitmap: &[u8], start: usize, max: usize) -> (bool, usize) {
+    let present = bitmap[start / 8] & calc_sector_mask(start) != 0;
+    let end = start + max;
+    let mut sector = start;
+
+    while sector < end {
+        let word_start = sector / 64 * 8;
+        let word_len = std::cmp::min(8, bitmap.len() - word_start);
+        let mut word = [0_u8; 8];
+        word[..word_len].copy_from_slice(&bitmap[word_start..word_start + word_len]);
+
+        // the bits from @sector on that differ from the first one
+        let word = u64::from_be_bytes(word);
+        let changes = if present { !word } else { word } << (sector % 64);
+        if changes != 0 {
+            sector += changes.leading_zeros() as usize;
+            break;
         }
 
-        let block_id = self.bat.borrow().block_id(index)?;
-        if block_id == bat::DD_BLOCK_UNUSED {
-            return Ok(true);
-        }
-
-        self.save_cached_bitmap()?;
+        sector += 64 - sector % 64;
+    }
 
-        let bitmap_pos = block_id as u64 * sizes::SECTOR_U64;
-        self.file
-            .read_exact_at(bitmap_pos, self.cached_bitmap.borrow_mut().as_mut_slice())?;
-        *self.cached_block_index.borrow_mut() = index;
+    (present, std::cmp::min(sector, end) - start)
+}
 
-        Ok(true)
+impl SparseExtent {
+    /// The lock of the block `index`: reads of a block hold it shared, writes exclusively,
+    /// so a block is allocated once and partial sectors are updated atomically.
+    fn block_lock(&self, index: usize) -> &RwLock<()> {
+        &self.block_locks[index % BLOCK_LOCKS]
     }
 
-    fn save_cached_bitmap(&self) -> Result<()> {
-        let cached_block_index = *self.cached_block_index.borrow();
-        let mut cached_bitmap_dirty = self.cached_bitmap_dirty.borrow_mut();
-        if cached_block_index == INVALID_CACHE_INDEX || !(*cached_bitmap_dirty) {
-            return Ok(());
-        }
+    // runs @f on the bitmap of block @index, loaded into the cache first; None if the block is not allocated
+    fn with_bitmap<R, F>(&self, index: usize, f: F) -> Result<Option<R>>
+    where
+        F: FnOnce(&mut CachedBitmap) -> R,
+    {
+        let mut cache = self.bitmap_cache.lock().unwrap();
+
+        match cache.entries.iter().position(|entry| entry.block_index == index) {
+            Some(0) => {}
+            Some(pos) => {
+                let entry = cache.entries.remove(pos).unwrap();
+                cache.entries.push_front(entry);
+            }
+            None => {
+                let block_id = self.block_id(index)?;
+                if block_id == bat::DD_BLOCK_UNUSED {
+                    return Ok(None);
+                }
 
-        let cached_block_id = self.bat.borrow().block_id(cached_block_index)?;
-        if cached_block_id == bat::DD_BLOCK_UNUSED {
-            return Err(VhdError::UnexpectedBlockId(cached_block_index, cached_block_id));
+                // the least recently used bitmap makes room, its buffer is reused
+                let mut bitmap = if cache.entries.len() >= cache.capacity {
+                    let mut entry = cache.entries.pop_back().unwrap();
+                    if let Err(e) = self.save_bitmap(&mut entry) {
+                        cache.entries.push_back(entry);
+                        return Err(e);
+                    }
+                    entry.bitmap
+                } else {
+                    vec![0_u8; self.bitmap_size as usize]
+                };
+
+                let bitmap_pos = block_id as u64 * sizes::SECTOR_U64;
+                self.file.read_exact_at(bitmap_pos, &mut bitmap)?;
+                cache.entries.push_front(CachedBitmap { block_index: index, bitmap, dirty: false });
+            }
         }
 
-        let bitmap_pos = cached_block_index as u64 * sizes::SECTOR_U64;
-        self.file
-            .write_all_at(bitmap_pos, self.cached_bitmap.borrow_mut().as_mut_slice())?;
-        *cached_bitmap_dirty = false;
-
-        Ok(())
+        Ok(Some(f(&mut cache.entries[0])))
     }
 
-    fn check_sector_mask(&self, index: usize, sector_in_block: u32) -> Result<bool> {
-        if *self.cached_block_index.borrow() != index {
-            let res = self.populate_block_bitmap(index)?;
-            if !res {
-                return Ok(false);
-            }
+    fn save_bitmap(&self, entry: &mut CachedBitmap) -> Result<()> {
+        if !entry.dirty {
+            return Ok(());
         }
 
-        debug_assert_eq!(*self.cached_block_index.borrow(), index);
+        let block_id = self.block_id(entry.block_index)?;
+        if block_id == bat::DD_BLOCK_UNUSED {
+            return Err(VhdError::UnexpectedBlockId(entry.block_index, block_id));
+        }
 
-        let sector_in_block = sector_in_block as usize;
-        let sector_mask = calc_sector_mask(sector_in_block);
-        let is_bit_set = self.cached_bitmap.borrow()[sector_in_block / 8] & sector_mask != 0;
+        let bitmap_pos = block_id as u64 * sizes::SECTOR_U64;
+        self.file.write_all_at(bitmap_pos, &entry.bitmap)?;
+        entry.dirty = false;
 
-        Ok(is_bit_set)
+        Ok(())
     }
 
-    fn read_sectors(&self, to_read: u32, block_index: usize, sector_in_block: u32) -> Result<(bool, usize)> {
-        let to_read_in_sectors = to_read / sizes::SECTOR;
-        // remember first sector bit (valid data\parent or not)
-        let first_sector_bit = self.check_sector_mask(block_index, sector_in_block)?;
-
-        // now look for subsequent sectors bits and stop if sector bit is different (or no more sectors)
-        let mut sectors_count = 1_u32;
-        while sectors_count < to_read_in_sectors {
-            let sector_bit = self.check_sector_mask(block_index, sector_in_block + sectors_count)?;
-            if sector_bit != first_sector_bit {
-                break;
-            }
-
-            sectors_count += 1;
-        }
+    // whether sector @sector_in_block is present, and the number of sectors from it up to
+    // @max_sectors in the same state
+    fn block_sector_run(&self, block_index: usize, sector_in_block: u32, max_sectors: u32) -> Result<(bool, u32)> {
+        let run = self.with_bitmap(block_index, |entry| {
+            sector_run(&entry.bitmap, sector_in_block as usize, max_sectors as usize)
+        })?;
+
+        Ok(match run {
+            Some((present, count)) => (present, count as u32),
+            None => (false, max_sectors),
+        })
+    }
 
-        Ok((first_sector_bit, (sectors_count * sizes::SECTOR) as usize))
+    fn block_id(&self, block_index: usize) -> Result<u32> {
+        self.bat.read().unwrap().block_id(block_index)
     }
 
     fn calc_sector_pos(&self, block_index: usize, sector_in_block: u32) -> Result<u64> {
-        let block_id = self.bat.borrow().block_id(block_index)?;
+        let block_id = self.block_id(block_index)?;
 
         if block_id == bat::DD_BLOCK_UNUSED {
             return Err(VhdError::UnexpectedBlockId(block_index, block_id));
         }
 
-        Ok(((block_id + sector_in_block) as u64) * sizes::SECTOR_U64 + self.cached_bitmap.borrow().len() as u64)
+        Ok(((block_id + sector_in_block) as u64) * sizes::SECTOR_U64 + self.bitmap_size as u64)
     }
 
     fn calc_bitmap_pos(&self, block_index: usize) -> Result<u64> {
-        let block_id = self.bat.borrow().block_id(block_index)?;
+        let block_id = self.block_id(block_index)?;
 
         if block_id == bat::DD_BLOCK_UNUSED {
             return Err(VhdError::UnexpectedBlockId(block_index, block_id));
@@ -320,7 +583,20 @@ impl SparseExtent {
 
     fn read_parent_or_zero(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
         match &self.parent {
-            Some(p) => p.read_at(offset, buffer),
+            Some(p) => {
+                // a grown child reads zeros beyond the end of its parent
+                let capacity = p.capacity()?;
+                let in_parent = if offset < capacity {
+                    std::cmp::min(capacity - offset, buffer.len() as u64) as usize
+                } else {
+                    0
+                };
+
+                p.read_exact_at(offset, &mut buffer[..in_parent])?;
+                buffer[in_parent..].fill(0);
+
+                Ok(buffer.len())
+            }
             None => {
                 for b in buffer.iter_mut() {
                     *b = 0;
@@ -331,19 +607,21 @@ impl SparseExtent {
         }
     }
 
+    // the caller holds the block lock
     fn read_block_data(&self, block_index: usize, offset_in_block: u32, buffer: &mut [u8]) -> Result<(bool, usize)> {
         let sector_in_block = offset_in_block / sizes::SECTOR;
         let offset_in_sector = offset_in_block % sizes::SECTOR;
         let to_read = buffer.len() as u32;
 
         let (data_exist, data_buffer) = if offset_in_sector != 0 || to_read < sizes::SECTOR {
-            // read at non sector boundary
-            let data_exist = self.check_sector_mask(block_index, sector_in_block)?;
-            (data_exist, buffer)
+            // read at non sector boundary, up to the end of the sector
+            let (data_exist, _) = self.block_sector_run(block_index, sector_in_block, 1)?;
+            let valid_len = std::cmp::min(to_read, sizes::SECTOR - offset_in_sector) as usize;
+            (data_exist, &mut buffer[..valid_len])
         } else {
             // read as many full sectors as possible
-            let (data_exist, valid_len) = self.read_sectors(to_read, block_index, sector_in_block)?;
-            (data_exist, &mut buffer[..valid_len])
+            let (data_exist, sectors_count) = self.block_sector_run(block_index, sector_in_block, to_read / sizes::SECTOR)?;
+            (data_exist, &mut buffer[..(sectors_count * sizes::SECTOR) as usize])
         };
 
         if data_exist {
@@ -357,30 +635,11 @@ impl SparseExtent {
         }
     }
 
-    fn read_block(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
-        let block_size = self.header.block_size() as u64;
-        let block_index = (offset / block_size) as usize;
-        let offset_in_block = (offset % block_size) as u32;
-        let to_read = std::cmp::min(buffer.len() as u32, self.header.block_size() - offset_in_block);
-        let block_buffer = &mut buffer[..to_read as usize];
-
-        let block_in_current_file = self.populate_block_bitmap(block_index)?;
-        if block_in_current_file {
-            self.read_block_data(block_index, offset_in_block, block_buffer).map(|r| r.1)
-        } else {
-            self.read_parent_or_zero(offset, block_buffer)
-        }
-    }
-
+    // the caller holds the block lock, and the block is allocated
     fn write_block(&self, offset: u64, data: &[u8]) -> Result<usize> {
         let block_size = self.header.block_size() as u64;
         let block_index = (offset / block_size) as usize;
 
-        let block_in_current_file = self.populate_block_bitmap(block_index)?;
-        if !block_in_current_file {
-            self.allocate_block(block_index)?;
-        }
-
         let offset_in_block = (offset % block_size) as u32;
         let sector_in_block = offset_in_block / sizes::SECTOR;
         let offset_in_sector = offset_in_block % sizes::SECTOR;
@@ -392,7 +651,7 @@ impl SparseExtent {
 
             // read the sector
             let mut sector_buffer = unsafe { util::alloc_buffer(sizes::SECTOR as usize) };
-            let sector_offset_in_block = math::round_down(offset_in_sector, sizes::SECTOR);
+            let sector_offset_in_block = math::round_down(offset_in_block, sizes::SECTOR);
             let (data_exist, _) = self.read_block_data(block_index, sector_offset_in_block, &mut sector_buffer)?;
 
             // update it
@@ -406,7 +665,7 @@ impl SparseExtent {
 
             if !data_exist {
                 // the sector was read from the parent
-                self.mark_cached_bitmap_dirty(sector_in_block as usize);
+                self.mark_sectors(block_index, sector_in_block, 1)?;
             }
         } else {
             // write as much whole sectors as possible
@@ -415,47 +674,34 @@ impl SparseExtent {
             self.file.write_all_at(pos, &data[..to_write])?;
 
             // update bitmap bits for written sectors
-            let mut i = 0;
-            let mut sector_in_block = sector_in_block as usize;
-            while i < to_write {
-                self.mark_cached_bitmap_dirty(sector_in_block);
-                sector_in_block += 1;
-                i += sizes::SECTOR as usize;
-            }
+            self.mark_sectors(block_index, sector_in_block, to_write as u32 / sizes::SECTOR)?;
         }
 
         Ok(to_write)
     }
-    
+
+    // the caller holds the block lock
     fn allocate_block(&self, block_index: usize) -> Result<()> {
-        let block_id = self.bat.borrow().block_id(block_index)?;
+        let mut next_block_pos = self.next_block_pos.lock().unwrap();
+
+        let block_id = self.block_id(block_index)?;
         if block_id != bat::DD_BLOCK_UNUSED {
             return Err(VhdError::UnexpectedBlockId(block_index, block_id));
         }
 
-        self.save_cached_bitmap()?;
-
-        let mut bitmap = self.cached_bitmap.borrow_mut();
-        // initial block bitmap should be zeroed
-        unsafe { std::ptr::write_bytes(bitmap.as_mut_ptr(), 0, bitmap.len()) }
-
-        let mut next_block_pos = self.next_block_pos.borrow_mut();
         let block_pos = *next_block_pos;
-        *next_block_pos += bitmap.len() as u64 + self.header.block_size() as u64;
-        *self.cached_block_index.borrow_mut() = block_index;
+        let block_end = block_pos + self.bitmap_size as u64 + self.header.block_size() as u64;
 
-        if block_pos < self.file.size()? {
-            // The footer is here! Have to override it with zeroes.
-            let zeroes = StructBuffer::<VhdFooter>::zeroed();
-            self.file.write_all_at(block_pos, zeroes.buffer())?;
-        }
+        // initial block bitmap should be zeroed, this also overrides the footer
+        self.file.write_all_at(block_pos, &vec![0_u8; self.bitmap_size as usize])?;
 
         // write one byte at the end of the block to expand the file (OS will fill it with zeroes)
-        self.file.write_all_at(*next_block_pos - 1, unsafe { 0_u8.as_byte_slice() })?;
+        self.file.write_all_at(block_end - 1, unsafe { 0_u8.as_byte_slice() })?;
+        *next_block_pos = block_end;
 
         // update BAT in memory...
         let block_pos_in_sectors = (block_pos / sizes::SECTOR_U64) as u32;
-        self.bat.borrow_mut().set_block_id(block_index, block_pos_in_sectors)?;
+        self.bat.write().unwrap().set_block_id(block_index, block_pos_in_sectors)?;
 
         // ...and in the file
         let swapped_id = block_pos_in_sectors.swap_bytes();
@@ -469,9 +715,551 @@ impl SparseExtent {
         Ok(())
     }
     
-    fn mark_cached_bitmap_dirty(&self, sector_in_block: usize) {
-        let sector_mask = calc_sector_mask(sector_in_block);
-        self.cached_bitmap.borrow_mut()[sector_in_block / 8] |= sector_mask;
-        *self.cached_bitmap_dirty.borrow_mut() = true;
+    /// The locks of the blocks holding `len` bytes from `offset`, in lock order.
+    fn block_locks_of(&self, offset: u64, len: usize) -> Vec<&RwLock<()>> {
+        let block_size = self.header.block_size() as u64;
+        let first = (offset / block_size) as usize;
+        let last = ((offset + len as u64 - 1) / block_size) as usize;
+
+        let mut indexes: Vec<usize> = (first..=std::cmp::min(last, first + BLOCK_LOCKS - 1))
+            .map(|index| index % BLOCK_LOCKS)
+            .collect();
+        indexes.sort_unstable();
+
+        indexes.into_iter().map(|index| &self.block_locks[index]).collect()
+    }
+
+    // adds @len bytes at @file_offset to @run if they follow it in the file, possibly after the
+    // bitmap of the next block, otherwise returns the run to complete and starts a new one
+    fn extend_run(&self, run: &mut Option<FileRun>, file_offset: u64, buffer_offset: usize, len: usize) -> Option<FileRun> {
+        let bitmap_size = self.bitmap_size as u64;
+        let done = match run {
+            Some(current) if current.end() == file_offset || current.end() + bitmap_size == file_offset => None,
+            _ => run.replace(FileRun::new(file_offset)),
+        };
+
+        let current = run.as_mut().unwrap();
+        current.len = (file_offset - current.file_offset) as usize;
+        current.pieces.push((buffer_offset, current.len, len));
+        current.len += len;
+
+        done
+    }
+
+    // reads @buffers from @offset, the caller holds the block locks
+    fn read_runs(&self, offset: u64, buffers: &mut ReadBuffers) -> Result<()> {
+        let block_size = self.header.block_size() as u64;
+        let end = offset + buffers.len() as u64;
+        let mut run = None;
+        // (offset, buffer offset, length) of the data read from the parent
+        let mut parent_run: Option<(u64, usize, usize)> = None;
+
+        let mut pos = offset;
+        while pos < end {
+            let block_index = (pos / block_size) as usize;
+            let block_start = block_index as u64 * block_size;
+            let block_end = std::cmp::min(end, block_start + block_size);
+            let buffer_offset = (pos - offset) as usize;
+
+            let block_id = self.block_id(block_index)?;
+            let (present, run_end) = if block_id == bat::DD_BLOCK_UNUSED {
+                (false, block_end)
+            } else {
+                let sector = ((pos - block_start) / sizes::SECTOR_U64) as u32;
+                let sectors = math::ceil(block_end - block_start, sizes::SECTOR_U64) as u32 - sector;
+                let (present, count) = self.block_sector_run(block_index, sector, sectors)?;
+                (present, std::cmp::min(block_end, block_start + (sector + count) as u64 * sizes::SECTOR_U64))
+            };
+            let len = (run_end - pos) as usize;
+
+            if present {
+                let file_offset = block_id as u64 * sizes::SECTOR_U64 + self.bitmap_size as u64 + (pos - block_start);
+                if let Some(done) = self.extend_run(&mut run, file_offset, buffer_offset, len) {
+                    self.read_run(&done, buffers)?;
+                }
+            } else {
+                parent_run = match parent_run {
+                    Some((start, start_offset, start_len)) if start + start_len as u64 == pos => {
+                        Some((start, start_offset, start_len + len))
+                    }
+                    previous => {
+                        if let Some((start, start_offset, start_len)) = previous {
+                            self.read_parent_run(start, buffers, start_offset, start_len)?;
+                        }
+                        Some((pos, buffer_offset, len))
+                    }
+                };
+            }
+
+            pos = run_end;
+        }
+
+        if let Some(done) = run {
+            self.read_run(&done, buffers)?;
+        }
+        if let Some((start, start_offset, start_len)) = parent_run {
+            self.read_parent_run(start, buffers, start_offset, start_len)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_run(&self, run: &FileRun, buffers: &mut ReadBuffers) -> Result<()> {
+        if let [(buffer_offset, 0, len)] = run.pieces[..] {
+            // the data goes straight to the caller buffer unless it is split between two of them
+            if let [buffer] = &mut buffers.slices(buffer_offset, len)[..] {
+                return self.file.read_exact_at(run.file_offset, buffer);
+            }
+        }
+
+        let mut data = vec![0_u8; run.len];
+        self.file.read_exact_at(run.file_offset, &mut data)?;
+        for &(buffer_offset, run_offset, len) in &run.pieces {
+            buffers.copy_from(buffer_offset, &data[run_offset..run_offset + len]);
+        }
+
+        Ok(())
+    }
+
+    // reads @len bytes from @offset of the parent into @buffers from @buffer_offset
+    fn read_parent_run(&self, mut offset: u64, buffers: &mut ReadBuffers, buffer_offset: usize, len: usize) -> Result<()> {
+        for buffer in buffers.slices(buffer_offset, len) {
+            self.read_parent_or_zero(offset, buffer)?;
+            offset += buffer.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    // writes @data from @offset, the caller holds the block locks
+    fn write_runs(&self, offset: u64, data: &WriteBuffers) -> Result<()> {
+        let block_size = self.header.block_size() as u64;
+        let end = offset + data.len() as u64;
+        let mut run: Option<FileRun> = None;
+
+        let mut pos = offset;
+        while pos < end {
+            let block_index = (pos / block_size) as usize;
+            let block_start = block_index as u64 * block_size;
+            let data_offset = (pos - offset) as usize;
+
+            let mut block_id = self.block_id(block_index)?;
+            if block_id == bat::DD_BLOCK_UNUSED {
+                self.allocate_block(block_index)?;
+                block_id = self.block_id(block_index)?;
+            }
+
+            if !pos.is_multiple_of(sizes::SECTOR_U64) || end - pos < sizes::SECTOR_U64 {
+                // a partial sector is read, updated and written back on its own
+                if let Some(done) = run.take() {
+                    self.write_run(&done, data)?;
+                }
+                let len = std::cmp::min(end - pos, sizes::SECTOR_U64 - pos % sizes::SECTOR_U64) as usize;
+                let mut sector = [0_u8; sizes::SECTOR as usize];
+                data.copy_to(data_offset, &mut sector[..len]);
+                pos += self.write_block(pos, &sector[..len])? as u64;
+                continue;
+            }
+
+            let block_end = std::cmp::min(block_start + block_size, math::round_down(end, sizes::SECTOR_U64));
+            let len = (block_end - pos) as usize;
+            let sector = ((pos - block_start) / sizes::SECTOR_U64) as u32;
+            let count = len as u32 / sizes::SECTOR;
+
+            let file_offset = block_id as u64 * sizes::SECTOR_U64 + self.bitmap_size as u64 + (pos - block_start);
+            if let Some(done) = self.extend_run(&mut run, file_offset, data_offset, len) {
+                self.write_run(&done, data)?;
+            }
+
+            let current = run.as_mut().unwrap();
+            if current.file_offset != file_offset {
+                // the run spans the bitmap of this block, which is written with the new sectors
+                let mut bitmap = self.with_bitmap(block_index, |entry| entry.bitmap.clone())?.unwrap();
+                for sector in sector as usize..(sector + count) as usize {
+                    bitmap[sector / 8] |= calc_sector_mask(sector);
+                }
+                current.bitmaps.push(((file_offset - current.file_offset) as usize - bitmap.len(), bitmap));
+            }
+            current.sectors.push((block_index, sector, count));
+
+            pos = block_end;
+        }
+
+        if let Some(done) = run {
+            self.write_run(&done, data)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_run(&self, run: &FileRun, data: &WriteBuffers) -> Result<()> {
+        // the data goes straight from the caller buffer unless it is split between two of them
+        let direct = match run.pieces[..] {
+            [(data_offset, 0, len)] => match data.slices(data_offset, len)[..] {
+                [slice] => Some(slice),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        if let Some(slice) = direct {
+            self.file.write_all_at(run.file_offset, slice)?;
+        } else {
+            let mut buffer = vec![0_u8; run.len];
+            for &(data_offset, run_offset, len) in &run.pieces {
+                data.copy_to(data_offset, &mut buffer[run_offset..run_offset + len]);
+            }
+            for (run_offset, bitmap) in &run.bitmaps {
+                buffer[*run_offset..*run_offset + bitmap.len()].copy_from_slice(bitmap);
+            }
+            self.file.write_all_at(run.file_offset, &buffer)?;
+        }
+
+        // the sectors are marked present once their data is in the file
+        for &(block_index, sector, count) in &run.sectors {
+            self.mark_sectors(block_index, sector, count)?;
+        }
+
+        Ok(())
+    }
+
+    // writes @data from @offset like write_runs, the parts of blocks that are all zeros are
+    // written with zero_block; the caller holds the block locks
+    fn write_runs_detecting_zeroes(&self, offset: u64, data: &WriteBuffers) -> Result<()> {
+        let block_size = self.header.block_size() as u64;
+        let end = offset + data.len() as u64;
+        // the start of the data not written yet
+        let mut pending = None;
+
+        let mut pos = offset;
+        while pos < end {
+            let block_end = std::cmp::min(end, (pos / block_size + 1) * block_size);
+            let chunk = data.slices((pos - offset) as usize, (block_end - pos) as usize);
+
+            if chunk.iter().all(|slice| slice.iter().all(|b| *b == 0)) {
+                if let Some(start) = pending.take() {
+                    self.write_pending(offset, data, start, pos)?;
+                }
+                self.zero_block(pos, (block_end - pos) as usize)?;
+            } else if pending.is_none() {
+                pending = Some(pos);
+            }
+
+            pos = block_end;
+        }
+
+        if let Some(start) = pending {
+            self.write_pending(offset, data, start, end)?;
+        }
+
+        Ok(())
+    }
+
+    // writes the part of @data from @start to @end, @data being written from @offset
+    fn write_pending(&self, offset: u64, data: &WriteBuffers, start: u64, end: u64) -> Result<()> {
+        let slices = data.slices((start - offset) as usize, (end - start) as usize);
+        self.write_runs(start, &WriteBuffers(&slices))
+    }
+
+    // zeroes @len bytes from @offset within a block, which is only allocated when the range
+    // would not read as zeros otherwise; the caller holds the block lock
+    fn zero_block(&self, offset: u64, len: usize) -> Result<()> {
+        let block_index = (offset / self.header.block_size() as u64) as usize;
+
+        if self.block_id(block_index)? == bat::DD_BLOCK_UNUSED {
+            let zeroed = match &self.parent {
+                Some(_) => {
+                    let mut buffer = vec![0_u8; len];
+                    self.read_parent_or_zero(offset, &mut buffer)?;
+                    buffer.iter().all(|b| *b == 0)
+                }
+                None => true,
+            };
+
+            if zeroed {
+                return Ok(());
+            }
+        }
+
+        self.write_runs(offset, &WriteBuffers(&[&vec![0_u8; len]]))
+    }
+
+    // discards the bytes from @start to @end of block @block_index of a dynamic image,
+    // the caller holds the block lock
+    fn discard_block(&self, block_index: usize, start: u64, end: u64) -> Result<()> {
+        let block_id = self.block_id(block_index)?;
+        if block_id == bat::DD_BLOCK_UNUSED {
+            return Ok(());
+        }
+
+        let data_pos = block_id as u64 * sizes::SECTOR_U64 + self.bitmap_size as u64;
+        let first_sector = math::ceil(start, sizes::SECTOR_U64);
+        let last_sector = end / sizes::SECTOR_U64;
+
+        // the bytes of partial sectors are zeroed, the sectors stay present
+        let mut partial = vec![(start, std::cmp::min(end, first_sector * sizes::SECTOR_U64))];
+        if last_sector >= first_sector {
+            partial.push((last_sector * sizes::SECTOR_U64, end));
+        }
+        for (from, to) in partial.into_iter().filter(|(from, to)| from < to) {
+            let (present, _) = self.block_sector_run(block_index, (from / sizes::SECTOR_U64) as u32, 1)?;
+            if present {
+                self.file.write_all_at(data_pos + from, &vec![0_u8; (to - from) as usize])?;
+            }
+        }
+
+        if first_sector >= last_sector {
+            return Ok(());
+        }
+
+        let empty = self.with_bitmap(block_index, |entry| {
+            for sector in first_sector as usize..last_sector as usize {
+                entry.bitmap[sector / 8] &= !calc_sector_mask(sector);
+            }
+            entry.dirty = true;
+            entry.bitmap.iter().all(|b| *b == 0)
+        })?;
+
+        if empty == Some(true) {
+            self.free_block(block_index, block_id)
+        } else {
+            let sectors = last_sector - first_sector;
+            self.file.punch_hole(data_pos + first_sector * sizes::SECTOR_U64, sectors * sizes::SECTOR_U64)
+        }
+    }
+
+    // makes block @block_index unallocated and releases its space in the file,
+    // the caller holds the block lock
+    fn free_block(&self, block_index: usize, block_id: u32) -> Result<()> {
+        let mut cache = self.bitmap_cache.lock().unwrap();
+        cache.entries.retain(|entry| entry.block_index != block_index);
+
+        self.bat.write().unwrap().set_block_id(block_index, bat::DD_BLOCK_UNUSED)?;
+        let raw_block_pos_in_sectors_pos = self.header.table_offset() + (block_index as u64 * 4);
+        self.file
+            .write_all_at(raw_block_pos_in_sectors_pos, unsafe { bat::DD_BLOCK_UNUSED.as_byte_slice() })?;
+        drop(cache);
+
+        let block_len = self.bitmap_size as u64 + self.header.block_size() as u64;
+        self.file.punch_hole(block_id as u64 * sizes::SECTOR_U64, block_len)
+    }
+
+    // sets the bitmap bits of @count sectors from @sector_in_block, the block is allocated
+    fn mark_sectors(&self, block_index: usize, sector_in_block: u32, count: u32) -> Result<()> {
+        self.with_bitmap(block_index, |entry| {
+            for sector in sector_in_block..sector_in_block + count {
+                let sector = sector as usize;
+                entry.bitmap[sector / 8] |= calc_sector_mask(sector);
+            }
+            entry.dirty = true;
+        })?
+        .ok_or(VhdError::UnexpectedBlockId(block_index, bat::DD_BLOCK_UNUSED))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OpenMode;
+    use crate::vhd::test_util::temp_path;
+
+    #[test]
+    fn sector_run_test() {
+        let mut bitmap = vec![0_u8; 512];
+        bitmap[8..24].fill(0xFF);
+        bitmap[24] = 0b1110_0000;
+
+        assert_eq!(sector_run(&bitmap, 0, 4096), (false, 64));
+        assert_eq!(sector_run(&bitmap, 3, 10), (false, 10));
+        assert_eq!(sector_run(&bitmap, 64, 4096 - 64), (true, 131));
+        assert_eq!(sector_run(&bitmap, 100, 20), (true, 20));
+        assert_eq!(sector_run(&bitmap, 195, 4096 - 195), (false, 4096 - 195));
+        assert_eq!(sector_run(&[0b1011_0000], 2, 6), (true, 2));
+    }
+
+    #[test]
+    fn bitmap_cache_test() {
+        let path = temp_path("rvhd_bitmap_cache.vhd");
+        let img = VhdImage::create_dynamic(path.as_str(), 8).unwrap();
+        img.set_bitmap_cache_size(2).unwrap();
+
+        // interleave three blocks through a cache of two
+        for round in 0..4_u64 {
+            for block in 0..3_u64 {
+                img.write_all_at((block << 21) + round * 512, &[block as u8 + 1; 512]).unwrap();
+            }
+        }
+
+        // the bitmaps still cached reach the file on flush
+        img.flush().unwrap();
+        let file = VhdFile::open(&path, OpenMode::ReadOnly).unwrap();
+        for block in 0..3 {
+            let (offset, bitmap) = img.sparse_block_bitmap(block).unwrap().unwrap();
+            let mut on_disk = vec![0_u8; bitmap.len()];
+            file.read_exact_at(offset, &mut on_disk).unwrap();
+            assert_eq!(on_disk, bitmap);
+            assert_eq!(bitmap[0], 0xF0);
+        }
+        drop(file);
+        drop(img);
+
+        let img = VhdImage::open(path.as_str()).unwrap();
+        let mut buffer = vec![0_u8; 2048 + 512];
+        for block in 0..3_u64 {
+            img.read_exact_at(block << 21, &mut buffer).unwrap();
+            assert!(buffer[..2048].iter().all(|b| *b == block as u8 + 1));
+            assert!(buffer[2048..].iter().all(|b| *b == 0));
+        }
+        drop(img);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn sparse_block_bitmap_test() {
+        let path = temp_path("rvhd_sparse_block_bitmap.vhd");
+        let img = VhdImage::create_dynamic(path.as_str(), 8).unwrap();
+        img.write_all_at(2 << 20, &[1_u8; 1024]).unwrap();
+
+        let (offset, bitmap) = img.sparse_block_bitmap(1).unwrap().unwrap();
+        assert_eq!(offset, img.sparse_bat().unwrap().read().unwrap().block_id(1).unwrap() as u64 * sizes::SECTOR_U64);
+        assert_eq!(bitmap[0], 0xC0);
+        assert!(img.sparse_block_bitmap(0).unwrap().is_none());
+        assert!(matches!(img.sparse_block_bitmap(100), Err(VhdError::InvalidBlockIndex(100))));
+        drop(img);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn discard_dynamic_test() {
+        let path = temp_path("rvhd_discard_dynamic.vhd");
+        let img = VhdImage::create_dynamic(path.as_str(), 8).unwrap();
+        let data: Vec<u8> = (0..(4 << 20)).map(|i| (i % 251) as u8 + 1).collect();
+        img.write_all_at(0, &data).unwrap();
+
+        // the first block entirely, and part of the second one ending with a partial sector
+        let end = (3 << 20) + 1000;
+        img.discard(0, end).unwrap();
+        img.discard((3 << 20) + 4196, 200).unwrap();
+        img.discard(6 << 20, 1 << 20).unwrap();
+        let mut expected = data.clone();
+        expected[..end as usize].fill(0);
+        expected[(3 << 20) + 4196..(3 << 20) + 4396].fill(0);
+
+        let bat = img.sparse_bat().unwrap().read().unwrap();
+        assert_eq!(bat.block_id(0).unwrap(), bat::DD_BLOCK_UNUSED);
+        assert_ne!(bat.block_id(1).unwrap(), bat::DD_BLOCK_UNUSED);
+        drop(bat);
+
+        let mut buffer = vec![0_u8; 4 << 20];
+        img.read_exact_at(0, &mut buffer).unwrap();
+        assert!(buffer == expected);
+
+        // the whole sectors are no longer present
+        let (_, bitmap) = img.sparse_block_bitmap(1).unwrap().unwrap();
+        assert_eq!(sector_run(&bitmap, 0, 4096), (false, 2049));
+        assert_eq!(sector_run(&bitmap, 2049, 2047), (true, 2047));
+        drop(img);
+
+        let img = VhdImage::open(path.as_str()).unwrap();
+        img.read_exact_at(0, &mut buffer).unwrap();
+        assert!(buffer == expected);
+        drop(img);
+        assert!(VhdImage::check(path.as_str()).unwrap().is_ok());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn discard_diff_test() {
+        let base = temp_path("rvhd_discard_diff_base.vhd");
+        let child = temp_path("rvhd_discard_diff_child.vhd");
+
+        let img = VhdImage::create_dynamic(base.as_str(), 4).unwrap();
+        img.write_all_at(0, &[1_u8; 8192]).unwrap();
+        drop(img);
+
+        let img = VhdImage::create_diff(child.as_str(), base.as_str()).unwrap();
+        img.write_all_at(4096, &[2_u8; 4096]).unwrap();
+        img.discard(1000, 6000).unwrap();
+
+        let mut buffer = vec![0_u8; 8192];
+        img.read_exact_at(0, &mut buffer).unwrap();
+        assert!(buffer[..1000].iter().all(|b| *b == 1));
+        assert!(buffer[1000..7000].iter().all(|b| *b == 0));
+        assert!(buffer[7000..].iter().all(|b| *b == 2));
+        drop(img);
+
+        // the parent is untouched
+        let img = VhdImage::open(base.as_str()).unwrap();
+        img.read_exact_at(0, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|b| *b == 1));
+        drop(img);
+
+        std::fs::remove_file(child).unwrap();
+        std::fs::remove_file(base).unwrap();
+    }
+
+    #[test]
+    fn write_zeroes_dynamic_test() {
+        let path = temp_path("rvhd_write_zeroes_dynamic.vhd");
+        let img = VhdImage::create_dynamic(path.as_str(), 8).unwrap();
+        img.write_all_at(2 << 20, &[3_u8; 8192]).unwrap();
+
+        // only the allocated block is written
+        img.write_zeroes(1 << 20, 2 << 20).unwrap();
+        let bat = img.sparse_bat().unwrap().read().unwrap();
+        assert_eq!(bat.block_id(0).unwrap(), bat::DD_BLOCK_UNUSED);
+        assert_ne!(bat.block_id(1).unwrap(), bat::DD_BLOCK_UNUSED);
+        drop(bat);
+
+        let mut buffer = vec![1_u8; 8192];
+        img.read_exact_at(2 << 20, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|b| *b == 0));
+
+        // a write with a zeroed block and data in the next one allocates the next one only
+        img.set_detect_zeroes(true);
+        let mut data = vec![0_u8; 4 << 20];
+        data[(4 << 20) - 512..].fill(4);
+        img.write_all_at(4 << 20, &data).unwrap();
+        let bat = img.sparse_bat().unwrap().read().unwrap();
+        assert_eq!(bat.block_id(2).unwrap(), bat::DD_BLOCK_UNUSED);
+        assert_ne!(bat.block_id(3).unwrap(), bat::DD_BLOCK_UNUSED);
+        drop(bat);
+
+        let mut buffer = vec![1_u8; 4 << 20];
+        img.read_exact_at(4 << 20, &mut buffer).unwrap();
+        assert!(buffer == data);
+        drop(img);
+        assert!(VhdImage::check(path.as_str()).unwrap().is_ok());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn write_zeroes_diff_test() {
+        let base = temp_path("rvhd_write_zeroes_diff_base.vhd");
+        let child = temp_path("rvhd_write_zeroes_diff_child.vhd");
+
+        let img = VhdImage::create_dynamic(base.as_str(), 4).unwrap();
+        img.write_all_at(2 << 20, &[1_u8; 4096]).unwrap();
+        drop(img);
+
+        // the parent reads zeros in the first block, so only the second one is allocated
+        let img = VhdImage::create_diff(child.as_str(), base.as_str()).unwrap();
+        img.write_zeroes(0, 4 << 20).unwrap();
+        let bat = img.sparse_bat().unwrap().read().unwrap();
+        assert_eq!(bat.block_id(0).unwrap(), bat::DD_BLOCK_UNUSED);
+        assert_ne!(bat.block_id(1).unwrap(), bat::DD_BLOCK_UNUSED);
+        drop(bat);
+
+        let mut buffer = vec![1_u8; 4 << 20];
+        img.read_exact_at(0, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|b| *b == 0));
+        drop(img);
+
+        std::fs::remove_file(child).unwrap();
+        std::fs::remove_file(base).unwrap();
     }
 }
\ No newline at end of file
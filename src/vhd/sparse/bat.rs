@@ -2,7 +2,6 @@ use crate::traits::{ReadAt, WriteAt};
 use crate::{Result, VhdError, math, sizes};
 use crate::util::AsByteSlice;
 
-#[repr(C, packed)]
 pub struct VhdBat {
     // sector number per block
     //spb: u32,
@@ -57,7 +56,7 @@ impl VhdBat {
         let data = unsafe { tmp.as_byte_slice() };
         buffer[..data.len()].copy_from_slice(data);
 
-        stream.write_all_at(offset, &buffer);
+        stream.write_all_at(offset, &buffer)?;
 
         Ok(buffer.len())
     }
@@ -65,7 +64,7 @@ impl VhdBat {
     pub fn block_id(&self, index: usize) -> Result<u32> {
         match self.bat.get(index) {
             Some(id) => Ok(*id),
-            None => return Err(VhdError::InvalidBlockIndex(index)),
+            None => Err(VhdError::InvalidBlockIndex(index)),
         }
     }
 
@@ -79,8 +78,4 @@ impl VhdBat {
 
         Ok(())
     }
-
-    pub fn bat_data(&self) -> &Vec<u32> {
-        &self.bat
-    }
 }
\ No newline at end of file
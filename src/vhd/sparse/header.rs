@@ -1,6 +1,6 @@
-use crate::{Uuid, UuidEx, sizes, geometry, StructBuffer, ReadAt, WriteAt, Result, AsByteSliceMut, VhdError, math};
-use crate::vhd::{VhdType, vhd_time, VhdImage, DEFAULT_TABLE_OFFSET};
-use std::path::{Path, MAIN_SEPARATOR};
+use crate::{Uuid, UuidEx, sizes, StructBuffer, ReadAt, WriteAt, Result, AsByteSliceMut, VhdError, math};
+use crate::vhd::VhdImage;
+use std::path::{Component, Path};
 
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
@@ -22,7 +22,89 @@ pub const PLAT_CODE_NONE: u32 = 0x0000_0000;
 pub const PLAT_CODE_W2RU: u32 = 0x5732_7275; 
 /// Windows absolute path (UTF-16) litter endian (W2ku)
 pub const PLAT_CODE_W2KU: u32 = 0x5732_6B75;
+/// Mac OS X URL (UTF-8) as written by Xen (MacX)
+pub const PLAT_CODE_MACX: u32 = 0x4D61_6358;
+
+const MACX_SCHEME: &str = "file://";
+
+/// The parent location stored by a parent locator, decoded according to its platform code.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum VhdLocator {
+    /// Absolute Windows path (W2ku).
+    W2ku(String),
+    /// Windows path relative to the image directory (W2ru).
+    W2ru(String),
+    /// Path relative to the image directory, stored as a `file://` URL (MacX).
+    MacX(String),
+    /// Any other platform code with its raw data.
+    Other(u32, Vec<u8>),
+}
+
+fn decode_utf16le(data: &[u8]) -> String {
+    let utf16: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect();
+
+    String::from_utf16_lossy(&utf16)
+}
+
+fn code_str(code: u32) -> String {
+    String::from_utf8_lossy(&code.to_be_bytes()).into_owned()
+}
+
+impl VhdLocator {
+    /// Decodes the data of a locator with platform code `code`, trailing NULs are ignored.
+    pub fn decode(code: u32, data: &[u8]) -> Self {
+        match code {
+            PLAT_CODE_W2KU => VhdLocator::W2ku(decode_utf16le(data)),
+            PLAT_CODE_W2RU => VhdLocator::W2ru(decode_utf16le(data)),
+            PLAT_CODE_MACX => {
+                let url = String::from_utf8_lossy(data);
+                let url = url.trim_end_matches('\0');
+                VhdLocator::MacX(url.strip_prefix(MACX_SCHEME).unwrap_or(url).to_string())
+            }
+            _ => VhdLocator::Other(code, data.to_vec()),
+        }
+    }
+
+    /// Returns the locator data as stored in the image.
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            VhdLocator::W2ku(path) | VhdLocator::W2ru(path) => path.encode_utf16().flat_map(|c| c.to_le_bytes()).collect(),
+            VhdLocator::MacX(path) => format!("{}{}", MACX_SCHEME, path).into_bytes(),
+            VhdLocator::Other(_, data) => data.clone(),
+        }
+    }
+
+    pub fn code(&self) -> u32 {
+        match self {
+            VhdLocator::W2ku(_) => PLAT_CODE_W2KU,
+            VhdLocator::W2ru(_) => PLAT_CODE_W2RU,
+            VhdLocator::MacX(_) => PLAT_CODE_MACX,
+            VhdLocator::Other(code, _) => *code,
+        }
+    }
+
+    /// Returns the parent path, `None` for unknown platform codes.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            VhdLocator::W2ku(path) | VhdLocator::W2ru(path) | VhdLocator::MacX(path) => Some(path),
+            VhdLocator::Other(..) => None,
+        }
+    }
+}
 
+impl std::fmt::Display for VhdLocator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VhdLocator::MacX(path) => write!(f, "MacX: {}{}", MACX_SCHEME, path),
+            VhdLocator::Other(code, data) => write!(f, "{}: {} bytes", code_str(*code), data.len()),
+            _ => write!(f, "{}: {}", code_str(self.code()), self.path().unwrap()),
+        }
+    }
+}
 
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
@@ -56,14 +138,19 @@ pub struct VhdHeader {
 }
 
 /// (Unused) 0xffs
-const DD_OFFSET: u64 = 0xFFFF_FFFF_FFFF_FFFF;
+pub(crate) const DD_OFFSET: u64 = 0xFFFF_FFFF_FFFF_FFFF;
 /// VHD cookie string
 const DD_COOKIE: u64 = 0x6573_7261_7073_7863; /* cxsparse  big endian*/
 /// Version field in VhdHeader
-const DD_VERSION: u32 = 0x0001_0000;
+pub(crate) const DD_VERSION: u32 = 0x0001_0000;
 /// Default blocksize is 2 meg
 pub const DD_BLOCKSIZE_DEFAULT: u32 = 0x0020_0000; 
 
+/// Block size must be a power of two number of sectors
+pub fn is_valid_block_size(block_size: u32) -> bool {
+    block_size.is_power_of_two() && block_size >= sizes::SECTOR
+}
+
 impl VhdHeader {
     fn swap_bytes(&mut self) {
         self.data_offset = self.data_offset.swap_bytes();
@@ -83,7 +170,7 @@ impl VhdHeader {
         }
     }
 
-    pub fn new(capacity: u64, table_offset: u64, block_size: u32, file_path: &String, parent: &Option<VhdImage>) -> (Self, Vec<u16>) {
+    pub fn new(capacity: u64, table_offset: u64, block_size: u32, file_path: &str, parent: &Option<VhdImage>) -> Result<(Self, Vec<VhdLocator>)> {
         let mut header = StructBuffer::<VhdHeader>::zeroed();        
         header.cookie = DD_COOKIE;
         header.data_offset = DD_OFFSET;
@@ -92,7 +179,7 @@ impl VhdHeader {
         header.max_bat_size = math::ceil(capacity, block_size as u64) as u32;
         header.block_size = block_size;
 
-        let relative_utf16_path = if parent.is_none() {
+        let locators = if parent.is_none() {
             header.prt_uuid = Uuid::nil();
             header.prt_ts = 0;
             header.prt_name = unsafe { std::mem::zeroed() };
@@ -101,58 +188,49 @@ impl VhdHeader {
             Vec::new()
         } else {
             let parent_footer = parent.as_ref().map(|img| img.footer()).unwrap();
-            header.prt_uuid = parent_footer.uuid().clone();
-            header.prt_ts = parent_footer.timestamps();
+            header.set_parent_id(*parent_footer.uuid(), parent_footer.timestamps());
 
             // get utf16 parent image name
-            let str_parent_path = parent.as_ref().map(|img| img.file_path()).unwrap();
-            let parent_path = std::path::Path::new(&str_parent_path);
-
-            let parent_name = parent_path
-                .file_name()
-                .map(|name| name.to_string_lossy()).unwrap();
-
-            let parent_utf16_name: Vec<u16> = parent_name.encode_utf16().collect();
-            header.prt_name[..parent_utf16_name.len()].copy_from_slice(&parent_utf16_name);
+            let parent_file_path = parent.as_ref().map(|img| img.file_path().clone()).unwrap();
+            header.set_parent_name(&parent_file_path);
 
             // get bat size
             let bat_size = math::round_up(header.max_bat_size as usize * 4, sizes::SECTOR as usize);
-            
-            let parent_file_path = parent.as_ref().map(|img| img.file_path().clone()).unwrap();
-            let p: Vec<u16> = Self::relative_path_to(file_path, &parent_file_path)
-                .unwrap()
-                .encode_utf16()
-                .collect();
 
-            for i in 0..2 as usize {
+            // the locators follow the BAT
+            let locators = Self::parent_locators(file_path, &parent_file_path)?;
+            let mut data_offset = table_offset + bat_size as u64;
+            for (i, locator) in locators.iter().enumerate() {
+                let data_len = locator.encode().len() as u32;
                 /*
                 write number of bytes ('size') instead of number of sectors
                 into loc->data_space to be compatible with MSFT, even though
                 this goes against the specs
                 */
-                header.prt_loc[i].data_space = sizes::SECTOR; 
-                // This field stores the actual length of the parent hard disk locator in bytes
-                header.prt_loc[i].data_len = (p.len() * 2) as u32; //(str_parent_path.encode_utf16().count() * 2) as u32;
-
-                if i == 0 {
-                    header.prt_loc[i].code = PLAT_CODE_W2KU;
-                    header.prt_loc[i].data_offset = table_offset + bat_size as u64;
-                } else {
-                    header.prt_loc[i].code = PLAT_CODE_W2RU;
-                    header.prt_loc[i].data_offset = table_offset + bat_size as u64 + sizes::SECTOR_U64;
-                }                    
+                let data_space = std::cmp::max(math::round_up(data_len, sizes::SECTOR), sizes::SECTOR);
+                header.set_locator(i, locator.code(), data_offset, data_space, data_len);
+                data_offset += data_space as u64;
             }
 
-            p
+            locators
         };
 
-        let checksum = crate::vhd::calc_header_bytes_checksum(&header);
-        header.checksum = checksum;        
+        header.update_checksum();
 
-        (header.copy(), relative_utf16_path.clone())
+        Ok((header.copy(), locators))
     }
 
     pub fn read(stream: &impl ReadAt, pos: u64) -> Result<Self> {
+        let header = Self::read_unchecked(stream, pos)?;
+        if !header.checksum_valid() {
+            return Err(VhdError::InvalidSparseHeaderChecksum);
+        }
+
+        Ok(header)
+    }
+
+    /// Reads a header checking its cookie and block size only, so a bad checksum can be repaired.
+    pub(crate) fn read_unchecked(stream: &impl ReadAt, pos: u64) -> Result<Self> {
         let mut header = unsafe { StructBuffer::<VhdHeader>::new() };
         stream.read_exact_at(pos, unsafe { header.as_byte_slice_mut() })?;
 
@@ -162,14 +240,26 @@ impl VhdHeader {
 
         header.swap_bytes();
 
-        let checksum = calc_header_checksum!(header);
-        if header.checksum != checksum {
-            return Err(VhdError::InvalidSparseHeaderChecksum);
+        if !is_valid_block_size(header.block_size) {
+            return Err(VhdError::InvalidBlockSize(header.block_size));
         }
 
         Ok(header.copy())
     }
 
+    fn calc_checksum(&self) -> u32 {
+        let header = unsafe { StructBuffer::<VhdHeader>::with_value(self) };
+        calc_header_checksum!(header)
+    }
+
+    pub(crate) fn checksum_valid(&self) -> bool {
+        self.checksum == self.calc_checksum()
+    }
+
+    pub(crate) fn update_checksum(&mut self) {
+        self.checksum = self.calc_checksum();
+    }
+
     pub fn write(&self, stream: &impl WriteAt, pos: u64) -> Result<()> {
         let mut header = unsafe { StructBuffer::<VhdHeader>::with_value(self) };
         header.swap_bytes();
@@ -177,53 +267,96 @@ impl VhdHeader {
         stream.write_all_at(pos, header.buffer())
     }
 
-    pub fn write_locator(&self, stream: &impl WriteAt, index: usize, parent_relative_path: &Vec<u16>) -> Result<usize> {
-        let mut temp = [0_u16; 256];
-        temp[..parent_relative_path.len()].copy_from_slice(parent_relative_path);
-        let buf = unsafe { 
-            std::slice::from_raw_parts(temp.as_ptr() as *const u8, sizes::SECTOR as usize)
-        };
-        stream.write_all_at(self.prt_loc[index].data_offset, buf).unwrap();
+    /// Writes `locator` at the offset of locator `index`, padded with zeros to its space.
+    pub fn write_locator(&self, stream: &impl WriteAt, index: usize, locator: &VhdLocator) -> Result<usize> {
+        let loc = self.prt_loc[index];
+        let data = locator.encode();
+        let mut buffer = vec![0_u8; std::cmp::max(loc.prt_loc_space_bytes() as usize, data.len())];
+        buffer[..data.len()].copy_from_slice(&data);
+        stream.write_all_at(loc.data_offset, &buffer)?;
 
-        Ok(sizes::SECTOR as usize)
+        Ok(buffer.len())
     }
 
-    // return a relative path from @file_path to @parent_file_path
-    fn relative_path_to(file_path: &String, parent_file_path: &String) -> Result<String> {
-        if !Path::new(&file_path).is_absolute() || !Path::new(&parent_file_path).is_absolute() {
+    // return the components of the shortest relative path from the directory of @file_path to
+    // @parent_file_path, starting with "." when the parent is in the same directory
+    fn relative_path_to(file_path: &str, parent_file_path: &str) -> Result<Vec<String>> {
+        let file_path = Path::new(file_path);
+        let parent_file_path = Path::new(parent_file_path);
+        if !file_path.is_absolute() || !parent_file_path.is_absolute() {
             return Err(VhdError::FilePathNeedAbsolute);
         }
 
-        let fp_nodes = file_path.split(MAIN_SEPARATOR);
-        let fp_nodes_count = file_path.split(MAIN_SEPARATOR).count();
-        let pfp_nodes = parent_file_path.split(MAIN_SEPARATOR);
-
-        let common_nodes_count = fp_nodes.zip(pfp_nodes).filter(|(n1, n2)| n1 == n2).count();
+        let dir: Vec<Component> = file_path.parent().map(|dir| dir.components().collect()).unwrap_or_default();
+        let target: Vec<Component> = parent_file_path.components().collect();
+        if dir.iter().chain(target.iter()).any(|c| *c == Component::ParentDir) {
+            return Err(VhdError::CannotGetRelativePath);
+        }
 
-        if common_nodes_count == 0 {
+        let common = dir.iter().zip(target.iter()).take_while(|(c1, c2)| c1 == c2).count();
+        // paths on different drives have no common root
+        if common == 0 || target.len() == common {
             return Err(VhdError::CannotGetRelativePath);
         }
 
-        let up_nodes = fp_nodes_count - common_nodes_count - 1;
-        let mut prefix = String::new();
-        if up_nodes == 0 {            
-            prefix.push('.');
-            prefix.push(MAIN_SEPARATOR);            
-        } else {
-            for _ in 0..up_nodes {
-                prefix.push_str("..");
-                prefix.push(MAIN_SEPARATOR);
+        let mut nodes = vec![String::from(".."); dir.len() - common];
+        if nodes.is_empty() {
+            nodes.push(String::from("."));
+        }
+        nodes.extend(target[common..].iter().map(|c| c.as_os_str().to_string_lossy().into_owned()));
+
+        Ok(nodes)
+    }
+
+    /// Returns the locators the image at `file_path` stores for `parent_file_path`:
+    /// W2ku with the absolute path, W2ru and MacX with the relative one if there is one.
+    /// W2ku and W2ru always use Windows separators, MacX uses `/`.
+    pub(crate) fn parent_locators(file_path: &str, parent_file_path: &str) -> Result<Vec<VhdLocator>> {
+        let mut locators = vec![VhdLocator::W2ku(parent_file_path.replace('/', "\\"))];
+
+        // a parent on another drive is only found by its absolute path
+        match Self::relative_path_to(file_path, parent_file_path) {
+            Ok(relative_nodes) => {
+                locators.push(VhdLocator::W2ru(relative_nodes.join("\\")));
+                locators.push(VhdLocator::MacX(relative_nodes.join("/")));
             }
+            Err(VhdError::CannotGetRelativePath) => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(locators)
+    }
+
+    pub(crate) fn set_parent_id(&mut self, prt_uuid: Uuid, prt_ts: u32) {
+        self.prt_uuid = prt_uuid;
+        self.prt_ts = prt_ts;
+        self.update_checksum();
+    }
+
+    /// Sets `prt_name` to the file name of `parent_file_path`, truncated to 256 UTF-16 units.
+    pub(crate) fn set_parent_name(&mut self, parent_file_path: &str) {
+        let parent_name = Path::new(parent_file_path)
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+
+        let mut prt_name = [0_u16; 256];
+        for (unit, c) in prt_name.iter_mut().zip(parent_name.encode_utf16()) {
+            *unit = c;
         }
-        
-        let pfp_offset = parent_file_path
-            .split(MAIN_SEPARATOR)
-            .skip(common_nodes_count)
-            .collect::<Vec<_>>().join(&MAIN_SEPARATOR.to_string());
+        self.prt_name = prt_name;
+        self.update_checksum();
+    }
 
-        prefix.push_str(&pfp_offset);
+    /// Sets locator `index`, `data_space` is in bytes as MSFT tools write it.
+    pub(crate) fn set_locator(&mut self, index: usize, code: u32, data_offset: u64, data_space: u32, data_len: u32) {
+        self.prt_loc[index] = VhdParentLocator { code, data_space, data_len, res: 0, data_offset };
+        self.update_checksum();
+    }
 
-        Ok(prefix)
+    pub(crate) fn clear_locators(&mut self) {
+        self.prt_loc = unsafe { std::mem::zeroed() };
+        self.update_checksum();
     }
 
     pub fn set_parent_loc_data_len(&mut self, len: u32) {
@@ -231,6 +364,26 @@ impl VhdHeader {
         self.prt_loc[1].data_len = len;
     }
 
+    /// Sets the number of BAT entries, the checksum is updated.
+    pub(crate) fn set_max_bat_size(&mut self, max_bat_size: u32) {
+        self.max_bat_size = max_bat_size;
+        self.update_checksum();
+    }
+
+    /// Moves the data of locator `index` to `data_offset`, the checksum is updated.
+    pub(crate) fn set_locator_offset(&mut self, index: usize, data_offset: u64) {
+        self.prt_loc[index].data_offset = data_offset;
+        self.update_checksum();
+    }
+
+    pub fn data_offset(&self) -> u64 {
+        self.data_offset
+    }
+
+    pub fn hdr_ver(&self) -> u32 {
+        self.hdr_ver
+    }
+
     pub fn table_offset(&self) -> u64 {
         self.table_offset
     }
@@ -245,14 +398,32 @@ impl VhdHeader {
 
     pub fn cookie(&self) -> &str {
         let cookie = unsafe {
-            std::slice::from_raw_parts(&self.cookie as *const _ as *const u8, 8)
+            std::slice::from_raw_parts(std::ptr::addr_of!(self.cookie) as *const u8, 8)
         };
 
         std::str::from_utf8(cookie).unwrap()
     }
 
     pub fn prt_name(&self) -> String {
-        String::from_utf16_lossy(&self.prt_name)
+        let prt_name = self.prt_name;
+        String::from_utf16_lossy(&prt_name)
+    }
+
+    pub fn prt_uuid(&self) -> Uuid {
+        self.prt_uuid
+    }
+
+    pub fn prt_ts(&self) -> u32 {
+        self.prt_ts
+    }
+
+    /// Reads and decodes the data of the `index` parent locator.
+    pub fn read_locator(&self, stream: &impl ReadAt, index: usize) -> Result<VhdLocator> {
+        let locator = self.prt_loc[index];
+        let mut buffer = vec![0_u8; locator.data_len as usize];
+        stream.read_exact_at(locator.data_offset, &mut buffer)?;
+
+        Ok(VhdLocator::decode(locator.code, &buffer))
     }
     
     pub fn prt_loc(&self) -> &[VhdParentLocator] {
@@ -278,6 +449,15 @@ impl VhdParentLocator {
         self.data_space    
     }
 
+    /// `data_space` in bytes: the spec counts sectors, but Windows stores a byte count.
+    pub fn prt_loc_space_bytes(&self) -> u64 {
+        if self.data_space < sizes::SECTOR {
+            self.data_space as u64 * sizes::SECTOR_U64
+        } else {
+            self.data_space as u64
+        }
+    }
+
     pub fn prt_loc_len(&self) -> u32 {
         self.data_len
     }
@@ -304,17 +484,103 @@ impl std::fmt::Display for VhdHeader {
 {:<20}: {:#010X}
 {:<20}: {:#010X}\n",
             "Cookie",  self.cookie(),
-            "Data offset (unused)", self.data_offset,
-            "Table offset",  self.table_offset,
-            "Header version", self.hdr_ver >> 16, self.hdr_ver >> 24,
-            "Max BAT size", self.max_bat_size,
-            "Block size", self.block_size >> 20, self.block_size,
+            "Data offset (unused)", { self.data_offset },
+            "Table offset",  { self.table_offset },
+            "Header version", { self.hdr_ver } >> 16, { self.hdr_ver } >> 24,
+            "Max BAT size", { self.max_bat_size },
+            "Block size", { self.block_size } >> 20, { self.block_size },
             "Parent name", self.prt_name(),
-            "Parent UUID", self.prt_uuid.to_string(),
-            "Parent timestamp", self.prt_ts,
-            "Checksum", self.checksum,            
+            "Parent UUID", { self.prt_uuid },
+            "Parent timestamp", { self.prt_ts },
+            "Checksum", { self.checksum },            
         );        
 
         f.write_str(&header)        
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an absolute path made of `nodes` under the root of the current platform
+    fn abs(nodes: &[&str]) -> String {
+        let root = if cfg!(windows) { "C:\\" } else { "/" };
+        nodes.iter().fold(std::path::PathBuf::from(root), |path, node| path.join(node)).to_string_lossy().into_owned()
+    }
+
+    fn relative(file_path: &[&str], parent_file_path: &[&str]) -> String {
+        VhdHeader::relative_path_to(&abs(file_path), &abs(parent_file_path)).unwrap().join("\\")
+    }
+
+    #[test]
+    fn relative_path_test() {
+        // same directory
+        assert_eq!(relative(&["vhd", "child.vhd"], &["vhd", "base.vhd"]), ".\\base.vhd");
+        // parent nested below the child
+        assert_eq!(relative(&["vhd", "child.vhd"], &["vhd", "a", "b", "base.vhd"]), ".\\a\\b\\base.vhd");
+        // child nested below the parent
+        assert_eq!(relative(&["vhd", "a", "b", "child.vhd"], &["vhd", "base.vhd"]), "..\\..\\base.vhd");
+        // sibling directories
+        assert_eq!(relative(&["vhd", "a", "child.vhd"], &["vhd", "b", "base.vhd"]), "..\\b\\base.vhd");
+        // paths diverging then sharing names again
+        assert_eq!(relative(&["x", "a", "b", "child.vhd"], &["y", "a", "b", "base.vhd"]), "..\\..\\..\\y\\a\\b\\base.vhd");
+        // root level
+        assert_eq!(relative(&["child.vhd"], &["base.vhd"]), ".\\base.vhd");
+        assert_eq!(relative(&["child.vhd"], &["vhd", "base.vhd"]), ".\\vhd\\base.vhd");
+        assert_eq!(relative(&["vhd", "child.vhd"], &["base.vhd"]), "..\\base.vhd");
+
+        let res = VhdHeader::relative_path_to("child.vhd", &abs(&["base.vhd"]));
+        assert!(matches!(res, Err(VhdError::FilePathNeedAbsolute)));
+    }
+
+    #[test]
+    fn parent_locators_test() {
+        let locators = VhdHeader::parent_locators(&abs(&["vhd", "a", "child.vhd"]), &abs(&["vhd", "b", "base.vhd"])).unwrap();
+        let w2ku = if cfg!(windows) { "C:\\vhd\\b\\base.vhd" } else { "\\vhd\\b\\base.vhd" };
+        assert_eq!(
+            locators,
+            [
+                VhdLocator::W2ku(w2ku.to_string()),
+                VhdLocator::W2ru("..\\b\\base.vhd".to_string()),
+                VhdLocator::MacX("../b/base.vhd".to_string()),
+            ]
+        );
+
+        // no relative path, only the absolute one is stored
+        let parent = abs(&["vhd", "..", "base.vhd"]);
+        let locators = VhdHeader::parent_locators(&abs(&["vhd", "child.vhd"]), &parent).unwrap();
+        assert_eq!(locators, [VhdLocator::W2ku(parent.replace('/', "\\"))]);
+
+        let res = VhdHeader::parent_locators("child.vhd", &parent);
+        assert!(matches!(res, Err(VhdError::FilePathNeedAbsolute)));
+    }
+
+    #[test]
+    fn locator_round_trip_test() {
+        let locators = [
+            VhdLocator::W2ku("C:\\vhd\\base.vhd".to_string()),
+            VhdLocator::W2ru(".\\base.vhd".to_string()),
+            VhdLocator::MacX("./base.vhd".to_string()),
+            VhdLocator::Other(0x4D61_6352, vec![1, 2, 3]),
+        ];
+
+        for locator in locators {
+            assert_eq!(VhdLocator::decode(locator.code(), &locator.encode()), locator);
+        }
+    }
+
+    #[test]
+    fn locator_decode_test() {
+        let mut data: Vec<u8> = "C:\\base.vhd".encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+        data.extend_from_slice(&[0; 6]);
+        assert_eq!(VhdLocator::decode(PLAT_CODE_W2KU, &data), VhdLocator::W2ku("C:\\base.vhd".to_string()));
+
+        let locator = VhdLocator::decode(PLAT_CODE_MACX, b"file://../base.vhd\0\0");
+        assert_eq!(locator.path(), Some("../base.vhd"));
+        assert_eq!(locator.to_string(), "MacX: file://../base.vhd");
+
+        assert_eq!(VhdLocator::decode(0x4D61_6352, &[1, 2]).path(), None);
+        assert_eq!(VhdLocator::W2ru("base.vhd".to_string()).to_string(), "W2ru: base.vhd");
+    }
+}
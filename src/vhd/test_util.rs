@@ -0,0 +1,21 @@
+//! Helpers shared by the unit tests of the VHD modules.
+
+use super::VhdImage;
+use crate::ReadAt;
+
+/// The path of `name` in the temporary directory.
+pub(crate) fn temp_path(name: &str) -> String {
+    std::env::temp_dir().join(name).to_string_lossy().into_owned()
+}
+
+/// `len` bytes of data that differ with `seed`.
+pub(crate) fn pattern(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i + seed) % 251) as u8).collect()
+}
+
+/// Reads `len` bytes of `img` from `offset`.
+pub(crate) fn read(img: &VhdImage, offset: u64, len: usize) -> Vec<u8> {
+    let mut buffer = vec![0_u8; len];
+    img.read_exact_at(offset, &mut buffer).unwrap();
+    buffer
+}